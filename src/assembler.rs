@@ -0,0 +1,841 @@
+// Copyright of Jordan Werthman (2019).
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::memory::USER_OFFSET;
+
+// Models an assembler for Cowgod-style mnemonics
+//
+// Each line holds an optional label followed by an instruction or directive
+// with any text after ';' ignored e.g.
+//
+//   sprite_count EQU 3          ; Or: sprite_count = 3
+//   start:
+//       LD V0, sprite_count     ; Mnemonics are case-insensitive.
+//       LD I, sprite
+//   loop: JP loop
+//   sprite:
+//       DB 0x3C, 0b01000010, 60
+//       DW 0x1234
+//   INCLUDE "more.asm"
+//
+// Numbers may be decimal, hexadecimal (0x) or binary (0b) and operands may
+// add or subtract labels, constants and numbers. Registers are V0-VF and I
+// along with DT, ST, K, F, B, HF and R (so these can't be used as labels).
+// The listing printed by the disassembler can be assembled as-is.
+//
+// The ROM is assembled in two passes: the first lays out every statement to
+// give labels their address and the second encodes them. The ROM is
+// assumed to be loaded at 0x200 unless "ORG 0x600" (say) comes before any
+// label or instruction.
+
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct AssemblerError {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssemblerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: {}",
+            self.file, self.line, self.column, self.message
+        )
+    }
+}
+
+impl Error for AssemblerError {}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(i64),
+    Str(String),
+    Comma,
+    Colon,
+    Equals,
+    Plus,
+    Minus,
+    LeftBracket,
+    RightBracket,
+}
+
+// A token and the (1-based) column it starts at.
+#[derive(Clone, Debug)]
+struct Spanned {
+    token: Token,
+    column: usize,
+}
+
+#[derive(Clone, Debug)]
+struct Location {
+    file: String,
+    line: usize,
+}
+
+impl Location {
+    fn error<S: Into<String>>(&self, column: usize, message: S) -> AssemblerError {
+        AssemblerError {
+            file: self.file.clone(),
+            line: self.line,
+            column,
+            message: message.into(),
+        }
+    }
+}
+
+// A number or symbol which is added (or subtracted) within an expression.
+#[derive(Debug)]
+enum Term {
+    Number(i64),
+    Symbol(String),
+}
+
+#[derive(Debug)]
+struct Expression {
+    // Each term with whether it is negated and its column.
+    terms: Vec<(bool, Term, usize)>,
+    column: usize,
+}
+
+#[derive(Debug)]
+enum Operand {
+    Register(usize),
+    I,
+    IndirectI,
+    Delay,
+    Sound,
+    Key,
+    Font,
+    BigFont,
+    Bcd,
+    Flags,
+    Long(Expression),
+    Value(Expression),
+}
+
+struct Statement {
+    location: Location,
+    column: usize,
+    mnemonic: String,
+    operands: Vec<Operand>,
+}
+
+// Assembles |source| with any includes resolved relative to the current
+// directory.
+pub fn assemble(source: &str) -> Result<Vec<u8>, AssemblerError> {
+    let mut assembler = Assembler::default();
+    assembler.process(source, "<source>", None, 0)?;
+    assembler.encode()
+}
+
+// Assembles |source| (read from |path|) with any includes resolved relative
+// to |path|.
+pub fn assemble_file(source: &str, path: &Path) -> Result<Vec<u8>, AssemblerError> {
+    let mut assembler = Assembler::default();
+    let file = path.display().to_string();
+    assembler.process(source, &file, path.parent(), 0)?;
+    assembler.encode()
+}
+
+#[derive(Default)]
+struct Assembler {
+    symbols: HashMap<String, i64>,
+    statements: Vec<Statement>,
+    // Size in bytes of the statements laid out so far.
+    size: usize,
+    // The address the ROM is loaded at if set by ORG.
+    origin: Option<usize>,
+    // Whether a label or statement has been given an address (after which
+    // ORG can't move the ROM).
+    placed: bool,
+}
+
+impl Assembler {
+    fn address(&self) -> i64 {
+        (self.origin.unwrap_or(USER_OFFSET) + self.size) as i64
+    }
+
+    fn define(
+        &mut self,
+        location: &Location,
+        column: usize,
+        name: &str,
+        value: i64,
+    ) -> Result<(), AssemblerError> {
+        if keyword(name).is_some() {
+            return Err(location.error(column, format!("'{}' is a reserved name", name)));
+        }
+        if self.symbols.insert(name.to_string(), value).is_some() {
+            return Err(location.error(column, format!("'{}' is already defined", name)));
+        }
+        Ok(())
+    }
+
+    // The first pass: tokenizes each line defining labels and constants and
+    // laying out the statements.
+    fn process(
+        &mut self,
+        source: &str,
+        file: &str,
+        directory: Option<&Path>,
+        depth: usize,
+    ) -> Result<(), AssemblerError> {
+        for (index, text) in source.lines().enumerate() {
+            let location = Location {
+                file: file.to_string(),
+                line: index + 1,
+            };
+            let mut tokens = &tokenize(text, &location)?[..];
+
+            if let [Spanned {
+                token: Token::Ident(name),
+                column,
+            }, Spanned {
+                token: Token::Colon,
+                ..
+            }, rest @ ..] = tokens
+            {
+                self.define(&location, *column, name, self.address())?;
+                self.placed = true;
+                tokens = rest;
+            }
+
+            match tokens {
+                [] => {}
+                [Spanned {
+                    token: Token::Ident(name),
+                    column,
+                }, Spanned {
+                    token: Token::Equals,
+                    ..
+                }, value @ ..] => {
+                    let value =
+                        self.evaluate(&location, &parse_expression(value, &location, *column)?)?;
+                    self.define(&location, *column, name, value)?;
+                }
+                [Spanned {
+                    token: Token::Ident(name),
+                    column,
+                }, Spanned {
+                    token: Token::Ident(equ),
+                    ..
+                }, value @ ..]
+                    if equ.eq_ignore_ascii_case("EQU") =>
+                {
+                    let value =
+                        self.evaluate(&location, &parse_expression(value, &location, *column)?)?;
+                    self.define(&location, *column, name, value)?;
+                }
+                [Spanned {
+                    token: Token::Ident(include),
+                    column,
+                }, rest @ ..]
+                    if include.eq_ignore_ascii_case("INCLUDE") =>
+                {
+                    let path = match rest {
+                        [Spanned {
+                            token: Token::Str(path),
+                            ..
+                        }] => path,
+                        _ => {
+                            return Err(
+                                location.error(*column, "expected a quoted path after INCLUDE")
+                            )
+                        }
+                    };
+                    if depth >= MAX_INCLUDE_DEPTH {
+                        return Err(location.error(*column, "includes are nested too deeply"));
+                    }
+                    let path = match directory {
+                        Some(directory) => directory.join(path),
+                        None => Path::new(path).to_path_buf(),
+                    };
+                    let source = fs::read_to_string(&path).map_err(|error| {
+                        location.error(
+                            *column,
+                            format!("unable to read {}: {}", path.display(), error),
+                        )
+                    })?;
+                    self.process(
+                        &source,
+                        &path.display().to_string(),
+                        path.parent(),
+                        depth + 1,
+                    )?;
+                }
+                [Spanned {
+                    token: Token::Ident(org),
+                    column,
+                }, rest @ ..]
+                    if org.eq_ignore_ascii_case("ORG") =>
+                {
+                    if self.placed {
+                        return Err(location
+                            .error(*column, "ORG must come before any labels or instructions"));
+                    }
+                    let origin =
+                        self.evaluate(&location, &parse_expression(rest, &location, *column)?)?;
+                    if !(0..=0xFFFF).contains(&origin) {
+                        return Err(location.error(*column, "ORG address is out of range"));
+                    }
+                    self.origin = Some(origin as usize);
+                }
+                [Spanned {
+                    token: Token::Ident(mnemonic),
+                    column,
+                }, rest @ ..] => {
+                    self.placed = true;
+                    let statement = Statement {
+                        location: location.clone(),
+                        column: *column,
+                        mnemonic: mnemonic.to_ascii_uppercase(),
+                        operands: parse_operands(rest, &location)?,
+                    };
+                    self.size += statement.size();
+                    self.statements.push(statement);
+                }
+                [first, ..] => {
+                    return Err(location.error(first.column, "expected a label or instruction"))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn evaluate(
+        &self,
+        location: &Location,
+        expression: &Expression,
+    ) -> Result<i64, AssemblerError> {
+        let mut total = 0;
+        for (negated, term, column) in expression.terms.iter() {
+            let value = match term {
+                Term::Number(value) => *value,
+                Term::Symbol(name) => *self.symbols.get(name).ok_or_else(|| {
+                    location.error(*column, format!("undefined symbol '{}'", name))
+                })?,
+            };
+            total = if *negated {
+                total - value
+            } else {
+                total + value
+            };
+        }
+        Ok(total)
+    }
+
+    // Evaluates |expression| checking it lies in [|min|, |max|] where |max|
+    // is also used to mask off the sign of negative values.
+    fn value(
+        &self,
+        location: &Location,
+        expression: &Expression,
+        min: i64,
+        max: i64,
+    ) -> Result<u16, AssemblerError> {
+        let value = self.evaluate(location, expression)?;
+        if value < min || value > max {
+            return Err(location.error(
+                expression.column,
+                format!("{} is out of range [{}, {}]", value, min, max),
+            ));
+        }
+        Ok((value & max) as u16)
+    }
+
+    fn address_value(
+        &self,
+        statement: &Statement,
+        expression: &Expression,
+    ) -> Result<u16, AssemblerError> {
+        self.value(&statement.location, expression, 0, 0xFFF)
+    }
+
+    fn byte_value(
+        &self,
+        statement: &Statement,
+        expression: &Expression,
+    ) -> Result<u16, AssemblerError> {
+        self.value(&statement.location, expression, -0x80, 0xFF)
+    }
+
+    fn nibble_value(
+        &self,
+        statement: &Statement,
+        expression: &Expression,
+    ) -> Result<u16, AssemblerError> {
+        self.value(&statement.location, expression, 0, 0xF)
+    }
+
+    // The second pass: encodes each statement now every label is known.
+    fn encode(&self) -> Result<Vec<u8>, AssemblerError> {
+        let mut rom = Vec::with_capacity(self.size);
+        for statement in self.statements.iter() {
+            rom.extend(self.encode_statement(statement)?);
+        }
+        Ok(rom)
+    }
+
+    fn encode_statement(&self, statement: &Statement) -> Result<Vec<u8>, AssemblerError> {
+        use Operand::*;
+
+        let location = &statement.location;
+        match (statement.mnemonic.as_str(), statement.operands.as_slice()) {
+            ("DB", values) => {
+                let mut bytes = Vec::new();
+                for value in values {
+                    match value {
+                        Value(expression) => {
+                            bytes.push(self.byte_value(statement, expression)? as u8)
+                        }
+                        _ => return Err(location.error(statement.column, "DB expects values")),
+                    }
+                }
+                return Ok(bytes);
+            }
+            ("DW", values) => {
+                let mut bytes = Vec::new();
+                for value in values {
+                    match value {
+                        Value(expression) => {
+                            let word = self.value(location, expression, -0x8000, 0xFFFF)?;
+                            bytes.extend_from_slice(&word.to_be_bytes());
+                        }
+                        _ => return Err(location.error(statement.column, "DW expects values")),
+                    }
+                }
+                return Ok(bytes);
+            }
+            ("LD", [I, Long(expression)]) => {
+                let address = self.value(location, expression, 0, 0xFFFF)?;
+                let mut bytes = 0xF000u16.to_be_bytes().to_vec();
+                bytes.extend_from_slice(&address.to_be_bytes());
+                return Ok(bytes);
+            }
+            _ => {}
+        }
+
+        let xy = |x: &usize, y: &usize| ((*x as u16) << 8) | ((*y as u16) << 4);
+        let x = |x: &usize| (*x as u16) << 8;
+
+        let opcode = match (statement.mnemonic.as_str(), statement.operands.as_slice()) {
+            ("CLS", []) => 0x00E0,
+            ("RET", []) => 0x00EE,
+            ("SCD", [Value(n)]) => 0x00C0 | self.nibble_value(statement, n)?,
+            ("SCU", [Value(n)]) => 0x00D0 | self.nibble_value(statement, n)?,
+            ("SCR", []) => 0x00FB,
+            ("SCL", []) => 0x00FC,
+            ("EXIT", []) => 0x00FD,
+            ("LOW", []) => 0x00FE,
+            ("HIGH", []) => 0x00FF,
+            ("SYS", [Value(nnn)]) => self.address_value(statement, nnn)?,
+            ("JP", [Value(nnn)]) => 0x1000 | self.address_value(statement, nnn)?,
+            ("JP", [Register(0), Value(nnn)]) => 0xB000 | self.address_value(statement, nnn)?,
+            ("CALL", [Value(nnn)]) => 0x2000 | self.address_value(statement, nnn)?,
+            ("SE", [Register(vx), Value(nn)]) => 0x3000 | x(vx) | self.byte_value(statement, nn)?,
+            ("SNE", [Register(vx), Value(nn)]) => {
+                0x4000 | x(vx) | self.byte_value(statement, nn)?
+            }
+            ("SE", [Register(vx), Register(vy)]) => 0x5000 | xy(vx, vy),
+            ("SAVE", [Register(vx), Register(vy)]) => 0x5002 | xy(vx, vy),
+            ("LOAD", [Register(vx), Register(vy)]) => 0x5003 | xy(vx, vy),
+            ("LD", [Register(vx), Value(nn)]) => 0x6000 | x(vx) | self.byte_value(statement, nn)?,
+            ("ADD", [Register(vx), Value(nn)]) => {
+                0x7000 | x(vx) | self.byte_value(statement, nn)?
+            }
+            ("LD", [Register(vx), Register(vy)]) => 0x8000 | xy(vx, vy),
+            ("OR", [Register(vx), Register(vy)]) => 0x8001 | xy(vx, vy),
+            ("AND", [Register(vx), Register(vy)]) => 0x8002 | xy(vx, vy),
+            ("XOR", [Register(vx), Register(vy)]) => 0x8003 | xy(vx, vy),
+            ("ADD", [Register(vx), Register(vy)]) => 0x8004 | xy(vx, vy),
+            ("SUB", [Register(vx), Register(vy)]) => 0x8005 | xy(vx, vy),
+            ("SHR", [Register(vx), Register(vy)]) => 0x8006 | xy(vx, vy),
+            ("SHR", [Register(vx)]) => 0x8006 | xy(vx, vx),
+            ("SUBN", [Register(vx), Register(vy)]) => 0x8007 | xy(vx, vy),
+            ("SHL", [Register(vx), Register(vy)]) => 0x800E | xy(vx, vy),
+            ("SHL", [Register(vx)]) => 0x800E | xy(vx, vx),
+            ("SNE", [Register(vx), Register(vy)]) => 0x9000 | xy(vx, vy),
+            ("LD", [I, Value(nnn)]) => 0xA000 | self.address_value(statement, nnn)?,
+            ("RND", [Register(vx), Value(nn)]) => {
+                0xC000 | x(vx) | self.byte_value(statement, nn)?
+            }
+            ("DRW", [Register(vx), Register(vy), Value(n)]) => {
+                0xD000 | xy(vx, vy) | self.nibble_value(statement, n)?
+            }
+            ("SKP", [Register(vx)]) => 0xE09E | x(vx),
+            ("SKNP", [Register(vx)]) => 0xE0A1 | x(vx),
+            ("PLANE", [Value(n)]) => 0xF001 | self.nibble_value(statement, n)? << 8,
+            ("AUDIO", []) => 0xF002,
+            ("LD", [Register(vx), Delay]) => 0xF007 | x(vx),
+            ("LD", [Register(vx), Key]) => 0xF00A | x(vx),
+            ("LD", [Delay, Register(vx)]) => 0xF015 | x(vx),
+            ("LD", [Sound, Register(vx)]) => 0xF018 | x(vx),
+            ("ADD", [I, Register(vx)]) => 0xF01E | x(vx),
+            ("LD", [Font, Register(vx)]) => 0xF029 | x(vx),
+            ("LD", [BigFont, Register(vx)]) => 0xF030 | x(vx),
+            ("LD", [Bcd, Register(vx)]) => 0xF033 | x(vx),
+            ("PITCH", [Register(vx)]) => 0xF03A | x(vx),
+            ("LD", [IndirectI, Register(vx)]) => 0xF055 | x(vx),
+            ("LD", [Register(vx), IndirectI]) => 0xF065 | x(vx),
+            ("LD", [Flags, Register(vx)]) => 0xF075 | x(vx),
+            ("LD", [Register(vx), Flags]) => 0xF085 | x(vx),
+            (mnemonic, _) if MNEMONICS.contains(&mnemonic) => {
+                return Err(location.error(
+                    statement.column,
+                    format!("invalid operands for {}", mnemonic),
+                ));
+            }
+            (mnemonic, _) => {
+                return Err(location.error(
+                    statement.column,
+                    format!("unknown instruction '{}'", mnemonic),
+                ));
+            }
+        };
+        Ok(opcode.to_be_bytes().to_vec())
+    }
+}
+
+const MNEMONICS: &[&str] = &[
+    "CLS", "RET", "SCD", "SCU", "SCR", "SCL", "EXIT", "LOW", "HIGH", "SYS", "JP", "CALL", "SE",
+    "SNE", "SAVE", "LOAD", "LD", "ADD", "OR", "AND", "XOR", "SUB", "SHR", "SUBN", "SHL", "RND",
+    "DRW", "SKP", "SKNP", "PLANE", "AUDIO", "PITCH",
+];
+
+impl Statement {
+    // The number of bytes the statement assembles to.
+    fn size(&self) -> usize {
+        match (self.mnemonic.as_str(), self.operands.as_slice()) {
+            ("DB", values) => values.len(),
+            ("DW", values) => 2 * values.len(),
+            ("LD", [Operand::I, Operand::Long(_)]) => 4,
+            _ => 2,
+        }
+    }
+}
+
+// Returns the operand named by |name| if it is a register or other reserved
+// name (ignoring case).
+fn keyword(name: &str) -> Option<Operand> {
+    let upper = name.to_ascii_uppercase();
+    let operand = match upper.as_str() {
+        "I" => Operand::I,
+        "DT" => Operand::Delay,
+        "ST" => Operand::Sound,
+        "K" => Operand::Key,
+        "F" => Operand::Font,
+        "HF" => Operand::BigFont,
+        "B" => Operand::Bcd,
+        "R" => Operand::Flags,
+        _ if upper.len() == 2 && upper.starts_with('V') => {
+            Operand::Register(usize::from_str_radix(&upper[1..], 16).ok()?)
+        }
+        _ => return None,
+    };
+    Some(operand)
+}
+
+fn tokenize(text: &str, location: &Location) -> Result<Vec<Spanned>, AssemblerError> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut index = 0;
+
+    while index < chars.len() {
+        let start = index;
+        let column = start + 1;
+        let token = match chars[index] {
+            ';' => break,
+            c if c.is_whitespace() => {
+                index += 1;
+                continue;
+            }
+            ',' => Token::Comma,
+            ':' => Token::Colon,
+            '=' => Token::Equals,
+            '+' => Token::Plus,
+            '-' => Token::Minus,
+            '[' => Token::LeftBracket,
+            ']' => Token::RightBracket,
+            '"' => {
+                let end = chars[start + 1..]
+                    .iter()
+                    .position(|&c| c == '"')
+                    .ok_or_else(|| location.error(column, "unterminated string"))?;
+                index = start + end + 2;
+                tokens.push(Spanned {
+                    token: Token::Str(chars[start + 1..start + 1 + end].iter().collect()),
+                    column,
+                });
+                continue;
+            }
+            c if c.is_ascii_alphanumeric() || c == '_' || c == '.' => {
+                while index < chars.len()
+                    && (chars[index].is_ascii_alphanumeric()
+                        || chars[index] == '_'
+                        || chars[index] == '.')
+                {
+                    index += 1;
+                }
+                let word: String = chars[start..index].iter().collect();
+                let token = if c.is_ascii_digit() {
+                    Token::Number(parse_number(&word).ok_or_else(|| {
+                        location.error(column, format!("invalid number '{}'", word))
+                    })?)
+                } else {
+                    Token::Ident(word)
+                };
+                tokens.push(Spanned { token, column });
+                continue;
+            }
+            c => return Err(location.error(column, format!("unexpected character '{}'", c))),
+        };
+        tokens.push(Spanned { token, column });
+        index += 1;
+    }
+    Ok(tokens)
+}
+
+fn parse_number(word: &str) -> Option<i64> {
+    let lower = word.to_ascii_lowercase();
+    if let Some(hex) = lower.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = lower.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()
+    } else {
+        lower.parse().ok()
+    }
+}
+
+// Parses a sum of numbers and symbols e.g. "sprite + 5 - 0x1" where
+// |column| is reported if the expression is empty.
+fn parse_expression(
+    tokens: &[Spanned],
+    location: &Location,
+    column: usize,
+) -> Result<Expression, AssemblerError> {
+    let mut terms = Vec::new();
+    let mut negated = false;
+    let mut expect_term = true;
+
+    for spanned in tokens {
+        match &spanned.token {
+            Token::Minus if expect_term => negated = !negated,
+            Token::Plus | Token::Minus if !expect_term => {
+                negated = spanned.token == Token::Minus;
+                expect_term = true;
+            }
+            Token::Number(value) if expect_term => {
+                terms.push((negated, Term::Number(*value), spanned.column));
+                expect_term = false;
+            }
+            Token::Ident(name) if expect_term && keyword(name).is_none() => {
+                terms.push((negated, Term::Symbol(name.clone()), spanned.column));
+                expect_term = false;
+            }
+            _ => return Err(location.error(spanned.column, "unexpected token in expression")),
+        }
+    }
+
+    if expect_term {
+        let column = tokens.last().map_or(column, |spanned| spanned.column);
+        return Err(location.error(column, "expected a value"));
+    }
+    Ok(Expression {
+        terms,
+        column: tokens[0].column,
+    })
+}
+
+fn parse_operand(tokens: &[Spanned], location: &Location) -> Result<Operand, AssemblerError> {
+    let operand = match tokens {
+        [Spanned {
+            token: Token::LeftBracket,
+            ..
+        }, Spanned {
+            token: Token::Ident(name),
+            ..
+        }, Spanned {
+            token: Token::RightBracket,
+            ..
+        }] if name.eq_ignore_ascii_case("I") => Operand::IndirectI,
+        [Spanned {
+            token: Token::Ident(name),
+            ..
+        }] if keyword(name).is_some() => keyword(name).unwrap(),
+        [Spanned {
+            token: Token::Ident(name),
+            column,
+        }, rest @ ..]
+            if name.eq_ignore_ascii_case("LONG") =>
+        {
+            Operand::Long(parse_expression(rest, location, *column)?)
+        }
+        _ => Operand::Value(parse_expression(tokens, location, tokens[0].column)?),
+    };
+    Ok(operand)
+}
+
+// Parses the comma separated operands following a mnemonic.
+fn parse_operands(tokens: &[Spanned], location: &Location) -> Result<Vec<Operand>, AssemblerError> {
+    let mut operands = Vec::new();
+    if tokens.is_empty() {
+        return Ok(operands);
+    }
+
+    let mut start = 0;
+    for end in 0..=tokens.len() {
+        if end < tokens.len() && tokens[end].token != Token::Comma {
+            continue;
+        }
+        if start == end {
+            // Report a missing operand at the comma before (or after) it.
+            let column = tokens[end.min(tokens.len() - 1)].column;
+            return Err(location.error(column, "expected an operand"));
+        }
+        operands.push(parse_operand(&tokens[start..end], location)?);
+        start = end + 1;
+    }
+    Ok(operands)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listing::Listing;
+
+    #[test]
+    fn assembles_instructions() {
+        let rom = assemble(
+            "start: CLS\n\
+             \tld v3, 0x1F\n\
+             \tDRW V0, V1, 5\n\
+             \tLD [I], VA\n\
+             \tLD I, LONG 0x1234\n\
+             \tJP start",
+        )
+        .unwrap();
+
+        assert_eq!(
+            rom,
+            vec![
+                0x00, 0xE0, 0x63, 0x1F, 0xD0, 0x15, 0xFA, 0x55, 0xF0, 0x00, 0x12, 0x34, 0x12, 0x00
+            ]
+        );
+    }
+
+    #[test]
+    fn resolves_labels_and_constants() {
+        let rom = assemble(
+            "height EQU 4\n\
+             offset = height + 1 - 0b10\n\
+             \tLD I, sprite + offset ; Forward reference.\n\
+             \tADD V0, -1\n\
+             sprite:\n\
+             \tDB 0x3C, 255, height\n\
+             \tDW 0xABCD, sprite",
+        )
+        .unwrap();
+
+        assert_eq!(
+            rom,
+            vec![0xA2, 0x07, 0x70, 0xFF, 0x3C, 0xFF, 0x04, 0xAB, 0xCD, 0x02, 0x04]
+        );
+    }
+
+    #[test]
+    fn includes_files() {
+        let directory = std::env::temp_dir().join("rust8_assembler_include");
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(directory.join("sprite.asm"), "sprite: DB 0xFF\n").unwrap();
+
+        let source = "LD I, sprite\nINCLUDE \"sprite.asm\"\n";
+        let rom = assemble_file(source, &directory.join("main.asm")).unwrap();
+
+        assert_eq!(rom, vec![0xA2, 0x02, 0xFF]);
+    }
+
+    #[test]
+    fn reports_line_and_column() {
+        let error = |source| assemble(source).unwrap_err();
+
+        assert_eq!(
+            error("CLS\n  LD V0, missing").to_string(),
+            "<source>:2:10: undefined symbol 'missing'"
+        );
+        assert_eq!(
+            error("  FOO V0").to_string(),
+            "<source>:1:3: unknown instruction 'FOO'"
+        );
+        assert_eq!(
+            error("SE V0, 0x100").to_string(),
+            "<source>:1:8: 256 is out of range [-128, 255]"
+        );
+        assert_eq!(
+            error("ADD V0, , V1").to_string(),
+            "<source>:1:9: expected an operand"
+        );
+        assert_eq!(
+            error("DRW V0, V1").to_string(),
+            "<source>:1:1: invalid operands for DRW"
+        );
+        assert_eq!(
+            error("a: CLS\na: CLS").to_string(),
+            "<source>:2:1: 'a' is already defined"
+        );
+        assert_eq!(
+            error("LD V0, @").to_string(),
+            "<source>:1:8: unexpected character '@'"
+        );
+    }
+
+    #[test]
+    fn reassembles_disassembly() {
+        let rom = vec![
+            0x00, 0xE0, 0x6A, 0x02, 0xA2, 0x30, 0x22, 0x2C, 0x3A, 0x02, 0x12, 0x08, 0x4A, 0x03,
+            0x5A, 0xB0, 0x9A, 0xB0, 0x7A, 0x01, 0x8A, 0xB0, 0x8A, 0xB1, 0x8A, 0xB2, 0x8A, 0xB3,
+            0x8A, 0xB4, 0x8A, 0xB5, 0x8A, 0xB6, 0x8A, 0xB7, 0x8A, 0xBE, 0xCA, 0x0F, 0xDA, 0xB5,
+            0xB2, 0x00, 0xEA, 0x9E, 0xEA, 0xA1, 0xFA, 0x07, 0xFA, 0x0A, 0xFA, 0x15, 0xFA, 0x18,
+            0xFA, 0x1E, 0xFA, 0x29, 0xFA, 0x30, 0xFA, 0x33, 0xFA, 0x55, 0xFA, 0x65, 0xFA, 0x75,
+            0xFA, 0x85, 0x00, 0xC4, 0x00, 0xD4, 0x00, 0xFB, 0x00, 0xFC, 0x00, 0xFE, 0x00, 0xFF,
+            0x5A, 0xB2, 0x5A, 0xB3, 0xF0, 0x00, 0x02, 0x30, 0xF2, 0x01, 0xF0, 0x02, 0xFA, 0x3A,
+            0x00, 0xFD, 0x00, 0xEE, 0x3C, 0x42, 0x81, 0x00, 0x12, 0x01,
+        ];
+
+        let listing = Listing::new(&rom, USER_OFFSET).to_string();
+        assert_eq!(assemble(&listing), Ok(rom));
+    }
+
+    #[test]
+    fn reassembles_disassembly_at_other_bases() {
+        // CALL 0x608; LD I, 0x60C; JP 0x604; DB 0x00, 0x00; RET
+        let rom = vec![0x26, 0x08, 0xA6, 0x0C, 0x16, 0x04, 0x00, 0x00, 0x00, 0xEE];
+        let listing = Listing::new(&rom, 0x600).to_string();
+        assert!(listing.starts_with("    ORG 0x600\n"));
+        assert_eq!(assemble(&listing), Ok(rom));
+    }
+
+    #[test]
+    fn sets_origin() {
+        let error = |source| assemble(source).unwrap_err();
+
+        assert_eq!(assemble("ORG 0x600\nstart: JP start"), Ok(vec![0x16, 0x00]));
+        assert_eq!(
+            error("JP 0x200\nORG 0x600").to_string(),
+            "<source>:2:1: ORG must come before any labels or instructions"
+        );
+        assert_eq!(
+            error("ORG 0x10000").to_string(),
+            "<source>:1:1: ORG address is out of range"
+        );
+    }
+}
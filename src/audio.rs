@@ -0,0 +1,294 @@
+// Copyright of Jordan Werthman (2019).
+
+use std::io;
+use std::io::prelude::*;
+
+// Models the CHIP-8 buzzer
+//
+// The buzzer sounds a single tone while the sound timer is non-zero. The
+// tone is rendered as a square wave of 32-bit float PCM samples in [-1, 1]
+// so that it can be played by any audio backend (or inspected headlessly).
+// XO-CHIP ROMs may instead load a 128-bit pattern (F002) which is played
+// 1-bit per sample at a rate set by the pitch register (FX3A).
+
+pub const DEFAULT_FREQUENCY: f64 = 440.0;
+pub const DEFAULT_VOLUME: f32 = 0.25;
+
+pub const PATTERN_SIZE: usize = 16;
+const PATTERN_BITS: f64 = (PATTERN_SIZE * 8) as f64;
+pub const DEFAULT_PITCH: u8 = 64;
+
+// Returns the playback rate of XO-CHIP pattern bits in Hz for |pitch|
+// where 64 is 4000Hz and each 48 steps doubles (or halves) the rate.
+pub fn pattern_rate(pitch: u8) -> f64 {
+    4000.0 * 2f64.powf((pitch as f64 - 64.0) / 48.0)
+}
+
+pub struct Beeper {
+    frequency: f64,
+    volume: f32,
+    pattern: Option<[u8; PATTERN_SIZE]>,
+    pitch: u8,
+    // Position within the current period of the square wave (or pattern)
+    // [0, 1)
+    phase: f64,
+    // Fraction of a sample left over from the previous render() so that
+    // rendering many short durations does not drift from the sample rate.
+    remainder: f64,
+}
+
+impl Default for Beeper {
+    fn default() -> Self {
+        Self::new(DEFAULT_FREQUENCY, DEFAULT_VOLUME)
+    }
+}
+
+impl Beeper {
+    pub fn new(frequency: f64, volume: f32) -> Beeper {
+        Beeper {
+            frequency,
+            volume: volume.clamp(0.0, 1.0),
+            pattern: None,
+            pitch: DEFAULT_PITCH,
+            phase: 0.0,
+            remainder: 0.0,
+        }
+    }
+
+    pub fn frequency(&self) -> f64 {
+        self.frequency
+    }
+
+    pub fn volume(&self) -> f32 {
+        self.volume
+    }
+
+    // Configures the square wave played until a pattern is loaded.
+    pub fn set_tone(&mut self, frequency: f64, volume: f32) {
+        self.frequency = frequency;
+        self.volume = volume.clamp(0.0, 1.0);
+    }
+
+    pub fn pattern(&self) -> Option<&[u8; PATTERN_SIZE]> {
+        self.pattern.as_ref()
+    }
+
+    // Replaces the square wave with a 128-bit XO-CHIP audio pattern.
+    pub fn set_pattern(&mut self, pattern: [u8; PATTERN_SIZE]) {
+        self.pattern = Some(pattern);
+    }
+
+    pub fn pitch(&self) -> u8 {
+        self.pitch
+    }
+
+    pub fn set_pitch(&mut self, pitch: u8) {
+        self.pitch = pitch;
+    }
+
+    // Returns to the square wave and default pitch.
+    pub fn reset(&mut self) {
+        self.pattern = None;
+        self.pitch = DEFAULT_PITCH;
+        self.phase = 0.0;
+    }
+
+    // Renders |duration| seconds of samples at |sample_rate| where the tone
+    // is audible for the first |active| seconds and silent afterwards.
+    pub fn render(&mut self, sample_rate: u32, duration: f64, active: f64) -> Vec<f32> {
+        let total = duration * sample_rate as f64 + self.remainder;
+        let count = total.floor() as usize;
+        self.remainder = total - count as f64;
+
+        let active_count = (active * sample_rate as f64).ceil() as usize;
+        let frequency = match self.pattern {
+            Some(_) => pattern_rate(self.pitch) / PATTERN_BITS,
+            None => self.frequency,
+        };
+        let step = frequency / sample_rate as f64;
+
+        (0..count)
+            .map(|index| {
+                if index >= active_count {
+                    self.phase = 0.0;
+                    return 0.0;
+                }
+
+                let high = match self.pattern {
+                    Some(ref pattern) => {
+                        let bit = (self.phase * PATTERN_BITS) as usize;
+                        (pattern[bit / 8] << (bit % 8)) & 0x80 != 0
+                    }
+                    None => self.phase < 0.5,
+                };
+                let sample = if high { self.volume } else { -self.volume };
+                self.phase = (self.phase + step) % 1.0;
+                sample
+            })
+            .collect()
+    }
+}
+
+// Accumulates the samples played during a run so that they can be saved
+// as a WAV file.
+pub struct Recording {
+    sample_rate: u32,
+    samples: Vec<f32>,
+}
+
+impl Recording {
+    pub fn new(sample_rate: u32) -> Recording {
+        Recording {
+            sample_rate,
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn samples(&self) -> &[f32] {
+        &self.samples
+    }
+
+    pub fn push(&mut self, samples: &[f32]) {
+        self.samples.extend_from_slice(samples);
+    }
+
+    // Writes the recording as a mono 16-bit PCM WAV file.
+    pub fn write_wav<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        const CHANNELS: u16 = 1;
+        const BITS_PER_SAMPLE: u16 = 16;
+        let block_align = CHANNELS * BITS_PER_SAMPLE / 8;
+        let data_len = (self.samples.len() * block_align as usize) as u32;
+
+        writer.write_all(b"RIFF")?;
+        writer.write_all(&(36 + data_len).to_le_bytes())?;
+        writer.write_all(b"WAVE")?;
+
+        writer.write_all(b"fmt ")?;
+        writer.write_all(&16u32.to_le_bytes())?;
+        writer.write_all(&1u16.to_le_bytes())?; // PCM
+        writer.write_all(&CHANNELS.to_le_bytes())?;
+        writer.write_all(&self.sample_rate.to_le_bytes())?;
+        writer.write_all(&(self.sample_rate * block_align as u32).to_le_bytes())?;
+        writer.write_all(&block_align.to_le_bytes())?;
+        writer.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+
+        writer.write_all(b"data")?;
+        writer.write_all(&data_len.to_le_bytes())?;
+        for sample in self.samples.iter() {
+            let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Bus;
+
+    #[test]
+    fn silent_when_inactive() {
+        let mut beeper = Beeper::default();
+        let samples = beeper.render(8000, 0.5, 0.0);
+
+        assert_eq!(samples.len(), 4000);
+        assert!(samples.iter().all(|&sample| sample == 0.0));
+    }
+
+    #[test]
+    fn square_wave_at_frequency() {
+        let mut beeper = Beeper::new(1000.0, 0.5);
+        let samples = beeper.render(8000, 0.01, 0.01);
+
+        // 8 samples per period: 4 high followed by 4 low.
+        assert_eq!(samples.len(), 80);
+        assert_eq!(&samples[..8], &[0.5, 0.5, 0.5, 0.5, -0.5, -0.5, -0.5, -0.5]);
+        let crossings = samples.windows(2).filter(|w| w[0] != w[1]).count();
+        assert_eq!(crossings, 19);
+    }
+
+    #[test]
+    fn stops_after_active_duration() {
+        let mut beeper = Beeper::default();
+        let samples = beeper.render(1000, 1.0, 0.1);
+
+        assert!(samples[..100].iter().all(|&sample| sample != 0.0));
+        assert!(samples[100..].iter().all(|&sample| sample == 0.0));
+    }
+
+    #[test]
+    fn keeps_sample_rate_across_renders() {
+        let mut beeper = Beeper::default();
+        let count: usize = (0..60)
+            .map(|_| beeper.render(44100, 1.0 / 60.0, 0.0).len())
+            .sum();
+
+        assert_eq!(count, 44100);
+    }
+
+    #[test]
+    fn bus_beeps_for_sound_timer() {
+        let mut bus = Bus::new();
+        bus.set_beeper(1000.0, 1.0);
+        assert!(bus
+            .audio_samples(1000, 0.1)
+            .iter()
+            .all(|&sample| sample == 0.0));
+
+        // Six ticks of the 60Hz sound timer last 0.1 seconds.
+        bus.set_sound_timer(6);
+        let samples = bus.audio_samples(1000, 0.2);
+        assert!(samples[..100].iter().all(|&sample| sample.abs() == 1.0));
+        assert!(samples[100..].iter().all(|&sample| sample == 0.0));
+    }
+
+    #[test]
+    fn pattern_rate_doubles_every_48_steps() {
+        assert_eq!(pattern_rate(64), 4000.0);
+        assert_eq!(pattern_rate(112), 8000.0);
+        assert_eq!(pattern_rate(16), 2000.0);
+    }
+
+    #[test]
+    fn plays_pattern_bits_at_pitch() {
+        let mut beeper = Beeper::new(DEFAULT_FREQUENCY, 1.0);
+        let mut pattern = [0x00; PATTERN_SIZE];
+        pattern[0] = 0b1010_0000;
+        beeper.set_pattern(pattern);
+
+        // At the default pitch each bit lasts a single sample at 4000Hz.
+        let samples = beeper.render(4000, 0.064, 1.0);
+        assert_eq!(&samples[..4], &[1.0, -1.0, 1.0, -1.0]);
+        assert!(samples[4..128].iter().all(|&sample| sample == -1.0));
+        assert_eq!(&samples[128..132], &samples[..4]);
+
+        // Raising the pitch by 48 plays each bit for half a sample.
+        beeper.reset();
+        beeper.set_pattern(pattern);
+        beeper.set_pitch(112);
+        let samples = beeper.render(4000, 0.001, 1.0);
+        assert_eq!(&samples[..2], &[1.0, 1.0]);
+        assert_eq!(samples[2], -1.0);
+    }
+
+    #[test]
+    fn writes_wav() {
+        let mut recording = Recording::new(8000);
+        recording.push(&[0.0, 1.0, -1.0]);
+
+        let mut wav = Vec::new();
+        recording.write_wav(&mut wav).unwrap();
+
+        assert_eq!(wav.len(), 44 + 6);
+        assert_eq!(&wav[..4], b"RIFF");
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        assert_eq!(&wav[24..28], &8000u32.to_le_bytes());
+        assert_eq!(&wav[36..44], &[b'd', b'a', b't', b'a', 6, 0, 0, 0]);
+        assert_eq!(&wav[44..], &[0x00, 0x00, 0xFF, 0x7F, 0x01, 0x80]);
+    }
+}
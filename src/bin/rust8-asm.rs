@@ -0,0 +1,81 @@
+// Copyright of Jordan Werthman (2019).
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+
+use rust8::assemble_file;
+use rust8::compile;
+use rust8::Platform;
+
+const USAGE: &str = "Usage: rust8-asm SOURCE [-o ROM] [--platform chip8|schip|xochip]";
+
+// Assembles a source file into a ROM which is written next to the source
+// (with a .ch8 extension) unless -o is given. Octo (.8o) sources are compiled
+// for the given platform (CHIP-8 by default).
+fn main() {
+    let mut source_path = None;
+    let mut rom_path = None;
+    let mut platform = Platform::default();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => {
+                rom_path = Some(PathBuf::from(
+                    args.next().unwrap_or_else(|| exit_with_usage()),
+                ))
+            }
+            "--platform" => {
+                platform = args
+                    .next()
+                    .and_then(|name| Platform::from_name(&name))
+                    .unwrap_or_else(|| exit_with_usage())
+            }
+            _ if source_path.is_none() => source_path = Some(PathBuf::from(arg)),
+            _ => exit_with_usage(),
+        }
+    }
+    let source_path = source_path.unwrap_or_else(|| exit_with_usage());
+    let rom_path = rom_path.unwrap_or_else(|| source_path.with_extension("ch8"));
+
+    let source = match fs::read_to_string(&source_path) {
+        Ok(source) => source,
+        Err(error) => exit_with_error(&source_path, error),
+    };
+    let result = if source_path
+        .extension()
+        .is_some_and(|extension| extension == "8o")
+    {
+        compile(&source, platform)
+            .map(|program| program.rom)
+            .map_err(|mut error| {
+                error.file = source_path.display().to_string();
+                error
+            })
+    } else {
+        assemble_file(&source, &source_path)
+    };
+    let rom = match result {
+        Ok(rom) => rom,
+        Err(error) => {
+            eprintln!("{}", error);
+            process::exit(1);
+        }
+    };
+    if let Err(error) = fs::write(&rom_path, &rom) {
+        exit_with_error(&rom_path, error);
+    }
+}
+
+fn exit_with_error(path: &Path, error: std::io::Error) -> ! {
+    eprintln!("{}: {}", path.display(), error);
+    process::exit(1);
+}
+
+fn exit_with_usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
@@ -0,0 +1,478 @@
+// Copyright of Jordan Werthman (2019).
+
+use std::env;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::ops::Range;
+use std::path::Path;
+use std::process;
+
+use rust8::compile;
+use rust8::disassemble;
+use rust8::Condition;
+use rust8::Config;
+use rust8::Machine;
+use rust8::Platform;
+use rust8::Program;
+use rust8::Quirks;
+use rust8::TickOutcome;
+
+const USAGE: &str = "Usage: rust8-dbg ROM [--platform NAME] [--quirks NAME]";
+const PROMPT: &str = "(rust8) ";
+const HELP: &str = "\
+break [ADDRESS]      Stop when PC reaches ADDRESS (or list breakpoints)
+break if CONDITION   Stop when CONDITION becomes true e.g. break if VF == 1
+watch ADDRESS [N]    Stop after a write to N bytes (default 1) at ADDRESS
+delete ADDRESS       Remove the breakpoint at ADDRESS
+delete if CONDITION  Remove the conditional breakpoint
+unwatch ADDRESS [N]  Remove the watchpoint
+step [N]             Execute N instructions (default 1)
+back [N]             Undo the last N instructions executed (default 1)
+continue             Execute until a breakpoint, error, exit or key wait
+regs                 Show PC, V0-VF, I, the stack pointer and timers
+mem ADDRESS LENGTH   Show LENGTH bytes of memory from ADDRESS
+stack                Show the return addresses on the stack
+disasm [ADDRESS] [N] Disassemble N instructions from ADDRESS (default PC)
+set REG VALUE        Set V0-VF, I or PC e.g. set V3 0x10
+key KEY              Toggle whether hexadecimal KEY is held down
+quit                 Exit the debugger
+An empty line repeats the previous command.";
+
+// The timers are advanced as if running 600 instructions per second (10 per
+// 60Hz frame) so that delay loops terminate when single stepping.
+const STEP_DURATION: f64 = 1.0 / 600.0;
+const DISASM_COUNT: usize = 10;
+const REWIND_CAPACITY: usize = 10_000;
+
+// The program an Octo (.8o) ROM was compiled from.
+struct Source {
+    program: Program,
+    lines: Vec<String>,
+}
+
+struct Debugger {
+    machine: Machine,
+    source: Option<Source>,
+}
+
+// Debugs a ROM (or an Octo source file which is compiled first) from a
+// command-line prompt; see HELP for the commands.
+fn main() {
+    let mut path = None;
+    let mut platform = Platform::default();
+    let mut quirks = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--platform" => {
+                platform = args
+                    .next()
+                    .and_then(|name| Platform::from_name(&name))
+                    .unwrap_or_else(|| exit_with_usage())
+            }
+            "--quirks" => {
+                quirks = Some(
+                    args.next()
+                        .and_then(|name| Quirks::from_name(&name))
+                        .unwrap_or_else(|| exit_with_usage()),
+                )
+            }
+            _ if path.is_none() => path = Some(arg),
+            _ => exit_with_usage(),
+        }
+    }
+    let path = path.unwrap_or_else(|| exit_with_usage());
+    let quirks = quirks.unwrap_or_else(|| platform.default_quirks());
+
+    let (rom, source) = match load(Path::new(&path), platform) {
+        Ok(loaded) => loaded,
+        Err(error) => {
+            eprintln!("{}", error);
+            process::exit(1);
+        }
+    };
+
+    let mut config = Config::new(platform);
+    config.quirks = quirks;
+    config.rewind = REWIND_CAPACITY;
+    let mut machine = Machine::new(config);
+    if let Err(error) = machine.load_rom(&rom) {
+        eprintln!("{}: {}", path, error);
+        process::exit(1);
+    }
+    let mut debugger = Debugger { machine, source };
+    println!(
+        "Loaded {} bytes from {}. Type 'help' for commands.",
+        rom.len(),
+        path
+    );
+    debugger.show_location();
+
+    let stdin = io::stdin();
+    let mut previous = String::new();
+    loop {
+        print!("{}", PROMPT);
+        io::stdout().flush().expect("Unable to write to stdout.");
+
+        let mut line = String::new();
+        if stdin
+            .lock()
+            .read_line(&mut line)
+            .expect("Unable to read stdin.")
+            == 0
+        {
+            break;
+        }
+        let line = if line.trim().is_empty() {
+            previous.clone()
+        } else {
+            line.trim().to_string()
+        };
+
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.first() {
+            None => continue,
+            Some(&"quit") | Some(&"q") => break,
+            Some(_) => {
+                if let Err(message) = debugger.execute(&words) {
+                    println!("{}", message);
+                }
+            }
+        }
+        previous = line;
+    }
+}
+
+// Reads the ROM at |path| compiling it if it is an Octo source file.
+fn load(path: &Path, platform: Platform) -> Result<(Vec<u8>, Option<Source>), String> {
+    if path.extension().is_some_and(|extension| extension == "8o") {
+        let text =
+            fs::read_to_string(path).map_err(|error| format!("{}: {}", path.display(), error))?;
+        let program = compile(&text, platform).map_err(|mut error| {
+            error.file = path.display().to_string();
+            error.to_string()
+        })?;
+        let rom = program.rom.clone();
+        let lines = text.lines().map(String::from).collect();
+        Ok((rom, Some(Source { program, lines })))
+    } else {
+        let rom = fs::read(path).map_err(|error| format!("{}: {}", path.display(), error))?;
+        Ok((rom, None))
+    }
+}
+
+// Parses a decimal or 0x prefixed hexadecimal number.
+fn parse_number(value: &str) -> Result<usize, String> {
+    let parsed = match value.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    parsed.map_err(|_| format!("Invalid number: {}", value))
+}
+
+fn argument(words: &[&str], index: usize) -> Result<usize, String> {
+    let word = words
+        .get(index)
+        .ok_or_else(|| format!("Missing argument; see 'help' for '{}'.", words[0]))?;
+    parse_number(word)
+}
+
+fn parse_condition(words: &[&str]) -> Result<Condition, String> {
+    let text = words.join(" ");
+    Condition::parse(&text).ok_or_else(|| format!("Invalid condition: {}", text))
+}
+
+// The range given by "watch ADDRESS [LENGTH]" within a memory of |size|
+// bytes.
+fn watch_range(words: &[&str], size: usize) -> Result<Range<usize>, String> {
+    let address = argument(words, 1)?;
+    let length = if words.len() > 2 {
+        argument(words, 2)?
+    } else {
+        1
+    };
+    match address.checked_add(length) {
+        Some(end) if end <= size => Ok(address..end),
+        _ => Err(format!("Memory ends at 0x{:X}.", size)),
+    }
+}
+
+impl Debugger {
+    fn execute(&mut self, words: &[&str]) -> Result<(), String> {
+        match words[0] {
+            "help" | "h" => println!("{}", HELP),
+            "break" | "b" if words.len() == 1 => {
+                for address in self.machine.cpu().breakpoints() {
+                    println!("break 0x{:03X}", address);
+                }
+                for condition in self.machine.cpu().conditions() {
+                    println!("break if {}", condition);
+                }
+                for range in self.machine.cpu().watchpoints() {
+                    println!("watch 0x{:03X} {}", range.start, range.len());
+                }
+            }
+            "break" | "b" if words[1] == "if" => {
+                let condition = parse_condition(&words[2..])?;
+                self.machine.cpu_mut().add_condition(condition);
+            }
+            "break" | "b" => {
+                let address = argument(words, 1)?;
+                if !self.machine.cpu_mut().add_breakpoint(address) {
+                    return Err(format!("Breakpoint already set at 0x{:03X}.", address));
+                }
+            }
+            "delete" | "d" if words.get(1) == Some(&"if") => {
+                let condition = parse_condition(&words[2..])?;
+                if !self.machine.cpu_mut().remove_condition(&condition) {
+                    return Err(format!("No breakpoint if {}.", condition));
+                }
+            }
+            "watch" | "w" => {
+                let range = watch_range(words, self.machine.cpu().memory().len())?;
+                self.machine.cpu_mut().add_watchpoint(range);
+            }
+            "unwatch" => {
+                let range = watch_range(words, self.machine.cpu().memory().len())?;
+                if !self.machine.cpu_mut().remove_watchpoint(&range) {
+                    return Err(format!("No watchpoint at 0x{:03X}.", range.start));
+                }
+            }
+            "delete" | "d" => {
+                let address = argument(words, 1)?;
+                if !self.machine.cpu_mut().remove_breakpoint(address) {
+                    return Err(format!("No breakpoint at 0x{:03X}.", address));
+                }
+            }
+            "step" | "s" => {
+                let count = if words.len() > 1 {
+                    argument(words, 1)?
+                } else {
+                    1
+                };
+                self.run(Some(count));
+            }
+            "back" => {
+                let count = if words.len() > 1 {
+                    argument(words, 1)?
+                } else {
+                    1
+                };
+                let undone = (0..count).take_while(|_| self.machine.step_back()).count();
+                if undone < count {
+                    println!("Only {} instructions could be undone.", undone);
+                }
+                self.show_location();
+            }
+            "continue" | "c" => self.run(None),
+            "regs" | "r" => self.show_registers(),
+            "mem" | "m" => {
+                let address = argument(words, 1)?;
+                let length = argument(words, 2)?;
+                self.show_memory(address, length)?;
+            }
+            "stack" => {
+                let stack = self.machine.cpu().stack();
+                if stack.is_empty() {
+                    println!("The stack is empty.");
+                }
+                for (depth, address) in stack.iter().enumerate().rev() {
+                    println!("#{} 0x{:03X}", depth, address);
+                }
+            }
+            "disasm" | "l" => {
+                let address = if words.len() > 1 {
+                    argument(words, 1)?
+                } else {
+                    self.machine.cpu().pc()
+                };
+                let count = if words.len() > 2 {
+                    argument(words, 2)?
+                } else {
+                    DISASM_COUNT
+                };
+                self.show_disassembly(address, count);
+            }
+            "set" => {
+                let register = words
+                    .get(1)
+                    .ok_or("Missing register; see 'help' for 'set'.")?;
+                let value = argument(words, 2)?;
+                self.set(register, value)?;
+            }
+            "key" => {
+                let key = argument(words, 1)?;
+                if key > 0xF {
+                    return Err(format!("Invalid key: {}", words[1]));
+                }
+                if self.machine.key_pressed(key as u8) {
+                    self.machine.release_key(key as u8);
+                    println!("Key {:X} is released.", key);
+                } else {
+                    self.machine.press_key(key as u8);
+                    println!("Key {:X} is held down.", key);
+                }
+            }
+            command => return Err(format!("Unknown command: {} (try 'help')", command)),
+        }
+        Ok(())
+    }
+
+    // Executes |count| instructions (or until stopped if None).
+    fn run(&mut self, count: Option<usize>) {
+        let mut executed = 0;
+        loop {
+            if count == Some(executed) {
+                break;
+            }
+            let outcome = self.machine.step();
+            self.machine.bus_mut().update_timers(STEP_DURATION);
+            match outcome {
+                Ok(TickOutcome::Executed) => executed += 1,
+                Ok(TickOutcome::Breakpoint) => {
+                    println!("Breakpoint at 0x{:03X}.", self.machine.cpu().pc());
+                    break;
+                }
+                Ok(TickOutcome::Watchpoint(address)) => {
+                    println!(
+                        "Wrote 0x{:02X} to 0x{:03X}.",
+                        self.machine.cpu().memory()[address],
+                        address
+                    );
+                    break;
+                }
+                Ok(TickOutcome::Condition(condition)) => {
+                    println!("Stopped as {}.", condition);
+                    break;
+                }
+                Ok(TickOutcome::WaitingForKey) => {
+                    println!("Waiting for a key press (use 'key').");
+                    break;
+                }
+                Ok(TickOutcome::Exited) => {
+                    println!("The ROM exited.");
+                    return;
+                }
+                Err(error) => {
+                    println!("{}", error);
+                    return;
+                }
+            }
+        }
+        self.show_location();
+    }
+
+    fn set(&mut self, register: &str, value: usize) -> Result<(), String> {
+        let register = register.to_ascii_uppercase();
+        let size = self.machine.cpu().memory().len();
+        match register.as_str() {
+            "PC" | "I" if value >= size => {
+                return Err(format!(
+                    "{} can't be 0x{:X} (memory ends at 0x{:X}).",
+                    register, value, size
+                ));
+            }
+            "PC" => self.machine.cpu_mut().set_pc(value),
+            "I" => self.machine.cpu_mut().set_i(value),
+            _ => {
+                let x = register
+                    .strip_prefix('V')
+                    .filter(|digit| digit.len() == 1)
+                    .and_then(|digit| usize::from_str_radix(digit, 16).ok())
+                    .ok_or_else(|| format!("Unknown register: {}", register))?;
+                if value > 0xFF {
+                    return Err(format!("V{:X} can't hold 0x{:X}.", x, value));
+                }
+                self.machine.cpu_mut().set_register(x, value as u8);
+            }
+        }
+        Ok(())
+    }
+
+    fn show_location(&self) {
+        self.show_disassembly(self.machine.cpu().pc(), 1);
+    }
+
+    fn show_registers(&self) {
+        println!(
+            "PC: 0x{:03X}  I: 0x{:03X}  SP: {}",
+            self.machine.cpu().pc(),
+            self.machine.cpu().i(),
+            self.machine.cpu().stack().len()
+        );
+        for (row, registers) in self.machine.cpu().registers().chunks(8).enumerate() {
+            let values: Vec<String> = registers
+                .iter()
+                .enumerate()
+                .map(|(x, value)| format!("V{:X}: 0x{:02X}", row * 8 + x, value))
+                .collect();
+            println!("{}", values.join("  "));
+        }
+        println!(
+            "DT: {}  ST: {}",
+            self.machine.bus().delay_timer(),
+            self.machine.bus().sound_timer()
+        );
+    }
+
+    fn show_memory(&self, address: usize, length: usize) -> Result<(), String> {
+        let memory = self.machine.cpu().memory();
+        let end = match address.checked_add(length) {
+            Some(end) if end <= memory.len() => end,
+            _ => return Err(format!("Memory ends at 0x{:X}.", memory.len())),
+        };
+        for (row, bytes) in memory[address..end].chunks(16).enumerate() {
+            let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+            println!("0x{:03X}: {}", address + row * 16, hex.join(" "));
+        }
+        Ok(())
+    }
+
+    // Lists |count| instructions from |address| marking PC with '>' and
+    // breakpoints with '*' along with the Octo source line (if known).
+    fn show_disassembly(&self, address: usize, count: usize) {
+        let memory = self.machine.cpu().memory();
+        if address >= memory.len() {
+            println!("0x{:03X} is outside of memory.", address);
+            return;
+        }
+        let end = address
+            .saturating_add(count.saturating_mul(4))
+            .min(memory.len());
+
+        for (address, instruction) in disassemble(&memory[address..end], address)
+            .into_iter()
+            .take(count)
+        {
+            let pc = if address == self.machine.cpu().pc() {
+                '>'
+            } else {
+                ' '
+            };
+            let breakpoint = if self.machine.cpu().breakpoints().contains(&address) {
+                '*'
+            } else {
+                ' '
+            };
+            let line = format!("{}{} 0x{:03X}: {}", pc, breakpoint, address, instruction);
+
+            match self.source_line(address) {
+                Some((number, text)) => println!("{:<32}# {}: {}", line, number, text.trim()),
+                None => println!("{}", line),
+            }
+        }
+    }
+
+    fn source_line(&self, address: usize) -> Option<(usize, &str)> {
+        let source = self.source.as_ref()?;
+        let number = source.program.line(address)?;
+        Some((number, source.lines.get(number - 1)?))
+    }
+}
+
+fn exit_with_usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
@@ -0,0 +1,58 @@
+// Copyright of Jordan Werthman (2019).
+
+use std::env;
+use std::fs;
+use std::process;
+
+use rust8::Listing;
+
+const USAGE: &str = "Usage: rust8-disasm ROM [--base ADDRESS]";
+const DEFAULT_BASE: usize = 0x200;
+
+// Prints a listing of a ROM, telling code apart from data by tracing the
+// paths reachable from the start of the ROM. The ROM is assumed to be loaded
+// at 0x200 unless --base is given (e.g. 0x600 for ETI-660 ROMs).
+fn main() {
+    let mut path = None;
+    let mut base = DEFAULT_BASE;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--base" => {
+                base = args
+                    .next()
+                    .and_then(|value| parse_address(&value))
+                    .unwrap_or_else(|| exit_with_usage())
+            }
+            _ if path.is_none() => path = Some(arg),
+            _ => exit_with_usage(),
+        }
+    }
+    let path = path.unwrap_or_else(|| exit_with_usage());
+
+    let rom = match fs::read(&path) {
+        Ok(rom) => rom,
+        Err(error) => {
+            eprintln!("Unable to read {}: {}", path, error);
+            process::exit(1);
+        }
+    };
+
+    println!("; {}: {} bytes loaded at 0x{:03X}", path, rom.len(), base);
+    print!("{}", Listing::new(&rom, base));
+}
+
+// Parses a decimal or 0x prefixed hexadecimal address.
+fn parse_address(value: &str) -> Option<usize> {
+    if let Some(hex) = value.strip_prefix("0x") {
+        usize::from_str_radix(hex, 16).ok()
+    } else {
+        value.parse().ok()
+    }
+}
+
+fn exit_with_usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
@@ -0,0 +1,275 @@
+// Copyright of Jordan Werthman (2019).
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::process;
+
+use rust8::compile;
+use rust8::Config;
+use rust8::Coverage;
+use rust8::Listing;
+use rust8::Machine;
+use rust8::Platform;
+use rust8::Program;
+use rust8::Quirks;
+use rust8::Screen;
+use rust8::TickOutcome;
+use rust8::Timing;
+
+const USAGE: &str = "\
+Usage: rust8-run ROM [--frames N] [--keys SCRIPT] [--dump-screen FILE]
+                     [--platform NAME] [--quirks NAME] [--timing NAME]
+                     [--speed N] [--seed N] [--trace FILE] [--profile]
+                     [--coverage FILE]";
+const DEFAULT_FRAMES: usize = 600;
+// Runs repeat unless asked otherwise so that their screens can be compared.
+const DEFAULT_SEED: u64 = 0;
+// The addresses and subroutines listed by --profile.
+const PROFILE_LIMIT: usize = 20;
+// Where ROMs are loaded in memory.
+const ROM_BASE: usize = 0x200;
+
+// A change to the keypad before the given frame runs.
+struct KeyEvent {
+    frame: usize,
+    key: u8,
+    pressed: bool,
+}
+
+// Runs a ROM (or Octo source) without a window for a number of 60Hz frames
+// then prints the display as text or saves it to a file (as a PNG if the
+// name ends in .png). Keys are pressed and released by a script of lines
+// "FRAME press|release KEY" (e.g. "120 press 5") where # starts a comment.
+// --trace writes a line of JSON per instruction executed (see trace.rs)
+// for rust8-trace-diff to compare and --profile reports where the time was
+// spent (see profile.rs) once the run ends. --coverage saves which bytes of
+// the ROM were executed, drawn, read or written (see coverage.rs) as an
+// annotated listing or, if the name ends in .info or .lcov, as an lcov
+// tracefile of the Octo source. Exits with 1 if the CPU stops with an error
+// and 2 for bad arguments.
+fn main() {
+    let mut path = None;
+    let mut frames = DEFAULT_FRAMES;
+    let mut keys = None;
+    let mut dump = None;
+    let mut platform = Platform::default();
+    let mut quirks = None;
+    let mut timing = Timing::default();
+    let mut speed = None;
+    let mut seed = DEFAULT_SEED;
+    let mut trace = None;
+    let mut profile = false;
+    let mut coverage = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| exit_with_usage());
+        match arg.as_str() {
+            "--frames" => frames = value().parse().unwrap_or_else(|_| exit_with_usage()),
+            "--keys" => keys = Some(value()),
+            "--dump-screen" => dump = Some(value()),
+            "--platform" => {
+                platform = Platform::from_name(&value()).unwrap_or_else(|| exit_with_usage())
+            }
+            "--quirks" => {
+                quirks = Some(Quirks::from_name(&value()).unwrap_or_else(|| exit_with_usage()))
+            }
+            "--timing" => timing = Timing::from_name(&value()).unwrap_or_else(|| exit_with_usage()),
+            "--speed" => speed = Some(value().parse().unwrap_or_else(|_| exit_with_usage())),
+            "--seed" => seed = value().parse().unwrap_or_else(|_| exit_with_usage()),
+            "--trace" => trace = Some(value()),
+            "--profile" => profile = true,
+            "--coverage" => coverage = Some(value()),
+            _ if path.is_none() => path = Some(arg),
+            _ => exit_with_usage(),
+        }
+    }
+    let path = path.unwrap_or_else(|| exit_with_usage());
+
+    let program = load(Path::new(&path), platform).unwrap_or_else(|error| exit_with_error(&error));
+    if coverage.as_deref().is_some_and(is_lcov) && program.source_map.is_empty() {
+        exit_with_error("lcov coverage requires an Octo source file");
+    }
+    let mut events = match keys {
+        Some(script) => read_script(&script).unwrap_or_else(|error| exit_with_error(&error)),
+        None => Vec::new(),
+    };
+    events.sort_by_key(|event| event.frame);
+
+    let mut config = Config::new(platform);
+    config.quirks = quirks.unwrap_or(config.quirks);
+    config.timing = timing;
+    config.speed = speed.unwrap_or(config.speed);
+    config.seed = Some(seed);
+    let mut machine = Machine::new(config);
+    if let Err(error) = machine.load_rom(&program.rom) {
+        exit_with_error(&format!("{}: {}", path, error));
+    }
+    if let Some(file) = &trace {
+        let writer = File::create(file).unwrap_or_else(|error| {
+            exit_with_error(&format!("Unable to write {}: {}", file, error))
+        });
+        machine
+            .cpu_mut()
+            .start_trace(Box::new(BufWriter::new(writer)), program.source_map.clone());
+    }
+    if profile {
+        machine.cpu_mut().start_profile();
+    }
+    if coverage.is_some() {
+        machine.cpu_mut().start_coverage();
+    }
+
+    let mut instructions = 0;
+    let mut result = Ok(());
+    let mut events = events.iter().peekable();
+    for frame in 0..frames {
+        while let Some(event) = events.next_if(|event| event.frame <= frame) {
+            if event.pressed {
+                machine.press_key(event.key);
+            } else {
+                machine.release_key(event.key);
+            }
+        }
+
+        let report = machine.run_frame();
+        instructions += report.instructions;
+        match report.outcome {
+            Ok(TickOutcome::Exited) => {
+                eprintln!("ROM exited after {} frames.", frame + 1);
+                break;
+            }
+            Ok(_) => (),
+            Err(error) => {
+                result = Err(format!("CPU stopped in frame {}: {}", frame + 1, error));
+                break;
+            }
+        }
+    }
+    eprintln!("Executed {} instructions.", instructions);
+    if let Err(error) = machine.cpu_mut().stop_trace() {
+        let file = trace.unwrap_or_default();
+        exit_with_error(&format!("Unable to write {}: {}", file, error));
+    }
+
+    if let Some(profile) = machine.cpu().profile() {
+        eprint!("{}", profile.report(machine.cpu().memory(), PROFILE_LIMIT));
+    }
+    if let (Some(file), Some(recorded)) = (&coverage, machine.cpu().coverage()) {
+        if let Err(error) = save_coverage(recorded, file, &path, &program) {
+            exit_with_error(&format!("Unable to write {}: {}", file, error));
+        }
+    }
+
+    let screen = Screen::capture(machine.bus());
+    match dump {
+        Some(file) => {
+            if let Err(error) = save_screen(&screen, &file) {
+                exit_with_error(&format!("Unable to write {}: {}", file, error));
+            }
+        }
+        None => print!("{}", screen),
+    }
+
+    if let Err(error) = result {
+        exit_with_error(&error);
+    }
+}
+
+// Reads the ROM at |path| compiling it if it is an Octo source file (the
+// source map of a binary ROM is empty).
+fn load(path: &Path, platform: Platform) -> Result<Program, String> {
+    if path.extension().is_some_and(|extension| extension == "8o") {
+        let text =
+            fs::read_to_string(path).map_err(|error| format!("{}: {}", path.display(), error))?;
+        let program = compile(&text, platform).map_err(|mut error| {
+            error.file = path.display().to_string();
+            error.to_string()
+        })?;
+        Ok(program)
+    } else {
+        let rom = fs::read(path).map_err(|error| format!("{}: {}", path.display(), error))?;
+        Ok(Program {
+            rom,
+            source_map: BTreeMap::new(),
+        })
+    }
+}
+
+fn read_script(path: &str) -> Result<Vec<KeyEvent>, String> {
+    let text = fs::read_to_string(path).map_err(|error| format!("{}: {}", path, error))?;
+    let mut events = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let event = parse_event(line)
+            .ok_or_else(|| format!("{}:{}: expected FRAME press|release KEY", path, index + 1))?;
+        events.push(event);
+    }
+    Ok(events)
+}
+
+fn parse_event(line: &str) -> Option<KeyEvent> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    if words.len() != 3 {
+        return None;
+    }
+    let frame = words[0].parse().ok()?;
+    let pressed = match words[1] {
+        "press" => true,
+        "release" => false,
+        _ => return None,
+    };
+    let key = u8::from_str_radix(words[2].trim_start_matches("0x"), 16).ok()?;
+    if key > 0xF {
+        return None;
+    }
+    Some(KeyEvent {
+        frame,
+        key,
+        pressed,
+    })
+}
+
+fn is_lcov(file: &str) -> bool {
+    file.ends_with(".info") || file.ends_with(".lcov")
+}
+
+// Saves |coverage| of the ROM (or Octo source) at |path| to |file|.
+fn save_coverage(
+    coverage: &Coverage,
+    file: &str,
+    path: &str,
+    program: &Program,
+) -> std::io::Result<()> {
+    let text = if is_lcov(file) {
+        coverage.to_lcov(path, program)
+    } else {
+        Listing::new(&program.rom, ROM_BASE).annotate(coverage)
+    };
+    fs::write(file, text)
+}
+
+fn save_screen(screen: &Screen, path: &str) -> std::io::Result<()> {
+    if path.ends_with(".png") {
+        screen.write_png(&mut File::create(path)?)
+    } else {
+        fs::write(path, screen.to_string())
+    }
+}
+
+fn exit_with_error(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
+
+fn exit_with_usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
@@ -0,0 +1,120 @@
+// Copyright of Jordan Werthman (2019).
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::process;
+
+use rust8::first_divergence;
+use rust8::parse_line;
+use rust8::Value;
+
+const USAGE: &str = "Usage: rust8-trace-diff FIRST SECOND [--ignore FIELD,...] [--context N]";
+const DEFAULT_CONTEXT: usize = 3;
+
+// Compares two traces (as written by rust8-run --trace or converted from
+// another emulator) and reports the first record where they diverge along
+// with the records leading up to it. Only the fields present in both traces
+// are compared and --ignore skips others (e.g. "cycle,mnemonic"). Exits with
+// 0 if the traces match, 1 if they diverge and 2 for bad arguments.
+fn main() {
+    let mut paths = Vec::new();
+    let mut ignore = Vec::new();
+    let mut context = DEFAULT_CONTEXT;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--ignore" => {
+                let fields = args.next().unwrap_or_else(|| exit_with_usage());
+                ignore.extend(fields.split(',').map(String::from));
+            }
+            "--context" => {
+                context = args
+                    .next()
+                    .and_then(|value| value.parse().ok())
+                    .unwrap_or_else(|| exit_with_usage())
+            }
+            _ if paths.len() < 2 => paths.push(arg),
+            _ => exit_with_usage(),
+        }
+    }
+    if paths.len() != 2 {
+        exit_with_usage();
+    }
+
+    let (first_lines, first) = read_trace(&paths[0]);
+    let (second_lines, second) = read_trace(&paths[1]);
+    let divergence = match first_divergence(&first, &second, &ignore) {
+        Some(divergence) => divergence,
+        None => {
+            println!("The traces match ({} records).", first.len());
+            return;
+        }
+    };
+
+    let index = divergence.index;
+    if divergence.fields.is_empty() {
+        let (shorter, longer) = if first.len() < second.len() {
+            (&paths[0], &paths[1])
+        } else {
+            (&paths[1], &paths[0])
+        };
+        println!(
+            "The traces match for {} records after which {} ends but {} continues.",
+            index, shorter, longer
+        );
+    } else {
+        println!(
+            "The traces diverge at record {} (line {} of {} and line {} of {}):",
+            index, first_lines[index].0, paths[0], second_lines[index].0, paths[1]
+        );
+        for (field, a, b) in divergence.fields.iter() {
+            println!("  {}: {} vs {}", field, a, b);
+        }
+    }
+
+    println!();
+    let start = index.saturating_sub(context);
+    for (path, lines) in [(&paths[0], &first_lines), (&paths[1], &second_lines)].iter() {
+        println!("{}:", path);
+        for (record, (number, line)) in lines.iter().enumerate().take(index + 1).skip(start) {
+            let marker = if record == index { '>' } else { ' ' };
+            println!("{} {:6} {}", marker, number, line);
+        }
+    }
+    process::exit(1);
+}
+
+// The non-empty lines of a trace along with their line numbers and the
+// fields of each.
+type Trace = (Vec<(usize, String)>, Vec<BTreeMap<String, Value>>);
+
+// Reads the trace at |path|.
+fn read_trace(path: &str) -> Trace {
+    let text = fs::read_to_string(path).unwrap_or_else(|error| {
+        eprintln!("Unable to read {}: {}", path, error);
+        process::exit(2);
+    });
+    let lines: Vec<(usize, String)> = text
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| (index + 1, line.to_string()))
+        .collect();
+    let records = lines
+        .iter()
+        .map(|(number, line)| {
+            parse_line(line).unwrap_or_else(|error| {
+                eprintln!("{}:{}: {}", path, number, error);
+                process::exit(2);
+            })
+        })
+        .collect();
+    (lines, records)
+}
+
+fn exit_with_usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
@@ -0,0 +1,171 @@
+// Copyright of Jordan Werthman (2019).
+
+use std::fmt;
+
+// Models a conditional breakpoint on the CPU registers
+//
+// A condition compares a register (V0-VF or I) against a value e.g.
+// "VF == 1" or "I > 0xE00". The CPU stops when an instruction makes the
+// condition hold having not held before it so that continuing does not stop
+// again straight away.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Register {
+    V(usize),
+    I,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Condition {
+    pub register: Register,
+    pub comparison: Comparison,
+    pub value: usize,
+}
+
+impl Condition {
+    // Parses "REGISTER OPERATOR VALUE" (spaces are optional) where the value
+    // is decimal or 0x prefixed hexadecimal and the operator is one of:
+    // == != < <= > >=
+    pub fn parse(text: &str) -> Option<Condition> {
+        const OPERATORS: [&str; 6] = ["==", "!=", "<=", ">=", "<", ">"];
+        let (index, comparison) = OPERATORS
+            .iter()
+            .filter_map(|operator| text.find(operator).map(|index| (index, *operator)))
+            .min_by_key(|&(index, operator)| (index, usize::MAX - operator.len()))?;
+        let register = text[..index].trim();
+        let value = text[index + comparison.len()..].trim();
+
+        let register = match register.to_ascii_uppercase().as_str() {
+            "I" => Register::I,
+            name if name.len() == 2 && name.starts_with('V') => {
+                Register::V(usize::from_str_radix(&name[1..], 16).ok()?)
+            }
+            _ => return None,
+        };
+        let comparison = match comparison {
+            "==" => Comparison::Equal,
+            "!=" => Comparison::NotEqual,
+            "<" => Comparison::Less,
+            "<=" => Comparison::LessOrEqual,
+            ">" => Comparison::Greater,
+            ">=" => Comparison::GreaterOrEqual,
+            _ => return None,
+        };
+        let value = match value.strip_prefix("0x") {
+            Some(hex) => usize::from_str_radix(hex, 16).ok()?,
+            None => value.parse().ok()?,
+        };
+
+        Some(Condition {
+            register,
+            comparison,
+            value,
+        })
+    }
+
+    pub fn holds(&self, registers: &[u8], i: usize) -> bool {
+        let actual = match self.register {
+            Register::V(x) => registers[x] as usize,
+            Register::I => i,
+        };
+        match self.comparison {
+            Comparison::Equal => actual == self.value,
+            Comparison::NotEqual => actual != self.value,
+            Comparison::Less => actual < self.value,
+            Comparison::LessOrEqual => actual <= self.value,
+            Comparison::Greater => actual > self.value,
+            Comparison::GreaterOrEqual => actual >= self.value,
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.register {
+            Register::V(x) => write!(f, "V{:X}", x)?,
+            Register::I => write!(f, "I")?,
+        }
+        let comparison = match self.comparison {
+            Comparison::Equal => "==",
+            Comparison::NotEqual => "!=",
+            Comparison::Less => "<",
+            Comparison::LessOrEqual => "<=",
+            Comparison::Greater => ">",
+            Comparison::GreaterOrEqual => ">=",
+        };
+        write!(f, " {} 0x{:X}", comparison, self.value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_conditions() {
+        let condition = Condition::parse("VF == 1").unwrap();
+        assert_eq!(
+            condition,
+            Condition {
+                register: Register::V(0xF),
+                comparison: Comparison::Equal,
+                value: 1,
+            }
+        );
+        assert_eq!(condition.to_string(), "VF == 0x1");
+
+        let condition = Condition::parse("i>=0xE00").unwrap();
+        assert_eq!(condition.register, Register::I);
+        assert_eq!(condition.comparison, Comparison::GreaterOrEqual);
+        assert_eq!(condition.value, 0xE00);
+        assert_eq!(condition.to_string(), "I >= 0xE00");
+
+        let operators = [
+            ("v3 != 2", Comparison::NotEqual),
+            ("V3 < 2", Comparison::Less),
+            ("V3 <= 2", Comparison::LessOrEqual),
+            ("V3 > 2", Comparison::Greater),
+        ];
+        for &(text, comparison) in operators.iter() {
+            assert_eq!(Condition::parse(text).unwrap().comparison, comparison);
+        }
+
+        for text in [
+            "VF = 1",
+            "VG == 1",
+            "V10 == 1",
+            "PC == 0x200",
+            "VF == x",
+            "== 1",
+        ]
+        .iter()
+        {
+            assert_eq!(Condition::parse(text), None, "{}", text);
+        }
+    }
+
+    #[test]
+    fn compares_registers() {
+        let mut registers = [0; 16];
+        registers[0xA] = 5;
+        let holds = |text: &str, i: usize| Condition::parse(text).unwrap().holds(&registers, i);
+        assert!(holds("VA == 5", 0));
+        assert!(!holds("VA != 5", 0));
+        assert!(holds("VA < 6", 0));
+        assert!(holds("VA <= 5", 0));
+        assert!(!holds("VA > 5", 0));
+        assert!(holds("VA >= 5", 0));
+        assert!(holds("I > 0x300", 0x301));
+        assert!(!holds("I > 0x300", 0x300));
+    }
+}
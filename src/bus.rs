@@ -2,87 +2,230 @@
 
 use log::*;
 
+use crate::audio::Beeper;
+use crate::audio::Recording;
+use crate::audio::PATTERN_SIZE;
 use crate::memory::Memory;
+use crate::savestate::{Reader, StateError, Writer};
 
 // Models the address bus for I/O
 //
 // Handles interfacing the CPU with the I/O used to render the screen or
-// read in keypresses.
+// read in keypresses. The screen is 64x32 pixels, or 128x64 pixels while
+// the SUPER-CHIP high resolution mode is enabled, with two bitplanes giving
+// four colours on XO-CHIP. The sound timer drives the |beeper| which renders
+// PCM samples for the frontend to play.
 
-pub const WINDOW_HEIGHT: usize = 32;
-pub const WINDOW_WIDTH: usize = 64;
+pub const LORES_WIDTH: usize = 64;
+pub const LORES_HEIGHT: usize = 32;
+pub const HIRES_WIDTH: usize = 128;
+pub const HIRES_HEIGHT: usize = 64;
 
 const KEY_COUNT: usize = 16;
-const PIXEL_COUNT: usize = WINDOW_HEIGHT * WINDOW_WIDTH;
+const PLANE_COUNT: usize = 2;
+const PLANE_MASK: u8 = 0x3;
 
 const TIMER_FREQUENCY: f64 = 60.0;
 
 pub struct Bus {
-    display: [bool; PIXEL_COUNT],
+    // Each pixel holds one bit per bitplane (XO-CHIP draws to two planes)
+    display: Vec<u8>,
+    width: usize,
+    height: usize,
+    // Bitmask of the planes affected by drawing, clearing and scrolling
+    planes: u8,
     keys: [bool; KEY_COUNT],
     delay_timer: f64,
     sound_timer: f64,
+    beeper: Beeper,
+    recording: Option<Recording>,
+}
+
+impl Default for Bus {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Bus {
     pub fn new() -> Bus {
         Bus {
-            display: [false; PIXEL_COUNT],
+            display: vec![0; LORES_WIDTH * LORES_HEIGHT],
+            width: LORES_WIDTH,
+            height: LORES_HEIGHT,
+            planes: 0x1,
             keys: [false; KEY_COUNT],
             delay_timer: 0.0,
             sound_timer: 0.0,
+            beeper: Beeper::default(),
+            recording: None,
         }
     }
 
-    pub fn display(&self) -> &[bool; PIXEL_COUNT] {
-        return &self.display;
+    // The display as rows of width() pixels. Each pixel is a colour index
+    // (0-3) made up of one bit from each bitplane.
+    pub fn display(&self) -> &[u8] {
+        &self.display
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn hires(&self) -> bool {
+        self.width == HIRES_WIDTH
+    }
+
+    // Switches between the 64x32 and 128x64 resolutions clearing the screen.
+    pub fn set_hires(&mut self, hires: bool) {
+        let (width, height) = if hires {
+            (HIRES_WIDTH, HIRES_HEIGHT)
+        } else {
+            (LORES_WIDTH, LORES_HEIGHT)
+        };
+        self.width = width;
+        self.height = height;
+        self.display = vec![0; width * height];
+    }
+
+    // Sets the colour index of the pixel at |index| in display().
+    pub fn set_pixel(&mut self, index: usize, value: u8) {
+        self.display[index] = value & PLANE_MASK;
+    }
+
+    // Replaces the display (and resolution) e.g. when restoring a state.
+    pub fn load_display(&mut self, hires: bool, display: &[u8]) {
+        self.set_hires(hires);
+        self.display.copy_from_slice(display);
+    }
+
+    pub fn planes(&self) -> u8 {
+        self.planes
+    }
+
+    // Selects the bitplanes (as a bitmask) used for drawing (XO-CHIP).
+    pub fn set_planes(&mut self, planes: u8) {
+        self.planes = planes & PLANE_MASK;
+    }
+
+    // The number of selected bitplanes i.e. the number of sprites read
+    // from memory by each draw_display().
+    pub fn plane_count(&self) -> usize {
+        self.planes.count_ones() as usize
     }
 
     pub fn draw_display(
         &mut self,
-        memory: &mut Memory,
+        memory: &Memory,
         memory_offset: usize,
         (x, y): (usize, usize),
-        height: usize,
+        (width, height): (usize, usize),
+        clip: bool,
     ) -> bool {
         let mut pixel_flipped = false;
+        let row_bytes = width / 8;
 
-        for dy in 0..height {
-            for dx in 0..8 {
-                let (x, y) = ((x + dx) % WINDOW_WIDTH, (y + dy) % WINDOW_HEIGHT);
-                let byte: u8 = memory.data()[memory_offset + dy];
+        // The starting position always wraps; pixels drawn past the edges
+        // of the screen are either clipped or wrapped around.
+        let (x, y) = (x % self.width, y % self.height);
 
-                let index: usize = y * WINDOW_WIDTH + x;
-                let value: bool = ((byte << dx) & 0x80) != 0;
+        // Each selected plane is drawn with its own sprite, stored one after
+        // the other in memory starting with the lowest plane.
+        let mut memory_offset = memory_offset;
+        for plane in (0..PLANE_COUNT).map(|plane| 1 << plane) {
+            if self.planes & plane == 0 {
+                continue;
+            }
+
+            for dy in 0..height {
+                for dx in 0..width {
+                    let (x, y) = (x + dx, y + dy);
+                    if clip && (x >= self.width || y >= self.height) {
+                        continue;
+                    }
+
+                    let (x, y) = (x % self.width, y % self.height);
+                    let byte: u8 = memory.read(memory_offset + dy * row_bytes + dx / 8);
 
-                if value {
-                    pixel_flipped |= self.display[index];
-                    self.display[index] ^= true;
+                    let index: usize = y * self.width + x;
+                    let value: bool = ((byte << (dx % 8)) & 0x80) != 0;
+
+                    if value {
+                        pixel_flipped |= self.display[index] & plane != 0;
+                        self.display[index] ^= plane;
+                    }
                 }
             }
+            memory_offset += row_bytes * height;
         }
 
-        if log_enabled!(Level::Info) {
+        if log_enabled!(Level::Trace) {
             self.print_board();
         }
-        return pixel_flipped;
+        pixel_flipped
     }
 
+    // Clears the selected planes.
     pub fn clear_display(&mut self) {
-        for i in 0..PIXEL_COUNT {
-            self.display[i] = false;
+        for pixel in self.display.iter_mut() {
+            *pixel &= !self.planes;
         }
     }
 
-    pub fn any_key(&self) -> Option<u8> {
-        match self.keys.iter().enumerate().find(|&(_, &value)| value) {
-            Some((key, _)) => Some(key as u8),
-            None => None,
+    // Scrolls the display down by |rows| filling the top with blank rows.
+    pub fn scroll_down(&mut self, rows: usize) {
+        self.scroll(0, rows as isize);
+    }
+
+    // Scrolls the display up by |rows| filling the bottom with blank rows.
+    pub fn scroll_up(&mut self, rows: usize) {
+        self.scroll(0, -(rows as isize));
+    }
+
+    // Scrolls the display left by |columns| filling the right with blanks.
+    pub fn scroll_left(&mut self, columns: usize) {
+        self.scroll(-(columns as isize), 0);
+    }
+
+    // Scrolls the display right by |columns| filling the left with blanks.
+    pub fn scroll_right(&mut self, columns: usize) {
+        self.scroll(columns as isize, 0);
+    }
+
+    // Moves the selected planes by (dx, dy) leaving the others in place.
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        let previous = self.display.clone();
+        let (width, height) = (self.width as isize, self.height as isize);
+
+        for y in 0..height {
+            for x in 0..width {
+                let (from_x, from_y) = (x - dx, y - dy);
+                let moved = if from_x >= 0 && from_x < width && from_y >= 0 && from_y < height {
+                    previous[(from_y * width + from_x) as usize]
+                } else {
+                    0
+                };
+
+                let index = (y * width + x) as usize;
+                self.display[index] = (previous[index] & !self.planes) | (moved & self.planes);
+            }
         }
     }
 
+    pub fn any_key(&self) -> Option<u8> {
+        self.keys
+            .iter()
+            .position(|&value| value)
+            .map(|key| key as u8)
+    }
+
+    // Keys outside of the keypad (above 0xF) are never pressed.
     pub fn check_key(&self, key: u8) -> bool {
-        self.keys[key as usize]
+        self.keys.get(key as usize) == Some(&true)
     }
 
     pub fn set_key(&mut self, key: usize) {
@@ -101,6 +244,10 @@ impl Bus {
         self.delay_timer = duration as f64;
     }
 
+    pub fn sound_timer(&self) -> u8 {
+        self.sound_timer as u8
+    }
+
     pub fn sound_active(&self) -> bool {
         self.sound_timer > 0.0
     }
@@ -109,6 +256,74 @@ impl Bus {
         self.sound_timer = duration as f64;
     }
 
+    // The delay and sound timers including the fraction of a 60Hz tick
+    // which has elapsed.
+    pub fn timers(&self) -> (f64, f64) {
+        (self.delay_timer, self.sound_timer)
+    }
+
+    pub fn set_timers(&mut self, delay_timer: f64, sound_timer: f64) {
+        self.delay_timer = delay_timer;
+        self.sound_timer = sound_timer;
+    }
+
+    // Configures the tone played while the sound timer is active.
+    pub fn set_beeper(&mut self, frequency: f64, volume: f32) {
+        self.beeper.set_tone(frequency, volume);
+    }
+
+    // Loads the XO-CHIP audio pattern played while the sound timer is
+    // active in place of the configured tone.
+    pub fn set_audio_pattern(&mut self, pattern: [u8; PATTERN_SIZE]) {
+        self.beeper.set_pattern(pattern);
+    }
+
+    // Sets the XO-CHIP pitch register controlling the pattern playback rate.
+    pub fn set_pitch(&mut self, pitch: u8) {
+        self.beeper.set_pitch(pitch);
+    }
+
+    pub fn beeper(&self) -> &Beeper {
+        &self.beeper
+    }
+
+    // Starts capturing the samples returned by audio_samples().
+    pub fn start_recording(&mut self, sample_rate: u32) {
+        self.recording = Some(Recording::new(sample_rate));
+    }
+
+    // Stops capturing samples returning everything recorded so far.
+    pub fn take_recording(&mut self) -> Option<Recording> {
+        self.recording.take()
+    }
+
+    // Renders the next |duration| seconds of audio at |sample_rate| as mono
+    // PCM samples. This should be called before update_timers() advances
+    // the sound timer by the same duration.
+    pub fn audio_samples(&mut self, sample_rate: u32, duration: f64) -> Vec<f32> {
+        let active = self.sound_timer / TIMER_FREQUENCY;
+        let samples = self.beeper.render(sample_rate, duration, active);
+        if let Some(ref mut recording) = self.recording {
+            recording.push(&samples);
+        }
+        samples
+    }
+
+    // Renders |duration| seconds of silence (as if the sound timer were not
+    // active) for when the timers are not running e.g. while paused.
+    pub fn silence(&mut self, sample_rate: u32, duration: f64) -> Vec<f32> {
+        let samples = self.beeper.render(sample_rate, duration, 0.0);
+        if let Some(ref mut recording) = self.recording {
+            recording.push(&samples);
+        }
+        samples
+    }
+
+    // Decrements the timers by a single 60Hz tick.
+    pub fn tick_timers(&mut self) {
+        self.update_timers(1.0 / TIMER_FREQUENCY);
+    }
+
     pub fn update_timers(&mut self, dt: f64) {
         if self.delay_timer > 0.0 {
             self.delay_timer -= dt * TIMER_FREQUENCY;
@@ -124,10 +339,74 @@ impl Bus {
         }
     }
 
+    // Saves the display, keys, timers and XO-CHIP audio state.
+    pub fn write_state(&self, writer: &mut Writer) {
+        writer.u8(self.hires() as u8);
+        writer.u8(self.planes);
+        writer.bytes(&self.display);
+        let keys = (0..KEY_COUNT)
+            .filter(|&key| self.keys[key])
+            .fold(0, |keys, key| keys | 1 << key);
+        writer.u16(keys);
+        writer.f64(self.delay_timer);
+        writer.f64(self.sound_timer);
+        writer.u8(self.beeper.pitch());
+        match self.beeper.pattern() {
+            Some(pattern) => writer.bytes(pattern),
+            None => writer.bytes(&[]),
+        }
+    }
+
+    pub fn read_state(&mut self, reader: &mut Reader) -> Result<(), StateError> {
+        let hires = reader.u8()? != 0;
+        let planes = reader.u8()?;
+        let display = reader.bytes()?;
+        let keys = reader.u16()?;
+        let delay_timer = reader.f64()?;
+        let sound_timer = reader.f64()?;
+        let pitch = reader.u8()?;
+        let pattern = reader.bytes()?;
+
+        let size = if hires {
+            HIRES_WIDTH * HIRES_HEIGHT
+        } else {
+            LORES_WIDTH * LORES_HEIGHT
+        };
+        if display.len() != size {
+            return Err(StateError::Invalid("display size"));
+        }
+        if display.iter().any(|&pixel| pixel & !PLANE_MASK != 0) {
+            return Err(StateError::Invalid("display"));
+        }
+        if !(0.0..=256.0).contains(&delay_timer) || !(0.0..=256.0).contains(&sound_timer) {
+            return Err(StateError::Invalid("timer"));
+        }
+        if !pattern.is_empty() && pattern.len() != PATTERN_SIZE {
+            return Err(StateError::Invalid("audio pattern"));
+        }
+
+        self.load_display(hires, display);
+        self.set_planes(planes);
+        for key in 0..KEY_COUNT {
+            self.keys[key] = keys & 1 << key != 0;
+        }
+        self.set_timers(delay_timer, sound_timer);
+        self.beeper.reset();
+        self.beeper.set_pitch(pitch);
+        if !pattern.is_empty() {
+            let mut bytes = [0; PATTERN_SIZE];
+            bytes.copy_from_slice(pattern);
+            self.beeper.set_pattern(bytes);
+        }
+        Ok(())
+    }
+
     pub fn reset(&mut self) {
-        self.clear_display();
+        self.set_hires(false);
+        self.set_planes(0x1);
         self.set_delay_timer(0);
         self.set_sound_timer(0);
+        self.beeper.reset();
 
         for key in 0..KEY_COUNT {
             self.clear_key(key);
@@ -135,12 +414,12 @@ impl Bus {
     }
 
     fn print_board(&self) {
-        for y in 0..WINDOW_HEIGHT {
-            for x in 0..WINDOW_WIDTH {
-                let index: usize = y as usize * WINDOW_WIDTH + x as usize;
-                print!("{}", if self.display[index] { "#" } else { "_" });
-            }
-            println!();
+        for row in self.display.chunks(self.width) {
+            let row: String = row
+                .iter()
+                .map(|&pixel| if pixel != 0 { '#' } else { '_' })
+                .collect();
+            trace!("{}", row);
         }
     }
 }
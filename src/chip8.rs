@@ -3,10 +3,12 @@
 use log::*;
 use wasm_bindgen::prelude::*;
 
-use crate::bus::Bus;
-use crate::cpu::Cpu;
-
-use crate::bus::WINDOW_WIDTH;
+use crate::cpu::CpuError;
+use crate::cpu::TickOutcome;
+use crate::machine::Machine;
+use crate::platform::Platform;
+use crate::quirks::Quirks;
+use crate::timing::{Timing, VIP_CYCLES_PER_SECOND};
 
 #[wasm_bindgen]
 extern "C" {
@@ -46,8 +48,18 @@ static LOGGER: ConsoleLogger = ConsoleLogger;
 
 #[wasm_bindgen]
 pub struct Chip8 {
-    bus: Bus,
-    cpu: Option<Cpu>,
+    machine: Machine,
+    // Whether a ROM has been loaded and has not exited.
+    running: bool,
+    error: Option<String>,
+    // The COSMAC VIP cycles owed to the CPU by run_for().
+    cycle_budget: f64,
+}
+
+impl Default for Chip8 {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[wasm_bindgen]
@@ -62,30 +74,196 @@ impl Chip8 {
         info!("Random: {}", random());
 
         Chip8 {
-        	bus: Bus::new(),
-        	cpu: None,
+        	machine: Machine::default(),
+        	running: false,
+        	error: None,
+        	cycle_budget: 0.0,
         }
     }
 
-    pub fn check_pixel(&self, x: usize, y: usize) -> bool {
-        return self.bus.display()[y as usize * WINDOW_WIDTH + x as usize];
+    // The colour index (0-3) of the pixel at (x, y).
+    pub fn pixel(&self, x: usize, y: usize) -> u8 {
+        self.machine.framebuffer()[y * self.machine.width() + x]
+    }
+
+    pub fn width(&self) -> usize {
+        self.machine.width()
     }
 
-    pub fn load(&mut self, rom: Vec<u8>) {
+    pub fn height(&self) -> usize {
+        self.machine.height()
+    }
+
+    // Starts running |rom|. Throws a description of the problem if it does
+    // not fit in memory.
+    pub fn load(&mut self, rom: Vec<u8>) -> Result<(), JsValue> {
+    	self.machine
+    		.load_rom(&rom)
+    		.map_err(|error| JsValue::from_str(&error.to_string()))?;
     	info!("Loaded {} instructions.", rom.len() / 2);
-    	self.cpu = Some(Cpu::new(&rom));
+    	self.running = true;
+    	self.cycle_budget = 0.0;
+    	self.error = None;
+    	Ok(())
+    }
+
+    // Makes CXNN produce the same numbers each time the next ROM is loaded
+    // (instead of seeding from Math.random()).
+    pub fn set_seed(&mut self, seed: u64) {
+        let mut config = self.machine.config();
+        config.seed = Some(seed);
+        self.machine.set_config(config);
+    }
+
+    // Selects the platform used by the next call to load() along with its
+    // default quirks. Returns false if |name| is not a known platform.
+    pub fn set_platform(&mut self, name: &str) -> bool {
+        match Platform::from_name(name) {
+            Some(platform) => {
+                let mut config = self.machine.config();
+                config.platform = platform;
+                config.quirks = platform.default_quirks();
+                self.machine.set_config(config);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Selects the quirks preset used by the next call to load(). Returns
+    // false if |name| is not a known preset.
+    pub fn set_quirks(&mut self, name: &str) -> bool {
+        match Quirks::from_name(name) {
+            Some(quirks) => {
+                let mut config = self.machine.config();
+                config.quirks = quirks;
+                self.machine.set_config(config);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Serializes the running ROM's state (see Cpu::save_state()). Returns
+    // None if no ROM is running.
+    pub fn save_state(&self) -> Option<Vec<u8>> {
+        if self.running {
+            Some(self.machine.save_state())
+        } else {
+            None
+        }
+    }
+
+    // Restores a state from save_state() made with the ROM which is running.
+    // Throws a description of the problem if the state cannot be loaded.
+    pub fn load_state(&mut self, state: Vec<u8>) -> Result<(), JsValue> {
+        if !self.running {
+            return Err(JsValue::from_str("No ROM is running"));
+        }
+        self.machine
+            .load_state(&state)
+            .map_err(|error| JsValue::from_str(&error.to_string()))?;
+        self.error = None;
+        Ok(())
+    }
+
+    // Selects the timing ("unlimited" or "vip") used by the next call to
+    // load(). Returns false if |name| is not known.
+    pub fn set_timing(&mut self, name: &str) -> bool {
+        match Timing::from_name(name) {
+            Some(timing) => {
+                let mut config = self.machine.config();
+                config.timing = timing;
+                self.machine.set_config(config);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Runs the ROM for |seconds| of emulated time. With COSMAC VIP timing
+    // this executes the instructions the VIP would have in that time (and
+    // the CPU updates the timers), otherwise it is update() then tick().
+    pub fn run_for(&mut self, seconds: f64) {
+        if self.machine.cpu().timing() == Timing::Unlimited {
+            self.update(seconds);
+            self.tick();
+            return;
+        }
+
+        self.cycle_budget += seconds * VIP_CYCLES_PER_SECOND;
+        while self.cycle_budget > 0.0 && self.running && self.error.is_none() {
+            let cycles = self.machine.cpu().cycles();
+            self.tick();
+            let elapsed = self.machine.cpu().cycles() - cycles;
+            if elapsed == 0 {
+                break;
+            }
+            self.cycle_budget -= elapsed as f64;
+        }
+    }
+
+    // Describes the error that stopped the CPU (if any).
+    pub fn error(&self) -> Option<String> {
+        self.error.clone()
     }
 
     pub fn update(&mut self, timer_delta: f64) {
-        self.bus.update_timers(timer_delta);
+        self.machine.bus_mut().update_timers(timer_delta);
     }
 
     pub fn tick(&mut self) {
-    	if let Some(ref mut cpu) = self.cpu {
-    		console_log!("CPU tick!");
-    		cpu.tick(&mut self.bus);
+    	if self.error.is_some() {
+    		return;
+    	}
+
+    	if self.running {
+    		trace!("CPU tick!");
+    		let outcome = self.machine.step();
+    		self.handle_outcome(outcome);
     	} else {
     		console_log!("No CPU :(");
     	}
     }
+
+    // Sets the instructions executed by each run_frame() (unless using
+    // COSMAC VIP timing).
+    pub fn set_speed(&mut self, instructions_per_frame: usize) {
+        let mut config = self.machine.config();
+        config.speed = instructions_per_frame;
+        self.machine.set_config(config);
+    }
+
+    // Runs a 60Hz frame of instructions and ticks the timers (see
+    // Cpu::run_frame()) so it should be called 60 times a second in place
+    // of update() and tick(). Returns whether the display changed.
+    pub fn run_frame(&mut self) -> bool {
+        if !self.running || self.error.is_some() {
+            return false;
+        }
+        let report = self.machine.run_frame();
+        self.handle_outcome(report.outcome);
+        report.display_changed
+    }
+
+    // Whether the sound timer is active (and a tone should be playing).
+    pub fn sound_on(&self) -> bool {
+        self.machine.bus().sound_active()
+    }
+}
+
+impl Chip8 {
+    fn handle_outcome(&mut self, outcome: Result<TickOutcome, CpuError>) {
+        match outcome {
+            Ok(TickOutcome::Exited) => {
+                info!("ROM exited.");
+                self.running = false;
+            }
+            Ok(_) => (),
+            Err(error) => {
+                error!("CPU stopped: {}", error);
+                self.error = Some(error.to_string());
+            }
+        }
+    }
 }
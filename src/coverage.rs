@@ -0,0 +1,173 @@
+// Copyright of Jordan Werthman (2019).
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::ops::Range;
+
+use crate::memory::USER_OFFSET;
+use crate::octo::Program;
+
+// Models which bytes of memory a ROM has used
+//
+// While tracking coverage (see Cpu::start_coverage()) each byte of memory
+// records how it has been accessed: executed as (part of) an instruction,
+// drawn as a sprite by DXYN or read or written by an instruction which
+// loads or stores registers (FX33, FX55 and FX65 along with the XO-CHIP
+// 5XY2, 5XY3 and F002). The coverage can be shown alongside a listing of
+// the ROM (see Listing::annotate()) or exported for an Octo source as an
+// lcov tracefile (see to_lcov()).
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Executed,
+    Sprite,
+    Read,
+    Written,
+}
+
+const ACCESSES: [Access; 4] = [
+    Access::Executed,
+    Access::Sprite,
+    Access::Read,
+    Access::Written,
+];
+
+impl Access {
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+
+    // The letter shown by flags() for the access.
+    fn letter(self) -> char {
+        match self {
+            Access::Executed => 'X',
+            Access::Sprite => 'S',
+            Access::Read => 'R',
+            Access::Written => 'W',
+        }
+    }
+}
+
+pub struct Coverage {
+    // The Access bits set for each address.
+    accesses: Vec<u8>,
+}
+
+impl Coverage {
+    // Creates an empty coverage of a memory of |size| bytes.
+    pub fn new(size: usize) -> Coverage {
+        Coverage {
+            accesses: vec![0; size],
+        }
+    }
+
+    // Marks the |len| bytes from |address| as accessed by |access|. Bytes
+    // past the end of memory are ignored.
+    pub fn mark(&mut self, address: usize, len: usize, access: Access) {
+        let end = self.accesses.len().min(address.saturating_add(len));
+        for accesses in self.accesses[address.min(end)..end].iter_mut() {
+            *accesses |= access.bit();
+        }
+    }
+
+    pub fn has(&self, address: usize, access: Access) -> bool {
+        self.accesses
+            .get(address)
+            .is_some_and(|accesses| accesses & access.bit() != 0)
+    }
+
+    // The number of bytes in |addresses| accessed by |access|.
+    pub fn count(&self, addresses: Range<usize>, access: Access) -> usize {
+        addresses
+            .filter(|&address| self.has(address, access))
+            .count()
+    }
+
+    // Describes the accesses to any of |addresses| as "XSRW" (executed,
+    // sprite, read, written) with '.' for those which did not happen.
+    pub fn flags(&self, addresses: Range<usize>) -> String {
+        ACCESSES
+            .iter()
+            .map(|&access| {
+                if addresses.clone().any(|address| self.has(address, access)) {
+                    access.letter()
+                } else {
+                    '.'
+                }
+            })
+            .collect()
+    }
+
+    // Writes an lcov tracefile for the source file |source| which was
+    // compiled into |program|. A line is hit if any byte it produced was
+    // accessed so sprite data is covered by drawing it.
+    pub fn to_lcov(&self, source: &str, program: &Program) -> String {
+        let mut lines: BTreeMap<usize, bool> = BTreeMap::new();
+        let starts: Vec<(usize, usize)> = program
+            .source_map
+            .iter()
+            .map(|(&address, &line)| (address, line))
+            .collect();
+        for (index, &(start, line)) in starts.iter().enumerate() {
+            let end = starts
+                .get(index + 1)
+                .map_or(USER_OFFSET + program.rom.len(), |&(next, _)| next);
+            let hit = (start..end).any(|address| {
+                self.accesses
+                    .get(address)
+                    .is_some_and(|&accesses| accesses != 0)
+            });
+            *lines.entry(line).or_insert(false) |= hit;
+        }
+
+        let mut lcov = format!("TN:\nSF:{}\n", source);
+        for (line, &hit) in lines.iter() {
+            let _ = writeln!(lcov, "DA:{},{}", line, hit as u8);
+        }
+        let hits = lines.values().filter(|&&hit| hit).count();
+        let _ = writeln!(lcov, "LF:{}\nLH:{}\nend_of_record", lines.len(), hits);
+        lcov
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn marks_accesses() {
+        let mut coverage = Coverage::new(0x1000);
+        coverage.mark(0x200, 2, Access::Executed);
+        coverage.mark(0x300, 3, Access::Sprite);
+        coverage.mark(0x301, 1, Access::Written);
+        coverage.mark(0xFFE, 4, Access::Read);
+
+        assert!(coverage.has(0x201, Access::Executed));
+        assert!(!coverage.has(0x202, Access::Executed));
+        assert_eq!(coverage.count(0x300..0x310, Access::Sprite), 3);
+        assert_eq!(coverage.flags(0x300..0x302), ".S.W");
+        assert_eq!(coverage.flags(0x200..0x202), "X...");
+        assert_eq!(coverage.flags(0x400..0x401), "....");
+        assert_eq!(coverage.count(0xF00..0x1000, Access::Read), 2);
+    }
+
+    #[test]
+    fn exports_lcov() {
+        let mut coverage = Coverage::new(0x1000);
+        coverage.mark(0x200, 2, Access::Executed);
+        coverage.mark(0x209, 1, Access::Sprite);
+        // Two statements on line 1, one on line 2 and four bytes of data on
+        // line 4 of which only the last was drawn.
+        let program = Program {
+            rom: vec![0; 10],
+            source_map: [(0x200, 1), (0x202, 1), (0x204, 2), (0x206, 4)]
+                .iter()
+                .cloned()
+                .collect(),
+        };
+        assert_eq!(
+            coverage.to_lcov("game.8o", &program),
+            "TN:\nSF:game.8o\nDA:1,1\nDA:2,0\nDA:4,1\nLF:3\nLH:2\nend_of_record\n"
+        );
+    }
+}
@@ -2,207 +2,973 @@
 
 use log::*;
 
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::io::Write;
+use std::ops::Range;
+
+use crate::audio::PATTERN_SIZE;
+use crate::breakpoint::Condition;
 use crate::bus::Bus;
+use crate::coverage::{Access, Coverage};
+use crate::instruction::decode;
+use crate::instruction::Instruction;
+use crate::instruction::LONG_OPCODE;
+use crate::memory::LoadError;
 use crate::memory::Memory;
+use crate::memory::BIG_FONT_OFFSET;
 use crate::memory::USER_OFFSET;
+use crate::platform::Platform;
+use crate::profile::Profile;
+use crate::quirks::LoadStoreQuirk;
+use crate::quirks::Quirks;
+use crate::random::{RandomSource, Xorshift};
+use crate::rewind;
+use crate::rewind::{Delta, DisplayChange, Rewind};
+use crate::savestate;
+use crate::savestate::{Reader, StateError, Writer};
+use crate::timing;
+use crate::timing::{Timing, VIP_CYCLES_PER_FRAME, VIP_INTERRUPT_CYCLES};
+use crate::trace::{Record, Tracer};
 
 // Models the CHIP-8 processor
 //
 // Given |memory| this processor interprets instructions in a ROM and
 // executes one each tick() of the CPU. On each tick() the CPU interacts
-// with I/O over the |bus|. The CHIP-8 CPU has 35 opcodes in total with
-// an explaination of each found at: https://en.wikipedia.org/wiki/CHIP-8
+// with I/O over the |bus|. Opcodes with ambiguous behaviour follow |quirks|.
+// The CHIP-8 CPU has 35 opcodes in total with an explaination of each found
+// at: https://en.wikipedia.org/wiki/CHIP-8 while the SUPER-CHIP and XO-CHIP
+// extensions enabled by |platform| are described at:
+// http://devernay.free.fr/hacks/chip8/schip.txt and
+// http://johnearnest.github.io/Octo/docs/XO-ChipSpecification.html
 
 enum PcOp {
     Next,        // Moves PC to the next instruction in memory
     Skip,        // Skips the current and next instruction
     Jump(usize), // Jumps to the given location in memory
     Halt,        // Remains on the current instruction
+    Exit,        // Stops the interpreter (SUPER-CHIP)
+}
+
+// The result of successfully executing a single tick() of the CPU.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TickOutcome {
+    Executed,             // The instruction at PC was executed
+    WaitingForKey,        // Execution is blocked on FX0A until a key is pressed
+    Exited,               // The ROM exited with 00FD
+    Breakpoint,           // PC reached a breakpoint (which has not been executed)
+    Watchpoint(usize),    // The instruction wrote to the given watched address
+    Condition(Condition), // The instruction made a breakpoint condition hold
+}
+
+// A summary of the instructions executed by run_frame().
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameReport {
+    pub instructions: usize,
+    pub display_changed: bool,
+    pub sound_on: bool,
+    // How the last instruction ended: Executed if the whole frame ran,
+    // otherwise why it stopped early (waiting for a key, a breakpoint etc).
+    pub outcome: Result<TickOutcome, CpuError>,
+}
+
+// Errors raised while executing a ROM. Each carries the PC of the failing
+// instruction (and the opcode when one was fetched) so it can be reported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CpuError {
+    UnknownInstruction {
+        pc: usize,
+        opcode: u16,
+    },
+    StackOverflow {
+        pc: usize,
+        opcode: u16,
+    },
+    StackUnderflow {
+        pc: usize,
+        opcode: u16,
+    },
+    MemoryOutOfBounds {
+        pc: usize,
+        opcode: u16,
+        address: usize,
+    },
+    ProgramCounterOutOfBounds {
+        pc: usize,
+    },
 }
 
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CpuError::UnknownInstruction { pc, opcode } => {
+                write!(f, "Unknown instruction 0x{:04X} at 0x{:03X}", opcode, pc)
+            }
+            CpuError::StackOverflow { pc, opcode } => {
+                write!(f, "Stack overflow by 0x{:04X} at 0x{:03X}", opcode, pc)
+            }
+            CpuError::StackUnderflow { pc, opcode } => {
+                write!(f, "Stack underflow by 0x{:04X} at 0x{:03X}", opcode, pc)
+            }
+            CpuError::MemoryOutOfBounds {
+                pc,
+                opcode,
+                address,
+            } => write!(
+                f,
+                "Out of bounds access to 0x{:X} by 0x{:04X} at 0x{:03X}",
+                address, opcode, pc
+            ),
+            CpuError::ProgramCounterOutOfBounds { pc } => {
+                write!(f, "Program counter out of bounds at 0x{:X}", pc)
+            }
+        }
+    }
+}
+
+impl Error for CpuError {}
+
 const REG_COUNT: usize = 16;
+const FLAG_COUNT: usize = 16;
+
+// The state of the CPU before an instruction from which its Delta is made.
+struct Capture {
+    delta: Delta,
+    reg: [u8; REG_COUNT],
+    flags: [u8; FLAG_COUNT],
+}
 
 pub struct Cpu {
     pc: usize,
     reg: [u8; REG_COUNT],
     memory: Memory,
     i: usize,
+    // SUPER-CHIP/XO-CHIP "RPL user flags" persisted with FX75/FX85
+    flags: [u8; FLAG_COUNT],
+    platform: Platform,
+    quirks: Quirks,
+    breakpoints: BTreeSet<usize>,
+    // Each condition along with whether it held after the last instruction.
+    conditions: Vec<(Condition, bool)>,
+    // Deltas of recently executed instructions for step_back() if enabled.
+    rewind: Option<Rewind>,
+    // Identifies the ROM being run so that save states are not mixed up.
+    rom_hash: u64,
+    // Supplies the random numbers for CXNN.
+    random: Box<dyn RandomSource>,
+    timing: Timing,
+    // The machine cycles executed so far (with COSMAC VIP timing).
+    cycles: u64,
+    // Records each instruction executed while tracing.
+    tracer: Option<Tracer>,
+    // Counts the instructions executed while profiling.
+    profile: Option<Profile>,
+    // Records how each byte of memory is used while tracking coverage.
+    coverage: Option<Coverage>,
 }
 
 impl Cpu {
-    pub fn new(rom: &Vec<u8>) -> Cpu {
-        Cpu {
+    // Creates a CPU about to run |rom|. Fails if |rom| does not fit in the
+    // memory of |platform|.
+    pub fn new(rom: &[u8], platform: Platform, quirks: Quirks) -> Result<Cpu, LoadError> {
+        Ok(Cpu {
             pc: USER_OFFSET,
             reg: [0; REG_COUNT],
-            memory: Memory::new(rom),
+            memory: Memory::new(rom, platform.memory_size())?,
             i: 0,
+            flags: [0; FLAG_COUNT],
+            platform,
+            quirks,
+            breakpoints: BTreeSet::new(),
+            conditions: Vec::new(),
+            rewind: None,
+            rom_hash: savestate::hash(rom),
+            random: Box::new(Xorshift::from_entropy()),
+            timing: Timing::default(),
+            cycles: 0,
+            tracer: None,
+            profile: None,
+            coverage: None,
+        })
+    }
+
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn set_pc(&mut self, pc: usize) {
+        self.pc = pc;
+    }
+
+    pub fn registers(&self) -> &[u8] {
+        &self.reg
+    }
+
+    pub fn set_register(&mut self, x: usize, value: u8) {
+        self.reg[x] = value;
+    }
+
+    pub fn i(&self) -> usize {
+        self.i
+    }
+
+    pub fn set_i(&mut self, i: usize) {
+        self.i = i;
+    }
+
+    // The return addresses on the stack from the outermost call.
+    pub fn stack(&self) -> Vec<usize> {
+        self.memory.stack()
+    }
+
+    // The whole address space including the system memory below 0x200.
+    pub fn memory(&self) -> &[u8] {
+        self.memory.bytes()
+    }
+
+    pub fn platform(&self) -> Platform {
+        self.platform
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    // Replaces the source of random numbers used by CXNN.
+    pub fn set_random(&mut self, random: Box<dyn RandomSource>) {
+        self.random = random;
+    }
+
+    // Makes CXNN produce the same sequence of numbers for the same |seed|.
+    pub fn seed_random(&mut self, seed: u64) {
+        self.random = Box::new(Xorshift::new(seed));
+    }
+
+    pub fn timing(&self) -> Timing {
+        self.timing
+    }
+
+    // Selects how long instructions take (see timing.rs). With COSMAC VIP
+    // timing the CPU decrements the timers on the bus itself.
+    pub fn set_timing(&mut self, timing: Timing) {
+        self.timing = timing;
+    }
+
+    // The machine cycles executed (including waits for the vertical blank)
+    // while using COSMAC VIP timing.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    // Writes a record of the state before each instruction to |writer| (see
+    // trace.rs) until stop_trace(). Records include the source line of the
+    // instruction if it is in |source_map| (see Program).
+    pub fn start_trace(&mut self, writer: Box<dyn Write>, source_map: BTreeMap<usize, usize>) {
+        self.tracer = Some(Tracer::new(writer, source_map));
+    }
+
+    // Returns the first error writing the trace if there was one.
+    pub fn stop_trace(&mut self) -> io::Result<()> {
+        match self.tracer.take() {
+            Some(tracer) => tracer.finish(),
+            None => Ok(()),
+        }
+    }
+
+    // Starts counting the instructions executed (see profile.rs) from an
+    // empty profile.
+    pub fn start_profile(&mut self) {
+        self.profile = Some(Profile::new(self.memory.bytes().len()));
+    }
+
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    pub fn stop_profile(&mut self) -> Option<Profile> {
+        self.profile.take()
+    }
+
+    // Starts recording how each byte of memory is used (see coverage.rs)
+    // from an empty coverage.
+    pub fn start_coverage(&mut self) {
+        self.coverage = Some(Coverage::new(self.memory.bytes().len()));
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    pub fn stop_coverage(&mut self) -> Option<Coverage> {
+        self.coverage.take()
+    }
+
+    pub fn breakpoints(&self) -> &BTreeSet<usize> {
+        &self.breakpoints
+    }
+
+    // Stops execution (returning TickOutcome::Breakpoint) whenever an
+    // instruction moves PC to |address|. Returns false if already set.
+    pub fn add_breakpoint(&mut self, address: usize) -> bool {
+        self.breakpoints.insert(address)
+    }
+
+    // Returns false if there was no breakpoint at |address|.
+    pub fn remove_breakpoint(&mut self, address: usize) -> bool {
+        self.breakpoints.remove(&address)
+    }
+
+    pub fn watchpoints(&self) -> &[Range<usize>] {
+        self.memory.watchpoints()
+    }
+
+    // Stops execution (returning TickOutcome::Watchpoint) after any
+    // instruction which writes to memory in |range|.
+    pub fn add_watchpoint(&mut self, range: Range<usize>) {
+        self.memory.add_watchpoint(range);
+    }
+
+    // Returns false if |range| was not being watched.
+    pub fn remove_watchpoint(&mut self, range: &Range<usize>) -> bool {
+        self.memory.remove_watchpoint(range)
+    }
+
+    pub fn conditions(&self) -> Vec<Condition> {
+        self.conditions
+            .iter()
+            .map(|&(condition, _)| condition)
+            .collect()
+    }
+
+    // Stops execution (returning TickOutcome::Condition) after any
+    // instruction which makes |condition| hold when it did not before.
+    pub fn add_condition(&mut self, condition: Condition) {
+        let holds = condition.holds(&self.reg, self.i);
+        self.conditions.push((condition, holds));
+    }
+
+    // Returns false if |condition| was not set.
+    pub fn remove_condition(&mut self, condition: &Condition) -> bool {
+        let count = self.conditions.len();
+        self.conditions.retain(|(set, _)| set != condition);
+        self.conditions.len() != count
+    }
+
+    // Returns the first condition which holds now but did not before the
+    // last instruction.
+    fn check_conditions(&mut self) -> Option<Condition> {
+        let mut met = None;
+        for (condition, held) in self.conditions.iter_mut() {
+            let holds = condition.holds(&self.reg, self.i);
+            if holds && !*held && met.is_none() {
+                met = Some(*condition);
+            }
+            *held = holds;
+        }
+        met
+    }
+
+    // Records the last |capacity| instructions executed so that they can be
+    // undone with step_back().
+    pub fn enable_rewind(&mut self, capacity: usize) {
+        self.rewind = Some(Rewind::new(capacity));
+    }
+
+    // The number of instructions which can be undone with step_back().
+    pub fn rewind_len(&self) -> usize {
+        self.rewind.as_ref().map_or(0, Rewind::len)
+    }
+
+    // Undoes the last instruction executed restoring the CPU, memory and
+    // |bus| to their state before it. Returns false if there is no history.
+    pub fn step_back(&mut self, bus: &mut Bus) -> bool {
+        let delta = match self.rewind.as_mut().and_then(Rewind::pop) {
+            Some(delta) => delta,
+            None => return false,
+        };
+
+        self.pc = delta.pc;
+        self.i = delta.i;
+        self.memory.set_stack_pointer(delta.stack_pointer);
+        self.memory.undo(&delta.memory);
+        self.random.set_state(delta.random);
+        self.cycles = delta.cycles;
+        for (x, value) in delta.registers {
+            self.reg[x] = value;
+        }
+        for (x, value) in delta.flags {
+            self.flags[x] = value;
         }
+        match delta.display {
+            Some(DisplayChange::Pixels(pixels)) => {
+                for (index, value) in pixels {
+                    bus.set_pixel(index, value);
+                }
+            }
+            Some(DisplayChange::Resolution { hires, display }) => bus.load_display(hires, &display),
+            None => (),
+        }
+        bus.set_planes(delta.planes);
+        bus.set_timers(delta.delay_timer, delta.sound_timer);
+        for (condition, held) in self.conditions.iter_mut() {
+            *held = condition.holds(&self.reg, self.i);
+        }
+        true
     }
 
-    pub fn tick(&mut self, bus: &mut Bus) {
-        let instruction = self.memory.read_instruction(self.pc);
-        match self.execute(instruction, bus) {
-            PcOp::Next => self.pc = self.pc + 2,
-            PcOp::Skip => self.pc = self.pc + 4,
+    // Serializes the CPU (registers, PC, I, stack, memory and random state)
+    // along with the display, keys and timers on the |bus| (see savestate.rs).
+    pub fn save_state(&self, bus: &Bus) -> Vec<u8> {
+        let mut writer = Writer::default();
+        writer.u32(self.pc as u32);
+        writer.u32(self.i as u32);
+        writer.bytes(&self.reg);
+        writer.bytes(&self.flags);
+        writer.u64(self.random.state());
+        writer.u64(self.cycles);
+        self.memory.write_state(&mut writer);
+        bus.write_state(&mut writer);
+        writer.finish(self.platform, self.rom_hash)
+    }
+
+    // Restores a state from save_state() made while running the same ROM on
+    // the same platform. Nothing is changed if the state cannot be loaded.
+    pub fn load_state(&mut self, bus: &mut Bus, state: &[u8]) -> Result<(), StateError> {
+        let mut reader = Reader::new(state, self.platform, self.rom_hash)?;
+        let backup = self.save_state(bus);
+        if let Err(error) = self.read_state(&mut reader, bus) {
+            let mut reader = Reader::new(&backup, self.platform, self.rom_hash)
+                .expect("Unable to read backup state");
+            self.read_state(&mut reader, bus)
+                .expect("Unable to restore backup state");
+            return Err(error);
+        }
+
+        // History from before the state was loaded can no longer be undone.
+        if let Some(ref mut rewind) = self.rewind {
+            rewind.clear();
+        }
+        for (condition, held) in self.conditions.iter_mut() {
+            *held = condition.holds(&self.reg, self.i);
+        }
+        Ok(())
+    }
+
+    fn read_state(&mut self, reader: &mut Reader, bus: &mut Bus) -> Result<(), StateError> {
+        self.pc = reader.u32()? as usize;
+        self.i = reader.u32()? as usize;
+        let reg = reader.bytes()?;
+        if reg.len() != REG_COUNT {
+            return Err(StateError::Invalid("register count"));
+        }
+        self.reg.copy_from_slice(reg);
+        let flags = reader.bytes()?;
+        if flags.len() != FLAG_COUNT {
+            return Err(StateError::Invalid("flag count"));
+        }
+        self.flags.copy_from_slice(flags);
+        // Version 1 states were saved before CXNN was random.
+        if reader.version() >= 2 {
+            self.random.set_state(reader.u64()?);
+        }
+        if reader.version() >= 3 {
+            self.cycles = reader.u64()?;
+        }
+        self.memory.read_state(reader)?;
+        bus.read_state(reader)
+    }
+
+    // Runs a 60Hz frame: |instructions| instructions followed by a tick of
+    // the timers or, with COSMAC VIP timing, until the next vertical blank.
+    // The frame ends early on a key wait (FX0A), exit, error or breakpoint
+    // though the timers are still ticked once for each call.
+    pub fn run_frame(&mut self, bus: &mut Bus, instructions: usize) -> FrameReport {
+        let display = bus.display().to_vec();
+        let frame = self.cycles / VIP_CYCLES_PER_FRAME;
+        let mut executed = 0;
+
+        let outcome = loop {
+            let done = match self.timing {
+                Timing::Unlimited => executed == instructions,
+                Timing::CosmacVip => self.cycles / VIP_CYCLES_PER_FRAME > frame,
+            };
+            if done {
+                break Ok(TickOutcome::Executed);
+            }
+            match self.tick(bus) {
+                Ok(TickOutcome::Executed) => executed += 1,
+                Ok(TickOutcome::WaitingForKey) => break Ok(TickOutcome::WaitingForKey),
+                Ok(TickOutcome::Exited) => break Ok(TickOutcome::Exited),
+                Ok(outcome) => {
+                    executed += 1;
+                    break Ok(outcome);
+                }
+                Err(error) => break Err(error),
+            }
+        };
+
+        match self.timing {
+            Timing::Unlimited => bus.tick_timers(),
+            // The VIP idles until the next frame while waiting for a key.
+            Timing::CosmacVip if outcome == Ok(TickOutcome::WaitingForKey) => {
+                let elapsed = self.cycles % VIP_CYCLES_PER_FRAME;
+                self.advance_cycles(VIP_CYCLES_PER_FRAME - elapsed, bus);
+            }
+            Timing::CosmacVip => (),
+        }
+
+        FrameReport {
+            instructions: executed,
+            display_changed: bus.display() != &display[..],
+            sound_on: bus.sound_active(),
+            outcome,
+        }
+    }
+
+    pub fn tick(&mut self, bus: &mut Bus) -> Result<TickOutcome, CpuError> {
+        let instruction = match self.memory.read_instruction(self.pc) {
+            Some(instruction) => instruction,
+            None => return Err(CpuError::ProgramCounterOutOfBounds { pc: self.pc }),
+        };
+
+        let decoded = decode(instruction);
+
+        // Discard any write recorded by an earlier instruction which failed.
+        self.memory.take_watch_hit();
+
+        let before = if self.rewind.is_some() {
+            self.memory.start_journal();
+            Some(self.capture(decoded, bus))
+        } else {
+            None
+        };
+        // The state is traced before the instruction runs but only written
+        // once it has retired (or failed) so a key wait is traced once.
+        let record = self.tracer.as_ref().map(|tracer| Record {
+            cycle: tracer.cycle(),
+            pc: self.pc,
+            opcode: instruction,
+            mnemonic: decoded.to_string(),
+            registers: self.reg,
+            i: self.i,
+            stack_depth: self.memory.stack().len(),
+            delay_timer: bus.delay_timer(),
+            sound_timer: bus.sound_timer(),
+            line: tracer.line(self.pc),
+        });
+        let op = self.execute(instruction, decoded, bus);
+        let writes = self.memory.take_journal();
+        if let (Some(tracer), Some(record)) = (self.tracer.as_mut(), record) {
+            if !matches!(op, Ok(PcOp::Halt)) {
+                tracer.record(&record);
+            }
+        }
+        let op = op?;
+        // A key wait (FX0A) is counted once, when the key arrives.
+        if let Some(profile) = self.profile.as_mut().filter(|_| !matches!(op, PcOp::Halt)) {
+            profile.record(self.pc, decoded);
+        }
+        self.cover(self.pc, decoded.size(), Access::Executed);
+        if self.timing == Timing::CosmacVip {
+            self.charge_cycles(decoded, &op, bus);
+        }
+
+        match op {
+            PcOp::Next => self.pc += 2,
+            PcOp::Skip => self.pc += 2 + self.instruction_length(self.pc + 2),
             PcOp::Jump(addr) => self.pc = addr,
-            PcOp::Halt => (),
+            PcOp::Halt => return Ok(TickOutcome::WaitingForKey),
+            PcOp::Exit => return Ok(TickOutcome::Exited),
+        }
+
+        if let Some(before) = before {
+            self.record(before, writes, bus);
+        }
+
+        if let Some(address) = self.memory.take_watch_hit() {
+            return Ok(TickOutcome::Watchpoint(address));
+        }
+        if let Some(condition) = self.check_conditions() {
+            return Ok(TickOutcome::Condition(condition));
+        }
+        if self.breakpoints.contains(&self.pc) {
+            return Ok(TickOutcome::Breakpoint);
         }
+        Ok(TickOutcome::Executed)
     }
 
-    fn execute(&mut self, instruction: u16, bus: &mut Bus) -> PcOp {
-        let nnn = instruction & 0xFFF;
-        let nn = (instruction & 0xFF) as u8;
-        let n = (instruction & 0xF) as u8;
-        let x = ((instruction >> 8) & 0xF) as usize;
-        let y = ((instruction >> 4) & 0xF) as usize;
+    // The state which |instruction| may change which is not journaled. The
+    // display is copied only for instructions which draw to it.
+    fn capture(&self, instruction: Instruction, bus: &Bus) -> Capture {
+        let display = match instruction {
+            Instruction::Cls
+            | Instruction::Drw(..)
+            | Instruction::Scd(_)
+            | Instruction::Scu(_)
+            | Instruction::Scr
+            | Instruction::Scl
+            | Instruction::Low
+            | Instruction::High => Some(DisplayChange::Resolution {
+                hires: bus.hires(),
+                display: bus.display().to_vec(),
+            }),
+            _ => None,
+        };
+        let (delay_timer, sound_timer) = bus.timers();
+        let delta = Delta {
+            pc: self.pc,
+            i: self.i,
+            stack_pointer: self.memory.stack_pointer(),
+            registers: Vec::new(),
+            flags: Vec::new(),
+            memory: Vec::new(),
+            display,
+            random: self.random.state(),
+            cycles: self.cycles,
+            planes: bus.planes(),
+            delay_timer,
+            sound_timer,
+        };
+        Capture {
+            delta,
+            reg: self.reg,
+            flags: self.flags,
+        }
+    }
 
-        let instruction_exploded = (
-            (instruction >> 12) & 0xF,
-            (instruction >> 8) & 0xF,
-            (instruction >> 4) & 0xF,
-            (instruction >> 0) & 0xF,
-        );
+    // Pushes the delta of the instruction captured in |before| keeping only
+    // the registers and pixels it changed.
+    fn record(&mut self, before: Capture, writes: Vec<(usize, u8)>, bus: &Bus) {
+        let mut delta = before.delta;
+        delta.registers = rewind::diff(&before.reg, &self.reg);
+        delta.flags = rewind::diff(&before.flags, &self.flags);
+        delta.memory = writes;
+        delta.display = match delta.display {
+            Some(DisplayChange::Resolution { hires, display }) if hires == bus.hires() => {
+                Some(DisplayChange::Pixels(rewind::diff(&display, bus.display())))
+            }
+            display => display,
+        };
+        if let Some(ref mut rewind) = self.rewind {
+            rewind.push(delta);
+        }
+    }
+
+    // Charges the COSMAC VIP machine cycles taken by |instruction| which
+    // completed with |op|. DXYN first waits for the next vertical blank.
+    fn charge_cycles(&mut self, instruction: Instruction, op: &PcOp, bus: &mut Bus) {
+        if let Instruction::Drw(..) = instruction {
+            let elapsed = self.cycles % VIP_CYCLES_PER_FRAME;
+            self.advance_cycles(VIP_CYCLES_PER_FRAME - elapsed, bus);
+        }
+        let skipped = matches!(op, PcOp::Skip);
+        self.advance_cycles(timing::vip_cycles(instruction, skipped), bus);
+    }
+
+    // Advances the cycle count by |cycles| decrementing the timers at the
+    // start of each frame where the display interrupt also takes its cycles.
+    fn advance_cycles(&mut self, cycles: u64, bus: &mut Bus) {
+        let frame = self.cycles / VIP_CYCLES_PER_FRAME;
+        self.cycles += cycles;
+        for _ in frame..self.cycles / VIP_CYCLES_PER_FRAME {
+            bus.tick_timers();
+            self.cycles += VIP_INTERRUPT_CYCLES;
+        }
+    }
+
+    // The length of the instruction at |pc| in bytes. XO-CHIP skips must
+    // step over both words of F000 NNNN.
+    fn instruction_length(&self, pc: usize) -> usize {
+        if self.platform.has_xochip() && self.memory.read_instruction(pc) == Some(LONG_OPCODE) {
+            4
+        } else {
+            2
+        }
+    }
+
+    // Ensures that [address, address + len) lies within memory before an
+    // instruction reads or writes it.
+    fn check_range(&self, instruction: u16, address: usize, len: usize) -> Result<(), CpuError> {
+        match address.checked_add(len) {
+            Some(end) if end <= self.memory.bytes().len() => Ok(()),
+            _ => Err(CpuError::MemoryOutOfBounds {
+                pc: self.pc,
+                opcode: instruction,
+                address: address.saturating_add(len - 1),
+            }),
+        }
+    }
+
+    // Marks the |len| bytes from |address| as used by |access| if coverage
+    // is being recorded.
+    fn cover(&mut self, address: usize, len: usize, access: Access) {
+        if let Some(coverage) = self.coverage.as_mut() {
+            coverage.mark(address, len, access);
+        }
+    }
+
+    fn apply_load_store_quirk(&mut self, x: usize) {
+        match self.quirks.load_store {
+            LoadStoreQuirk::Unchanged => (),
+            LoadStoreQuirk::IncrementByX => self.i += x,
+            LoadStoreQuirk::IncrementByXPlusOne => self.i += x + 1,
+        }
+    }
 
-        info!("PC: {} Executing instruction: 0x{:04X}", self.pc, instruction);
+    // Executes |decoded| which was read as the opcode |instruction|.
+    fn execute(
+        &mut self,
+        instruction: u16,
+        decoded: Instruction,
+        bus: &mut Bus,
+    ) -> Result<PcOp, CpuError> {
+        let schip = self.platform.has_superchip();
+        let xochip = self.platform.has_xochip();
 
-        match instruction_exploded {
+        trace!(
+            "PC: {} Executing instruction: 0x{:04X} {}",
+            self.pc,
+            instruction,
+            decoded
+        );
+
+        match decoded {
             // 0x00E0: Clears the screen
-            (0x0, 0x0, 0xE, 0x0) => {
-                info!("Clear Screen");
+            Instruction::Cls => {
+                trace!("Clear Screen");
                 bus.clear_display();
             }
             // 0x00EE: Returns from a subroutine
-            (0x0, 0x0, 0xE, 0xE) => {
-                info!("Return from subroutine");
-                return PcOp::Jump(self.memory.pop_stack());
+            Instruction::Ret => {
+                trace!("Return from subroutine");
+                return match self.memory.pop_stack() {
+                    Some(address) => Ok(PcOp::Jump(address)),
+                    None => Err(CpuError::StackUnderflow {
+                        pc: self.pc,
+                        opcode: instruction,
+                    }),
+                };
+            }
+            // 0x00CN: Scrolls the display down by N pixels (SUPER-CHIP)
+            Instruction::Scd(n) if schip => {
+                trace!("Scroll down N");
+                bus.scroll_down(n as usize);
+            }
+            // 0x00DN: Scrolls the display up by N pixels (XO-CHIP)
+            Instruction::Scu(n) if xochip => {
+                trace!("Scroll up N");
+                bus.scroll_up(n as usize);
+            }
+            // 0x00FB: Scrolls the display right by 4 pixels (SUPER-CHIP)
+            Instruction::Scr if schip => {
+                trace!("Scroll right");
+                bus.scroll_right(4);
+            }
+            // 0x00FC: Scrolls the display left by 4 pixels (SUPER-CHIP)
+            Instruction::Scl if schip => {
+                trace!("Scroll left");
+                bus.scroll_left(4);
+            }
+            // 0x00FD: Exits the interpreter (SUPER-CHIP)
+            Instruction::Exit if schip => {
+                trace!("Exit");
+                return Ok(PcOp::Exit);
+            }
+            // 0x00FE: Disables high resolution mode (SUPER-CHIP)
+            Instruction::Low if schip => {
+                trace!("Low resolution");
+                bus.set_hires(false);
+            }
+            // 0x00FF: Enables 128x64 high resolution mode (SUPER-CHIP)
+            Instruction::High if schip => {
+                trace!("High resolution");
+                bus.set_hires(true);
             }
-            (0x0, _, _, _) => panic!("Calls to RCA 1802"),
             // 0x1NNN: goto NNN
-            (0x1, _, _, _) => {
-                info!("goto 0x{:03X}", nnn);
-                return PcOp::Jump(nnn as usize);
+            Instruction::Jp(nnn) => {
+                trace!("goto 0x{:03X}", nnn);
+                return Ok(PcOp::Jump(nnn));
             }
             // 0x2NNN: Calls subroutine at NNN
-            (0x2, _, _, _) => {
-                info!("Call: 0x{:03X}()", nnn);
+            Instruction::Call(nnn) => {
+                trace!("Call: 0x{:03X}()", nnn);
                 // Add 2 to the current PC so that we return to the instruction after
                 // the current one; otherwise we end up calling the subroutine again.
-                self.memory.push_stack(self.pc + 2);
-                return PcOp::Jump(nnn as usize);
+                if self.memory.push_stack(self.pc + 2).is_none() {
+                    return Err(CpuError::StackOverflow {
+                        pc: self.pc,
+                        opcode: instruction,
+                    });
+                }
+                return Ok(PcOp::Jump(nnn));
             }
             // 0x3XNN: Skips next instruction if VX equals NN
-            (0x3, _, _, _) => {
-                info!("Skip if Vx == NN");
+            Instruction::SeByte(x, nn) => {
+                trace!("Skip if Vx == NN");
                 if self.reg[x] == nn {
-                    return PcOp::Skip;
+                    return Ok(PcOp::Skip);
                 }
             }
             // 0x4XNN: Skips next instruction if VX does not equals NN
-            (0x4, _, _, _) => {
-                info!("Skip if Vx != NN");
+            Instruction::SneByte(x, nn) => {
+                trace!("Skip if Vx != NN");
                 if self.reg[x] != nn {
-                    return PcOp::Skip;
+                    return Ok(PcOp::Skip);
                 }
             }
             // 0x5XY0: Skips next instruction if VX equals VY
-            (0x5, _, _, 0x0) => {
-                info!("Skip if Vx == Vy");
+            Instruction::SeReg(x, y) => {
+                trace!("Skip if Vx == Vy");
                 if self.reg[x] == self.reg[y] {
-                    return PcOp::Skip;
+                    return Ok(PcOp::Skip);
+                }
+            }
+            // 0x5XY2: Stores VX to VY (including VY) in memory starting at
+            //         address I; I itself is left unmodified (XO-CHIP)
+            Instruction::SaveRange(x, y) if xochip => {
+                trace!("Store Vx-Vy to address I");
+                let range = register_range(x, y);
+                self.check_range(instruction, self.i, range.len())?;
+                self.cover(self.i, range.len(), Access::Written);
+                for (offset, reg) in range.into_iter().enumerate() {
+                    self.memory.write(self.i + offset, self.reg[reg]);
+                }
+            }
+            // 0x5XY3: Fills VX to VY (including VY) with values from memory
+            //         starting at address I; I itself is left unmodified
+            //         (XO-CHIP)
+            Instruction::LoadRange(x, y) if xochip => {
+                trace!("Load Vx-Vy from address I");
+                let range = register_range(x, y);
+                self.check_range(instruction, self.i, range.len())?;
+                self.cover(self.i, range.len(), Access::Read);
+                for (offset, reg) in range.into_iter().enumerate() {
+                    self.reg[reg] = self.memory.read(self.i + offset);
                 }
             }
             // 0x6XNN: Sets VX to NN
-            (0x6, _, _, _) => {
-                info!("Vx = NN");
+            Instruction::LdByte(x, nn) => {
+                trace!("Vx = NN");
                 self.reg[x] = nn;
             }
             // 0x7XNN: Adds NN to VX (Carry flag is not changed)
-            (0x7, _, _, _) => {
-                info!("Vx += NN");
+            Instruction::AddByte(x, nn) => {
+                trace!("Vx += NN");
                 self.reg[x] = self.reg[x].wrapping_add(nn);
             }
             // 0x8XY0: Asigns VX to the value of VY
-            (0x8, _, _, 0x0) => {
-                info!("Vx = Vy");
+            Instruction::LdReg(x, y) => {
+                trace!("Vx = Vy");
                 self.reg[x] = self.reg[y];
             }
             // 0x8XY1: Sets VX to VX or VY (Bitwise OR operation)
-            (0x8, _, _, 0x1) => {
-                info!("Vx = Vx | Vy");
-                self.reg[x] = self.reg[x] | self.reg[y];
+            Instruction::Or(x, y) => {
+                trace!("Vx = Vx | Vy");
+                self.reg[x] |= self.reg[y];
+                if self.quirks.vf_reset {
+                    self.reg[0xF] = 0;
+                }
             }
             // 0x8XY2: Sets VX to VX and VY (Bitwise AND operation)
-            (0x8, _, _, 0x2) => {
-                info!("Vx = Vx & Vy");
-                self.reg[x] = self.reg[x] & self.reg[y];
+            Instruction::And(x, y) => {
+                trace!("Vx = Vx & Vy");
+                self.reg[x] &= self.reg[y];
+                if self.quirks.vf_reset {
+                    self.reg[0xF] = 0;
+                }
             }
             // 0x8XY3: Sets VX to VX xor VY
-            (0x8, _, _, 0x3) => {
-                info!("Vx = Vx ^ Vy");
-                self.reg[x] = self.reg[x] ^ self.reg[y];
+            Instruction::Xor(x, y) => {
+                trace!("Vx = Vx ^ Vy");
+                self.reg[x] ^= self.reg[y];
+                if self.quirks.vf_reset {
+                    self.reg[0xF] = 0;
+                }
             }
             // 0x8XY4: Adds VY to VX; VF is set to 1 when there's a carry,
             //         and to 0 when there isn't i.e. the flag is set if the
-            //         result would exceed the max value of u8 (255).
-            (0x8, _, _, 0x4) => {
-                info!("Vx += Vy");
-                self.reg[x] = self.reg[x].wrapping_add(self.reg[y]);
-
-                let value: u16 = self.reg[x] as u16 + self.reg[y] as u16;
-                self.reg[0xF] = if value > 255 { 1 } else { 0 };
+            //         result would exceed the max value of u8 (255). VF is
+            //         set last so the flag wins when X is F.
+            Instruction::AddReg(x, y) => {
+                trace!("Vx += Vy");
+                let (value, carry) = self.reg[x].overflowing_add(self.reg[y]);
+                self.reg[x] = value;
+                self.reg[0xF] = carry as u8;
             }
             // 0x8XY5: VY is subtracted from VX; VF is set to 0 when there's
-            //         a borrow, and 1 when there isn't i.e. the flag is set if
-            //         the result of the subtraction would be negative.
-            (0x8, _, _, 0x5) => {
-                info!("Vx -= Vy");
-                self.reg[0xF] = if self.reg[x] > self.reg[y] { 1 } else { 0 };
+            //         a borrow, and 1 when there isn't i.e. the flag is clear
+            //         if the result of the subtraction would be negative.
+            Instruction::Sub(x, y) => {
+                trace!("Vx -= Vy");
+                let no_borrow = self.reg[x] >= self.reg[y];
                 self.reg[x] = self.reg[x].wrapping_sub(self.reg[y]);
+                self.reg[0xF] = no_borrow as u8;
             }
             // 0x8XY6: Stores the least significant bit of VX in VF and then
-            //         shifts VX to the right by 1
-            (0x8, _, _, 0x6) => {
-                info!("Vx >>= 1");
-                self.reg[0x0f] = self.reg[x] & 1;
-                self.reg[x] >>= 1;
+            //         shifts VX to the right by 1 (or VX = VY >> 1 with the
+            //         |shift_uses_vy| quirk)
+            Instruction::Shr(x, y) => {
+                trace!("Vx >>= 1");
+                let value = if self.quirks.shift_uses_vy {
+                    self.reg[y]
+                } else {
+                    self.reg[x]
+                };
+                self.reg[x] = value >> 1;
+                self.reg[0x0f] = value & 1;
             }
             // 0x8XY7: Sets VX to VY minus VX. VF is set to 0 when there's a
-            //         borrow, and 1 when there isn't i.e. the flag is set if
+            //         borrow, and 1 when there isn't i.e. the flag is clear if
             //         the result of the subtraction would be negative.
-            (0x8, _, _, 0x7) => {
-                info!("Vx = Vy - Vx");
-                self.reg[0xF] = if self.reg[y] > self.reg[x] { 1 } else { 0 };
+            Instruction::Subn(x, y) => {
+                trace!("Vx = Vy - Vx");
+                let no_borrow = self.reg[y] >= self.reg[x];
                 self.reg[x] = self.reg[y].wrapping_sub(self.reg[x]);
+                self.reg[0xF] = no_borrow as u8;
             }
             // 0x8XYE: Stores the most significant bit of VX in VF and then
-            //         shifts VX to the left by 1
-            (0x8, _, _, 0xE) => {
-                info!("Vx <<= 1");
-                self.reg[0x0f] = (self.reg[x] & 0x80) >> 7;
-                self.reg[x] <<= 1;
+            //         shifts VX to the left by 1 (or VX = VY << 1 with the
+            //         |shift_uses_vy| quirk)
+            Instruction::Shl(x, y) => {
+                trace!("Vx <<= 1");
+                let value = if self.quirks.shift_uses_vy {
+                    self.reg[y]
+                } else {
+                    self.reg[x]
+                };
+                self.reg[x] = value << 1;
+                self.reg[0x0f] = (value & 0x80) >> 7;
             }
             // 0x9XY0: Skips the next instruction if VX doesn't equal VY
-            (0x9, _, _, 0x0) => {
-                info!("Skip if Vx != Vy");
+            Instruction::SneReg(x, y) => {
+                trace!("Skip if Vx != Vy");
                 if self.reg[x] != self.reg[y] {
-                    return PcOp::Skip;
+                    return Ok(PcOp::Skip);
                 }
             }
             // 0xANNN: Sets I to the address NNN
-            (0xA, _, _, _) => {
-                info!("I = NNN");
-                self.i = nnn as usize;
+            Instruction::LdI(nnn) => {
+                trace!("I = NNN");
+                self.i = nnn;
             }
-            // 0xBNNN: Jumps to the address NNN plus V0
-            (0xB, _, _, _) => {
-                info!("PC = V0 + NNN");
-                return PcOp::Jump((self.reg[0] as u16 + nnn) as usize);
+            // 0xBNNN: Jumps to the address NNN plus V0 (or XNN plus VX with
+            //         the |jump_uses_vx| quirk)
+            Instruction::JpV0(nnn) => {
+                trace!("PC = V0 + NNN");
+                let offset = if self.quirks.jump_uses_vx {
+                    self.reg[nnn >> 8]
+                } else {
+                    self.reg[0]
+                };
+                return Ok(PcOp::Jump(offset as usize + nnn));
             }
             // 0xCXNN: Sets VX to the result of a bitwise and operation on a
             //         random number (Typically: 0 to 255) and NN
-            (0xC, _, _, _) => {
-                info!("Vx = rand() & NN");
-                self.reg[x] = 16; // rand::random::<u8>() & nn;
+            Instruction::Rnd(x, nn) => {
+                trace!("Vx = rand() & NN");
+                self.reg[x] = self.random.next_byte() & nn;
             }
             // 0xDXYN: Draws a sprite at coordinate (VX, VY) that has a width
             //         of 8 pixels and a height of N pixels. Each row of 8
@@ -211,65 +977,110 @@ impl Cpu {
             //         instruction. As described above, VF is set to 1 if any
             //         screen pixels are flipped from set to unset when the
             //         sprite is drawn, and to 0 if that doesn’t happen
-            (0xD, _, _, _) => {
-                info!("draw(Vx,Vy,N)");
+            //         (SUPER-CHIP: N of 0 draws a 16x16 sprite from 32 bytes)
+            Instruction::Drw(x, y, n) => {
+                trace!("draw(Vx,Vy,N)");
+                let size = if schip && n == 0 {
+                    (16, 16)
+                } else {
+                    (8, n as usize)
+                };
+                let len = size.0 / 8 * size.1 * bus.plane_count();
+                self.check_range(instruction, self.i, len)?;
+                self.cover(self.i, len, Access::Sprite);
                 let position = (self.reg[x] as usize, self.reg[y] as usize);
-                let pixel_flip = bus.draw_display(&mut self.memory, self.i, position, n as usize);
+                let pixel_flip = bus.draw_display(
+                    &self.memory,
+                    self.i,
+                    position,
+                    size,
+                    self.quirks.clip_sprites,
+                );
                 self.reg[0xf] = pixel_flip as u8;
             }
             // 0xEX9E: Skips the next instruction if the key stored in VX is
             //         pressed
-            (0xE, _, 0x9, 0xE) => {
-                info!("Skip if key() == Vx");
+            Instruction::Skp(x) => {
+                trace!("Skip if key() == Vx");
                 if bus.check_key(self.reg[x]) {
-                    return PcOp::Skip;
+                    return Ok(PcOp::Skip);
                 }
             }
             // 0xEXA1: Skips the next instruction if the key stored in VX is
             //         not pressed
-            (0xE, _, 0xA, 0x1) => {
-                info!("Skip if key() != Vx");
+            Instruction::Sknp(x) => {
+                trace!("Skip if key() != Vx");
                 if !bus.check_key(self.reg[x]) {
-                    return PcOp::Skip;
+                    return Ok(PcOp::Skip);
                 }
             }
+            // 0xF000: Sets I to the 16-bit address NNNN stored in the word
+            //         following the instruction (XO-CHIP)
+            Instruction::LdILong(_) if xochip => {
+                trace!("I = NNNN");
+                self.check_range(instruction, self.pc + 2, 2)?;
+                self.i = self.memory.read_instruction(self.pc + 2).unwrap_or(0) as usize;
+                return Ok(PcOp::Jump(self.pc + 4));
+            }
+            // 0xFN01: Selects the bitplanes N (0-3) used for drawing
+            //         (XO-CHIP)
+            Instruction::Plane(planes) if xochip => {
+                trace!("plane(N)");
+                bus.set_planes(planes);
+            }
+            // 0xF002: Loads the 16-byte audio pattern starting at address I
+            //         (XO-CHIP)
+            Instruction::Audio if xochip => {
+                trace!("audio(I)");
+                self.check_range(instruction, self.i, PATTERN_SIZE)?;
+                self.cover(self.i, PATTERN_SIZE, Access::Read);
+                let mut pattern = [0; PATTERN_SIZE];
+                pattern.copy_from_slice(&self.memory.bytes()[self.i..self.i + PATTERN_SIZE]);
+                bus.set_audio_pattern(pattern);
+            }
             // 0xFX07: Sets VX to the value of the delay timer
-            (0xF, _, 0x0, 0x7) => {
-                info!("Vx = delay_timer()");
+            Instruction::LdVxDt(x) => {
+                trace!("Vx = delay_timer()");
                 self.reg[x] = bus.delay_timer();
             }
             // 0xFX0A: A key press is awaited, and then stored in VX. (Blocking
             //         Operation. All instruction halted until next key event)
-            (0xF, _, 0x0, 0xA) => {
-                info!("Vx = get_key()");
+            Instruction::LdVxK(x) => {
+                trace!("Vx = get_key()");
                 if let Some(key) = bus.any_key() {
-                    info!("Set Vx to {:X}", key);
+                    trace!("Set Vx to {:X}", key);
                     self.reg[x] = key;
                 } else {
-                    return PcOp::Halt;
+                    return Ok(PcOp::Halt);
                 }
             }
             // 0xFX15: Sets the delay timer to Vx
-            (0xF, _, 0x1, 0x5) => {
-                info!("delay_timer(Vx)");
+            Instruction::LdDtVx(x) => {
+                trace!("delay_timer(Vx)");
                 bus.set_delay_timer(self.reg[x]);
             }
             // 0xFX18: Sets the sound timer to VX
-            (0xF, _, 0x1, 0x8) => {
-                info!("sound_timer(Vx)");
+            Instruction::LdStVx(x) => {
+                trace!("sound_timer(Vx)");
                 bus.set_sound_timer(self.reg[x]);
             }
             // 0xFX1E: Adds VX to I
-            (0xF, _, 0x1, 0xE) => {
-                info!("I += Vx");
+            Instruction::AddI(x) => {
+                trace!("I += Vx");
                 self.i += self.reg[x] as usize;
             }
             // 0xFX29: Sets I to the location of the sprite for the character
             //         in VX. Characters 0-F (in hexadecimal) are represented
-            //         by a 4x5 font
-            (0xF, _, 0x2, 0x9) => {
-                info!("I = sprite_addr[Vx]");
-                self.i = self.reg[x] as usize * 5;
+            //         by a 4x5 font (only the low nibble of VX is used)
+            Instruction::LdF(x) => {
+                trace!("I = sprite_addr[Vx]");
+                self.i = (self.reg[x] & 0xF) as usize * 5;
+            }
+            // 0xFX30: Sets I to the location of the 8x10 sprite for the
+            //         character in VX (SUPER-CHIP)
+            Instruction::LdHf(x) if schip => {
+                trace!("I = big_sprite_addr[Vx]");
+                self.i = BIG_FONT_OFFSET + (self.reg[x] & 0xF) as usize * 10;
             }
             // 0xFX33: Stores the binary-coded decimal representation of VX,
             //         with the most significant of three digits at the address
@@ -278,39 +1089,750 @@ impl Cpu {
             //         the decimal representation of VX, place the hundreds
             //         digit in memory at location in I, the tens digit at
             //         location I+1, and the ones digit at location I+2.)
-            (0xF, _, 0x3, 0x3) => {
-                info!("Store BCD");
+            Instruction::LdB(x) => {
+                trace!("Store BCD");
+                self.check_range(instruction, self.i, 3)?;
+                self.cover(self.i, 3, Access::Written);
                 let mut value = self.reg[x];
                 for pos in 0..3 {
                     // Use integer division to separate each digit of |value|.
                     let magnitude = 10_u8.pow(2 - pos);
                     let digit = value / magnitude;
 
-                    self.memory.data()[self.i + pos as usize] = digit;
+                    self.memory.write(self.i + pos as usize, digit);
                     value -= magnitude * digit;
                 }
             }
+            // 0xFX3A: Sets the audio pattern playback pitch to VX (XO-CHIP)
+            Instruction::Pitch(x) if xochip => {
+                trace!("pitch(Vx)");
+                bus.set_pitch(self.reg[x]);
+            }
             // 0xFX55: Stores V0 to VX (including VX) in memory starting at
             //         address I. The offset from I is increased by 1 for each
-            //         value written, but I itself is left unmodified
-            (0xF, _, 0x5, 0x5) => {
-                info!("Store V0-X to address I");
-                for pos in 0..x + 1 {
-                    self.memory.data()[self.i + pos] = self.reg[pos];
+            //         value written, I itself is modified per |load_store|
+            Instruction::LdMemVx(x) => {
+                trace!("Store V0-X to address I");
+                self.check_range(instruction, self.i, x + 1)?;
+                self.cover(self.i, x + 1, Access::Written);
+                for pos in 0..=x {
+                    self.memory.write(self.i + pos, self.reg[pos]);
                 }
+                self.apply_load_store_quirk(x);
             }
             // 0xFX65: Fills V0 to VX (including VX) with values from memory
             //         starting at address I. The offset from I is increased by
-            //         1 for each value written, but I itself is left unmodified
-            (0xF, _, 0x6, 0x5) => {
-                info!("Load V0-X from address I");
-                for pos in 0..x + 1 {
-                    self.reg[pos] = self.memory.data()[self.i + pos];
+            //         1 for each value written, I itself is modified per
+            //         |load_store|
+            Instruction::LdVxMem(x) => {
+                trace!("Load V0-X from address I");
+                self.check_range(instruction, self.i, x + 1)?;
+                self.cover(self.i, x + 1, Access::Read);
+                for pos in 0..=x {
+                    self.reg[pos] = self.memory.read(self.i + pos);
                 }
+                self.apply_load_store_quirk(x);
+            }
+            // 0xFX75: Stores V0 to VX in the RPL user flags (SUPER-CHIP:
+            //         X < 8, XO-CHIP: X < 16)
+            Instruction::LdRVx(x) if x < self.platform.flag_count() => {
+                trace!("Store V0-X to flags");
+                self.flags[..=x].copy_from_slice(&self.reg[..=x]);
+            }
+            // 0xFX85: Fills V0 to VX from the RPL user flags (SUPER-CHIP:
+            //         X < 8, XO-CHIP: X < 16)
+            Instruction::LdVxR(x) if x < self.platform.flag_count() => {
+                trace!("Load V0-X from flags");
+                self.reg[..=x].copy_from_slice(&self.flags[..=x]);
+            }
+            _ => {
+                return Err(CpuError::UnknownInstruction {
+                    pc: self.pc,
+                    opcode: instruction,
+                })
             }
-            (_, _, _, _) => panic!("Unknown instruction: 0x{:04X}", instruction),
         };
 
-        PcOp::Next
+        Ok(PcOp::Next)
+    }
+}
+
+// The registers VX to VY inclusive in the order they are saved/loaded by
+// 5XY2/5XY3, which is descending when X > Y.
+fn register_range(x: usize, y: usize) -> Vec<usize> {
+    if x <= y {
+        (x..=y).collect()
+    } else {
+        (y..=x).rev().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::trace::{parse_line, Value};
+
+    fn cpu(source: &str) -> Cpu {
+        let rom = assemble(source).unwrap();
+        Cpu::new(&rom, Platform::Chip8, Quirks::default()).unwrap()
+    }
+
+    // A CPU about to execute |opcodes| from 0x200 (with modern quirks and a
+    // fixed random seed) along with its bus. The state can be chosen before
+    // tick() and inspected afterwards.
+    struct Fixture {
+        cpu: Cpu,
+        bus: Bus,
+    }
+
+    impl Fixture {
+        fn new(opcodes: &[u16]) -> Fixture {
+            Fixture::with_quirks(opcodes, Quirks::modern())
+        }
+
+        fn with_quirks(opcodes: &[u16], quirks: Quirks) -> Fixture {
+            let rom: Vec<u8> = opcodes.iter().flat_map(|op| op.to_be_bytes()).collect();
+            let mut cpu = Cpu::new(&rom, Platform::Chip8, quirks).unwrap();
+            cpu.seed_random(1);
+            Fixture {
+                cpu,
+                bus: Bus::new(),
+            }
+        }
+
+        fn reg(mut self, x: usize, value: u8) -> Fixture {
+            self.cpu.reg[x] = value;
+            self
+        }
+
+        fn index(mut self, i: usize) -> Fixture {
+            self.cpu.i = i;
+            self
+        }
+
+        fn write(mut self, address: usize, bytes: &[u8]) -> Fixture {
+            for (offset, &byte) in bytes.iter().enumerate() {
+                self.cpu.memory.write(address + offset, byte);
+            }
+            self
+        }
+
+        fn press(mut self, key: u8) -> Fixture {
+            self.bus.set_key(key as usize);
+            self
+        }
+
+        // Executes an instruction which is expected to succeed.
+        fn run(mut self) -> Fixture {
+            assert_eq!(self.tick(), Ok(TickOutcome::Executed));
+            self
+        }
+
+        fn tick(&mut self) -> Result<TickOutcome, CpuError> {
+            self.cpu.tick(&mut self.bus)
+        }
+
+        fn v(&self, x: usize) -> u8 {
+            self.cpu.reg[x]
+        }
+
+        fn pc(&self) -> usize {
+            self.cpu.pc
+        }
+
+        fn memory(&self, address: usize, len: usize) -> &[u8] {
+            &self.cpu.memory()[address..address + len]
+        }
+
+        fn pixel(&self, x: usize, y: usize) -> u8 {
+            self.bus.display()[y * self.bus.width() + x]
+        }
+    }
+
+    // Whether the conditional skip |opcode| skips the next instruction.
+    fn skips(fixture: Fixture) -> bool {
+        let fixture = fixture.run();
+        match fixture.pc() {
+            0x202 => false,
+            0x204 => true,
+            pc => panic!("unexpected PC 0x{:03X}", pc),
+        }
+    }
+
+    #[test]
+    fn run_frame_executes_instructions_and_ticks_timers() {
+        let mut cpu = cpu("LD V0, 5\nLD DT, V0\nLD ST, V0\nloop: ADD V1, 1\nJP loop");
+        let mut bus = Bus::new();
+
+        let report = cpu.run_frame(&mut bus, 10);
+        assert_eq!(report.instructions, 10);
+        assert_eq!(report.outcome, Ok(TickOutcome::Executed));
+        assert!(!report.display_changed);
+        assert!(report.sound_on);
+        assert_eq!(bus.delay_timer(), 4);
+        assert_eq!(cpu.registers()[1], 4);
+    }
+
+    #[test]
+    fn run_frame_stops_early() {
+        let mut cpu = cpu("CLS\nLD F, V0\nDRW V0, V0, 5\nLD V1, K\nJP 0x200");
+        let mut bus = Bus::new();
+
+        let report = cpu.run_frame(&mut bus, 10);
+        assert_eq!(report.instructions, 3);
+        assert_eq!(report.outcome, Ok(TickOutcome::WaitingForKey));
+        assert!(report.display_changed);
+
+        cpu.add_breakpoint(0x200);
+        bus.set_key(0x7);
+        let report = cpu.run_frame(&mut bus, 10);
+        assert_eq!(report.instructions, 2);
+        assert_eq!(report.outcome, Ok(TickOutcome::Breakpoint));
+        assert_eq!(cpu.registers()[1], 0x7);
+
+        let mut cpu = self::cpu("DW 0xFFFF");
+        let report = cpu.run_frame(&mut bus, 10);
+        assert_eq!(report.instructions, 0);
+        assert!(report.outcome.is_err());
+    }
+
+    #[test]
+    fn cls_00e0() {
+        let mut f = Fixture::new(&[0x00E0]);
+        f.bus.set_pixel(70, 1);
+        let f = f.run();
+        assert!(f.bus.display().iter().all(|&pixel| pixel == 0));
+        assert_eq!(f.pc(), 0x202);
+    }
+
+    #[test]
+    fn ret_00ee() {
+        let mut f = Fixture::new(&[0x00EE]);
+        f.cpu.memory.push_stack(0x346);
+        let mut f = f.run();
+        assert_eq!(f.pc(), 0x346);
+        assert!(f.cpu.stack().is_empty());
+
+        f.cpu.set_pc(0x200);
+        assert_eq!(
+            f.tick(),
+            Err(CpuError::StackUnderflow {
+                pc: 0x200,
+                opcode: 0x00EE
+            })
+        );
+    }
+
+    #[test]
+    fn sys_0nnn_is_unsupported() {
+        let mut f = Fixture::new(&[0x0123]);
+        assert_eq!(
+            f.tick(),
+            Err(CpuError::UnknownInstruction {
+                pc: 0x200,
+                opcode: 0x0123
+            })
+        );
+        assert_eq!(f.pc(), 0x200);
+    }
+
+    #[test]
+    fn jp_1nnn() {
+        assert_eq!(Fixture::new(&[0x1ABC]).run().pc(), 0xABC);
+    }
+
+    #[test]
+    fn call_2nnn() {
+        let f = Fixture::new(&[0x2400]).run();
+        assert_eq!(f.pc(), 0x400);
+        assert_eq!(f.cpu.stack(), vec![0x202]);
+
+        let mut f = Fixture::new(&[0x2200]);
+        for _ in 0..16 {
+            assert_eq!(f.tick(), Ok(TickOutcome::Executed));
+        }
+        assert_eq!(
+            f.tick(),
+            Err(CpuError::StackOverflow {
+                pc: 0x200,
+                opcode: 0x2200
+            })
+        );
+    }
+
+    #[test]
+    fn se_3xnn() {
+        assert!(skips(Fixture::new(&[0x3342]).reg(3, 0x42)));
+        assert!(!skips(Fixture::new(&[0x3342]).reg(3, 0x43)));
+    }
+
+    #[test]
+    fn sne_4xnn() {
+        assert!(!skips(Fixture::new(&[0x4342]).reg(3, 0x42)));
+        assert!(skips(Fixture::new(&[0x4342]).reg(3, 0x43)));
+    }
+
+    #[test]
+    fn se_5xy0() {
+        assert!(skips(Fixture::new(&[0x5120]).reg(1, 7).reg(2, 7)));
+        assert!(!skips(Fixture::new(&[0x5120]).reg(1, 7).reg(2, 8)));
+    }
+
+    #[test]
+    fn ld_6xnn() {
+        assert_eq!(Fixture::new(&[0x6A5C]).run().v(0xA), 0x5C);
+    }
+
+    #[test]
+    fn add_7xnn_wraps_without_carry() {
+        let f = Fixture::new(&[0x7102]).reg(1, 0xFF).reg(0xF, 9).run();
+        assert_eq!(f.v(1), 0x01);
+        assert_eq!(f.v(0xF), 9);
+    }
+
+    #[test]
+    fn ld_8xy0() {
+        assert_eq!(Fixture::new(&[0x8120]).reg(2, 0x99).run().v(1), 0x99);
+    }
+
+    #[test]
+    fn or_8xy1() {
+        let f = Fixture::new(&[0x8121])
+            .reg(1, 0x0F)
+            .reg(2, 0x30)
+            .reg(0xF, 5);
+        let f = f.run();
+        assert_eq!(f.v(1), 0x3F);
+        assert_eq!(f.v(0xF), 5);
+
+        let f = Fixture::with_quirks(&[0x8121], Quirks::cosmac_vip()).reg(0xF, 5);
+        assert_eq!(f.run().v(0xF), 0);
+    }
+
+    #[test]
+    fn and_8xy2() {
+        let f = Fixture::new(&[0x8122]).reg(1, 0x3C).reg(2, 0x0F).run();
+        assert_eq!(f.v(1), 0x0C);
+    }
+
+    #[test]
+    fn xor_8xy3() {
+        let f = Fixture::new(&[0x8123]).reg(1, 0x3C).reg(2, 0x0F).run();
+        assert_eq!(f.v(1), 0x33);
+    }
+
+    #[test]
+    fn add_8xy4() {
+        let f = Fixture::new(&[0x8124]).reg(1, 0x10).reg(2, 0x20).run();
+        assert_eq!((f.v(1), f.v(0xF)), (0x30, 0));
+
+        let f = Fixture::new(&[0x8124]).reg(1, 0xFF).reg(2, 0x01).run();
+        assert_eq!((f.v(1), f.v(0xF)), (0x00, 1));
+    }
+
+    #[test]
+    fn add_8xy4_carry_uses_original_vx() {
+        // A carry whose wrapped result plus VY no longer overflows.
+        let f = Fixture::new(&[0x8124]).reg(1, 0xF0).reg(2, 0x20).run();
+        assert_eq!((f.v(1), f.v(0xF)), (0x10, 1));
+
+        let f = Fixture::new(&[0x8114]).reg(1, 0x80).run();
+        assert_eq!((f.v(1), f.v(0xF)), (0x00, 1));
+    }
+
+    #[test]
+    fn add_8xy4_into_vf_keeps_carry() {
+        let f = Fixture::new(&[0x8F14]).reg(1, 0x01).reg(0xF, 0xFF).run();
+        assert_eq!(f.v(0xF), 1);
+    }
+
+    #[test]
+    fn sub_8xy5() {
+        let f = Fixture::new(&[0x8125]).reg(1, 0x30).reg(2, 0x10).run();
+        assert_eq!((f.v(1), f.v(0xF)), (0x20, 1));
+
+        let f = Fixture::new(&[0x8125]).reg(1, 0x10).reg(2, 0x30).run();
+        assert_eq!((f.v(1), f.v(0xF)), (0xE0, 0));
+    }
+
+    #[test]
+    fn sub_8xy5_equal_has_no_borrow() {
+        let f = Fixture::new(&[0x8125]).reg(1, 0x42).reg(2, 0x42).run();
+        assert_eq!((f.v(1), f.v(0xF)), (0x00, 1));
+
+        let f = Fixture::new(&[0x8F15]).reg(1, 0x20).reg(0xF, 0x10).run();
+        assert_eq!(f.v(0xF), 0);
+    }
+
+    #[test]
+    fn shr_8xy6() {
+        let f = Fixture::new(&[0x8126]).reg(1, 0x05).reg(2, 0x80).run();
+        assert_eq!((f.v(1), f.v(0xF)), (0x02, 1));
+
+        let f = Fixture::with_quirks(&[0x8126], Quirks::cosmac_vip());
+        let f = f.reg(1, 0x05).reg(2, 0x80).run();
+        assert_eq!((f.v(1), f.v(0xF)), (0x40, 0));
+    }
+
+    #[test]
+    fn subn_8xy7() {
+        let f = Fixture::new(&[0x8127]).reg(1, 0x10).reg(2, 0x30).run();
+        assert_eq!((f.v(1), f.v(0xF)), (0x20, 1));
+
+        let f = Fixture::new(&[0x8127]).reg(1, 0x30).reg(2, 0x10).run();
+        assert_eq!((f.v(1), f.v(0xF)), (0xE0, 0));
+    }
+
+    #[test]
+    fn subn_8xy7_equal_has_no_borrow() {
+        let f = Fixture::new(&[0x8127]).reg(1, 0x42).reg(2, 0x42).run();
+        assert_eq!((f.v(1), f.v(0xF)), (0x00, 1));
+    }
+
+    #[test]
+    fn shl_8xye() {
+        let f = Fixture::new(&[0x812E]).reg(1, 0x81).reg(2, 0x01).run();
+        assert_eq!((f.v(1), f.v(0xF)), (0x02, 1));
+
+        let f = Fixture::with_quirks(&[0x812E], Quirks::cosmac_vip());
+        let f = f.reg(1, 0x81).reg(2, 0x01).run();
+        assert_eq!((f.v(1), f.v(0xF)), (0x02, 0));
+    }
+
+    #[test]
+    fn sne_9xy0() {
+        assert!(!skips(Fixture::new(&[0x9120]).reg(1, 7).reg(2, 7)));
+        assert!(skips(Fixture::new(&[0x9120]).reg(1, 7).reg(2, 8)));
+    }
+
+    #[test]
+    fn ld_annn() {
+        assert_eq!(Fixture::new(&[0xA123]).run().cpu.i(), 0x123);
+    }
+
+    #[test]
+    fn jp_bnnn() {
+        let f = Fixture::new(&[0xB300]).reg(0, 0x10).reg(3, 0x20).run();
+        assert_eq!(f.pc(), 0x310);
+
+        let f = Fixture::with_quirks(&[0xB300], Quirks::superchip());
+        let f = f.reg(0, 0x10).reg(3, 0x20).run();
+        assert_eq!(f.pc(), 0x320);
+    }
+
+    #[test]
+    fn rnd_cxnn() {
+        let mut random = Xorshift::new(1);
+        let f = Fixture::new(&[0xC50F]).run();
+        assert_eq!(f.v(5), random.next_byte() & 0x0F);
+    }
+
+    #[test]
+    fn drw_dxyn() {
+        let f = Fixture::new(&[0xD012, 0xD012])
+            .write(0x300, &[0xF0, 0x90])
+            .index(0x300)
+            .reg(0, 62)
+            .reg(1, 31)
+            .run();
+        // The sprite wraps around both edges of the display.
+        assert_eq!(f.pixel(63, 31), 1);
+        assert_eq!(f.pixel(1, 31), 1);
+        assert_eq!(f.pixel(62, 0), 1);
+        assert_eq!(f.pixel(63, 0), 0);
+        assert_eq!(f.pixel(1, 0), 1);
+        assert_eq!(f.v(0xF), 0);
+        assert_eq!(f.cpu.i(), 0x300);
+
+        let f = f.run();
+        assert!(f.bus.display().iter().all(|&pixel| pixel == 0));
+        assert_eq!(f.v(0xF), 1);
+    }
+
+    #[test]
+    fn skp_ex9e() {
+        assert!(skips(Fixture::new(&[0xE39E]).reg(3, 0xA).press(0xA)));
+        assert!(!skips(Fixture::new(&[0xE39E]).reg(3, 0xA).press(0xB)));
+    }
+
+    #[test]
+    fn sknp_exa1() {
+        assert!(!skips(Fixture::new(&[0xE3A1]).reg(3, 0xA).press(0xA)));
+        assert!(skips(Fixture::new(&[0xE3A1]).reg(3, 0xA).press(0xB)));
+        assert!(skips(Fixture::new(&[0xE3A1]).reg(3, 0x1A).press(0xA)));
+    }
+
+    #[test]
+    fn ld_fx07() {
+        let mut f = Fixture::new(&[0xF407]);
+        f.bus.set_delay_timer(33);
+        assert_eq!(f.run().v(4), 33);
+    }
+
+    #[test]
+    fn ld_fx0a() {
+        let mut f = Fixture::new(&[0xF40A]);
+        assert_eq!(f.tick(), Ok(TickOutcome::WaitingForKey));
+        assert_eq!(f.pc(), 0x200);
+
+        let f = f.press(0xC).run();
+        assert_eq!(f.v(4), 0xC);
+        assert_eq!(f.pc(), 0x202);
+    }
+
+    #[test]
+    fn ld_fx15() {
+        let f = Fixture::new(&[0xF415]).reg(4, 20).run();
+        assert_eq!(f.bus.delay_timer(), 20);
+    }
+
+    #[test]
+    fn ld_fx18() {
+        let f = Fixture::new(&[0xF418]).reg(4, 20).run();
+        assert_eq!(f.bus.sound_timer(), 20);
+        assert!(f.bus.sound_active());
+    }
+
+    #[test]
+    fn add_fx1e() {
+        let f = Fixture::new(&[0xF41E])
+            .index(0x2F0)
+            .reg(4, 0x20)
+            .reg(0xF, 7);
+        let f = f.run();
+        assert_eq!(f.cpu.i(), 0x310);
+        assert_eq!(f.v(0xF), 7);
+    }
+
+    #[test]
+    fn ld_fx29() {
+        let f = Fixture::new(&[0xF429]).reg(4, 0xB).run();
+        assert_eq!(f.memory(f.cpu.i(), 5), &[0xE0, 0x90, 0xE0, 0x90, 0xE0]);
+        assert_eq!(
+            Fixture::new(&[0xF429]).reg(4, 0x1B).run().cpu.i(),
+            f.cpu.i()
+        );
+    }
+
+    #[test]
+    fn ld_fx33() {
+        let f = Fixture::new(&[0xF433]).index(0x300).reg(4, 254).run();
+        assert_eq!(f.memory(0x300, 3), &[2, 5, 4]);
+        assert_eq!(f.cpu.i(), 0x300);
+
+        let mut f = Fixture::new(&[0xF433]).index(0xFFE);
+        assert_eq!(
+            f.tick(),
+            Err(CpuError::MemoryOutOfBounds {
+                pc: 0x200,
+                opcode: 0xF433,
+                address: 0x1000
+            })
+        );
+    }
+
+    #[test]
+    fn ld_fx55() {
+        let f = Fixture::new(&[0xF255])
+            .index(0x300)
+            .reg(0, 1)
+            .reg(1, 2)
+            .reg(2, 3)
+            .reg(3, 4)
+            .run();
+        assert_eq!(f.memory(0x300, 4), &[1, 2, 3, 0]);
+        assert_eq!(f.cpu.i(), 0x300);
+
+        let f = Fixture::with_quirks(&[0xF255], Quirks::cosmac_vip());
+        assert_eq!(f.index(0x300).run().cpu.i(), 0x303);
+
+        let mut f = Fixture::new(&[0xF255]).index(usize::MAX);
+        assert_eq!(
+            f.tick(),
+            Err(CpuError::MemoryOutOfBounds {
+                pc: 0x200,
+                opcode: 0xF255,
+                address: usize::MAX
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_roms_larger_than_memory() {
+        assert!(Cpu::new(&[0; 0xE00], Platform::Chip8, Quirks::default()).is_ok());
+        assert_eq!(
+            Cpu::new(&[0; 0xE01], Platform::Chip8, Quirks::default()).err(),
+            Some(LoadError::TooLarge {
+                size: 0xE01,
+                capacity: 0xE00
+            })
+        );
+        assert!(Cpu::new(&[0; 0xE01], Platform::XoChip, Quirks::default()).is_ok());
+    }
+
+    #[test]
+    fn stops_when_pc_leaves_memory() {
+        for &pc in [0xFFF, 0x1000, usize::MAX].iter() {
+            let mut f = Fixture::new(&[]);
+            f.cpu.set_pc(pc);
+            assert_eq!(f.tick(), Err(CpuError::ProgramCounterOutOfBounds { pc }));
+        }
+    }
+
+    #[test]
+    fn ld_fx65() {
+        let f = Fixture::new(&[0xF265])
+            .write(0x300, &[1, 2, 3, 4])
+            .index(0x300)
+            .run();
+        assert_eq!(&f.cpu.registers()[..4], &[1, 2, 3, 0]);
+        assert_eq!(f.cpu.i(), 0x300);
+
+        let f = Fixture::with_quirks(&[0xF265], Quirks::chip48());
+        assert_eq!(f.index(0x300).run().cpu.i(), 0x302);
+    }
+
+    #[test]
+    fn stops_when_a_condition_starts_to_hold() {
+        // ADD V0, 0x01 (x3); LD V0, 0x00; ADD V0, 0x01 (x2)
+        let mut f = Fixture::new(&[0x7001, 0x7001, 0x7001, 0x6000, 0x7001, 0x7001]);
+        let condition = Condition::parse("V0 >= 2").unwrap();
+        f.cpu.add_condition(condition);
+        // A condition which already holds only stops once it has not held.
+        f.cpu.add_condition(Condition::parse("V1 == 0").unwrap());
+
+        let outcomes: Vec<TickOutcome> = (0..6).map(|_| f.tick().unwrap()).collect();
+        assert_eq!(
+            outcomes,
+            vec![
+                TickOutcome::Executed,
+                TickOutcome::Condition(condition),
+                TickOutcome::Executed,
+                TickOutcome::Executed,
+                TickOutcome::Executed,
+                TickOutcome::Condition(condition),
+            ]
+        );
+    }
+
+    #[test]
+    fn stops_when_a_watched_address_is_written() {
+        // LD [I], V2 writes 0x300-0x302 of which 0x302 is watched.
+        let mut f = Fixture::new(&[0xF255, 0xF255]).index(0x300).reg(2, 7);
+        f.cpu.add_watchpoint(0x302..0x304);
+        assert_eq!(f.tick(), Ok(TickOutcome::Watchpoint(0x302)));
+        assert_eq!(f.memory(0x300, 3), &[0, 0, 7]);
+        assert_eq!(f.pc(), 0x202);
+
+        assert!(f.cpu.remove_watchpoint(&(0x302..0x304)));
+        assert_eq!(f.tick(), Ok(TickOutcome::Executed));
+    }
+
+    // A writer whose output can be read after it is handed to the CPU.
+    #[derive(Clone, Default)]
+    struct Output(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn traces_retired_instructions() {
+        // LD V0, K; ADD V0, 0x01; RET
+        let mut f = Fixture::new(&[0xF00A, 0x7001, 0x00EE]);
+        let output = Output::default();
+        let source_map = [(0x200, 1), (0x202, 2)].iter().cloned().collect();
+        f.cpu.start_trace(Box::new(output.clone()), source_map);
+        for _ in 0..3 {
+            assert_eq!(f.tick(), Ok(TickOutcome::WaitingForKey));
+        }
+        f = f.press(0x5).run().run();
+        // Instructions which fail are traced to show where a run stopped.
+        assert!(f.tick().is_err());
+        f.cpu.stop_trace().unwrap();
+
+        let text = String::from_utf8(output.0.borrow().clone()).unwrap();
+        let records: Vec<_> = text.lines().map(|line| parse_line(line).unwrap()).collect();
+        let fields = |field: &str| -> Vec<Value> {
+            records.iter().map(|record| record[field].clone()).collect()
+        };
+        assert_eq!(
+            fields("pc"),
+            vec![
+                Value::Number(0x200),
+                Value::Number(0x202),
+                Value::Number(0x204)
+            ]
+        );
+        assert_eq!(
+            fields("cycle"),
+            vec![Value::Number(0), Value::Number(1), Value::Number(2)]
+        );
+        // Only instructions in the source map have a line.
+        assert_eq!(records[0]["line"], Value::Number(1));
+        assert_eq!(records[1]["line"], Value::Number(2));
+        assert!(!records[2].contains_key("line"));
+    }
+
+    #[test]
+    fn profiles_executed_instructions() {
+        // CALL 0x204; JP 0x202; ADD V0, 0x01; RET
+        let mut f = Fixture::new(&[0x2204, 0x1202, 0x7001, 0x00EE]);
+        f.cpu.start_profile();
+        for _ in 0..5 {
+            f = f.run();
+        }
+        // Instructions which fail (here RET with an empty stack) are not
+        // counted.
+        f.cpu.set_pc(0x206);
+        assert!(f.tick().is_err());
+
+        let profile = f.cpu.stop_profile().unwrap();
+        assert_eq!(profile.instructions(), 5);
+        assert_eq!(profile.hottest()[0], (0x202, 2));
+        assert_eq!(profile.classes()[0], ("1NNN", 2));
+        assert_eq!(profile.subroutines()[0].0, 0x204);
+        assert_eq!(profile.subroutines()[0].1.instructions, 2);
+        assert!(f.cpu.profile().is_none());
+    }
+
+    #[test]
+    fn profiles_key_waits_once() {
+        // LD V0, K; ADD V0, 0x01
+        let mut f = Fixture::new(&[0xF00A, 0x7001]);
+        f.cpu.start_profile();
+        for _ in 0..3 {
+            assert_eq!(f.tick(), Ok(TickOutcome::WaitingForKey));
+        }
+        f = f.press(0x5).run().run();
+
+        let profile = f.cpu.profile().unwrap();
+        assert_eq!(profile.instructions(), 2);
+        assert_eq!(profile.hottest(), vec![(0x200, 1), (0x202, 1)]);
+        assert_eq!(profile.classes(), vec![("7XNN", 1), ("FX0A", 1)]);
+    }
+
+    #[test]
+    fn covers_memory_accesses() {
+        // LD F, V0; DRW V0, V0, 5; LD I, 0x300; LD B, V0; LD V1, [I]
+        let mut f = Fixture::new(&[0xF029, 0xD005, 0xA300, 0xF033, 0xF165]);
+        f.cpu.start_coverage();
+        for _ in 0..5 {
+            f = f.run();
+        }
+
+        let coverage = f.cpu.stop_coverage().unwrap();
+        assert_eq!(coverage.flags(0x200..0x20A), "X...");
+        assert!(!coverage.has(0x20A, Access::Executed));
+        assert_eq!(coverage.count(0..0x50, Access::Sprite), 5);
+        assert_eq!(coverage.flags(0x300..0x302), "..RW");
+        assert_eq!(coverage.flags(0x302..0x303), "...W");
     }
 }
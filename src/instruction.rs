@@ -0,0 +1,421 @@
+// Copyright of Jordan Werthman (2019).
+
+use std::fmt;
+
+// Models a decoded CHIP-8 instruction
+//
+// Each opcode is decoded into an Instruction which the CPU executes and
+// which displays as a Cowgod-style mnemonic (e.g. "LD V3, 0x1F") as found
+// at: http://devernay.free.fr/hacks/chip8/C8TECH10.HTM. The SUPER-CHIP and
+// XO-CHIP extensions are always decoded; it is up to the CPU to reject them
+// when running a platform which does not support them.
+//
+// Registers are decoded as indices (X/Y), bytes as NN, sprite heights and
+// scroll amounts as N and addresses as NNN.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    Cls,                     // 00E0
+    Ret,                     // 00EE
+    Sys(usize),              // 0NNN
+    Jp(usize),               // 1NNN
+    Call(usize),             // 2NNN
+    SeByte(usize, u8),       // 3XNN
+    SneByte(usize, u8),      // 4XNN
+    SeReg(usize, usize),     // 5XY0
+    LdByte(usize, u8),       // 6XNN
+    AddByte(usize, u8),      // 7XNN
+    LdReg(usize, usize),     // 8XY0
+    Or(usize, usize),        // 8XY1
+    And(usize, usize),       // 8XY2
+    Xor(usize, usize),       // 8XY3
+    AddReg(usize, usize),    // 8XY4
+    Sub(usize, usize),       // 8XY5
+    Shr(usize, usize),       // 8XY6
+    Subn(usize, usize),      // 8XY7
+    Shl(usize, usize),       // 8XYE
+    SneReg(usize, usize),    // 9XY0
+    LdI(usize),              // ANNN
+    JpV0(usize),             // BNNN
+    Rnd(usize, u8),          // CXNN
+    Drw(usize, usize, u8),   // DXYN
+    Skp(usize),              // EX9E
+    Sknp(usize),             // EXA1
+    LdVxDt(usize),           // FX07
+    LdVxK(usize),            // FX0A
+    LdDtVx(usize),           // FX15
+    LdStVx(usize),           // FX18
+    AddI(usize),             // FX1E
+    LdF(usize),              // FX29
+    LdB(usize),              // FX33
+    LdMemVx(usize),          // FX55
+    LdVxMem(usize),          // FX65
+    Scd(u8),                 // 00CN (SUPER-CHIP)
+    Scr,                     // 00FB (SUPER-CHIP)
+    Scl,                     // 00FC (SUPER-CHIP)
+    Exit,                    // 00FD (SUPER-CHIP)
+    Low,                     // 00FE (SUPER-CHIP)
+    High,                    // 00FF (SUPER-CHIP)
+    LdHf(usize),             // FX30 (SUPER-CHIP)
+    LdRVx(usize),            // FX75 (SUPER-CHIP)
+    LdVxR(usize),            // FX85 (SUPER-CHIP)
+    Scu(u8),                 // 00DN (XO-CHIP)
+    SaveRange(usize, usize), // 5XY2 (XO-CHIP)
+    LoadRange(usize, usize), // 5XY3 (XO-CHIP)
+    LdILong(usize),          // F000 NNNN (XO-CHIP)
+    Plane(u8),               // FN01 (XO-CHIP)
+    Audio,                   // F002 (XO-CHIP)
+    Pitch(usize),            // FX3A (XO-CHIP)
+    Unknown(u16),
+}
+
+// The first word of the only 4-byte instruction (XO-CHIP: F000 NNNN).
+pub const LONG_OPCODE: u16 = 0xF000;
+
+// Decodes a single opcode. F000 is decoded as LdILong(0) as its address
+// is stored in the following word; see disassemble().
+pub fn decode(opcode: u16) -> Instruction {
+    let nnn = (opcode & 0xFFF) as usize;
+    let nn = (opcode & 0xFF) as u8;
+    let n = (opcode & 0xF) as u8;
+    let x = ((opcode >> 8) & 0xF) as usize;
+    let y = ((opcode >> 4) & 0xF) as usize;
+
+    let opcode_exploded = (
+        (opcode >> 12) & 0xF,
+        (opcode >> 8) & 0xF,
+        (opcode >> 4) & 0xF,
+        opcode & 0xF,
+    );
+
+    match opcode_exploded {
+        (0x0, 0x0, 0xE, 0x0) => Instruction::Cls,
+        (0x0, 0x0, 0xE, 0xE) => Instruction::Ret,
+        (0x0, 0x0, 0xC, _) => Instruction::Scd(n),
+        (0x0, 0x0, 0xD, _) => Instruction::Scu(n),
+        (0x0, 0x0, 0xF, 0xB) => Instruction::Scr,
+        (0x0, 0x0, 0xF, 0xC) => Instruction::Scl,
+        (0x0, 0x0, 0xF, 0xD) => Instruction::Exit,
+        (0x0, 0x0, 0xF, 0xE) => Instruction::Low,
+        (0x0, 0x0, 0xF, 0xF) => Instruction::High,
+        (0x0, _, _, _) => Instruction::Sys(nnn),
+        (0x1, _, _, _) => Instruction::Jp(nnn),
+        (0x2, _, _, _) => Instruction::Call(nnn),
+        (0x3, _, _, _) => Instruction::SeByte(x, nn),
+        (0x4, _, _, _) => Instruction::SneByte(x, nn),
+        (0x5, _, _, 0x0) => Instruction::SeReg(x, y),
+        (0x5, _, _, 0x2) => Instruction::SaveRange(x, y),
+        (0x5, _, _, 0x3) => Instruction::LoadRange(x, y),
+        (0x6, _, _, _) => Instruction::LdByte(x, nn),
+        (0x7, _, _, _) => Instruction::AddByte(x, nn),
+        (0x8, _, _, 0x0) => Instruction::LdReg(x, y),
+        (0x8, _, _, 0x1) => Instruction::Or(x, y),
+        (0x8, _, _, 0x2) => Instruction::And(x, y),
+        (0x8, _, _, 0x3) => Instruction::Xor(x, y),
+        (0x8, _, _, 0x4) => Instruction::AddReg(x, y),
+        (0x8, _, _, 0x5) => Instruction::Sub(x, y),
+        (0x8, _, _, 0x6) => Instruction::Shr(x, y),
+        (0x8, _, _, 0x7) => Instruction::Subn(x, y),
+        (0x8, _, _, 0xE) => Instruction::Shl(x, y),
+        (0x9, _, _, 0x0) => Instruction::SneReg(x, y),
+        (0xA, _, _, _) => Instruction::LdI(nnn),
+        (0xB, _, _, _) => Instruction::JpV0(nnn),
+        (0xC, _, _, _) => Instruction::Rnd(x, nn),
+        (0xD, _, _, _) => Instruction::Drw(x, y, n),
+        (0xE, _, 0x9, 0xE) => Instruction::Skp(x),
+        (0xE, _, 0xA, 0x1) => Instruction::Sknp(x),
+        (0xF, 0x0, 0x0, 0x0) => Instruction::LdILong(0),
+        (0xF, _, 0x0, 0x1) => Instruction::Plane(x as u8),
+        (0xF, 0x0, 0x0, 0x2) => Instruction::Audio,
+        (0xF, _, 0x0, 0x7) => Instruction::LdVxDt(x),
+        (0xF, _, 0x0, 0xA) => Instruction::LdVxK(x),
+        (0xF, _, 0x1, 0x5) => Instruction::LdDtVx(x),
+        (0xF, _, 0x1, 0x8) => Instruction::LdStVx(x),
+        (0xF, _, 0x1, 0xE) => Instruction::AddI(x),
+        (0xF, _, 0x2, 0x9) => Instruction::LdF(x),
+        (0xF, _, 0x3, 0x0) => Instruction::LdHf(x),
+        (0xF, _, 0x3, 0x3) => Instruction::LdB(x),
+        (0xF, _, 0x3, 0xA) => Instruction::Pitch(x),
+        (0xF, _, 0x5, 0x5) => Instruction::LdMemVx(x),
+        (0xF, _, 0x6, 0x5) => Instruction::LdVxMem(x),
+        (0xF, _, 0x7, 0x5) => Instruction::LdRVx(x),
+        (0xF, _, 0x8, 0x5) => Instruction::LdVxR(x),
+        (_, _, _, _) => Instruction::Unknown(opcode),
+    }
+}
+
+// Decodes |rom| (loaded at |base|) word by word into (address, instruction)
+// pairs. Both words of F000 NNNN are decoded together (or as Unknown if
+// the ROM ends before its address) and a trailing odd byte is ignored.
+pub fn disassemble(rom: &[u8], base: usize) -> Vec<(usize, Instruction)> {
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+
+    while offset + 1 < rom.len() {
+        let opcode = read_word(rom, offset);
+        let instruction = match decode(opcode) {
+            Instruction::LdILong(_) if offset + 3 < rom.len() => {
+                Instruction::LdILong(read_word(rom, offset + 2) as usize)
+            }
+            Instruction::LdILong(_) => Instruction::Unknown(opcode),
+            instruction => instruction,
+        };
+
+        instructions.push((base + offset, instruction));
+        offset += instruction.size();
+    }
+    instructions
+}
+
+fn read_word(rom: &[u8], offset: usize) -> u16 {
+    (rom[offset] as u16) << 8 | rom[offset + 1] as u16
+}
+
+impl Instruction {
+    // The length of the instruction in bytes.
+    pub fn size(&self) -> usize {
+        match self {
+            Instruction::LdILong(_) => 4,
+            _ => 2,
+        }
+    }
+
+    // The pattern of the opcode (e.g. "8XY4") which identifies its class.
+    pub fn pattern(&self) -> &'static str {
+        match self {
+            Instruction::Cls => "00E0",
+            Instruction::Ret => "00EE",
+            Instruction::Sys(_) => "0NNN",
+            Instruction::Jp(_) => "1NNN",
+            Instruction::Call(_) => "2NNN",
+            Instruction::SeByte(..) => "3XNN",
+            Instruction::SneByte(..) => "4XNN",
+            Instruction::SeReg(..) => "5XY0",
+            Instruction::LdByte(..) => "6XNN",
+            Instruction::AddByte(..) => "7XNN",
+            Instruction::LdReg(..) => "8XY0",
+            Instruction::Or(..) => "8XY1",
+            Instruction::And(..) => "8XY2",
+            Instruction::Xor(..) => "8XY3",
+            Instruction::AddReg(..) => "8XY4",
+            Instruction::Sub(..) => "8XY5",
+            Instruction::Shr(..) => "8XY6",
+            Instruction::Subn(..) => "8XY7",
+            Instruction::Shl(..) => "8XYE",
+            Instruction::SneReg(..) => "9XY0",
+            Instruction::LdI(_) => "ANNN",
+            Instruction::JpV0(_) => "BNNN",
+            Instruction::Rnd(..) => "CXNN",
+            Instruction::Drw(..) => "DXYN",
+            Instruction::Skp(_) => "EX9E",
+            Instruction::Sknp(_) => "EXA1",
+            Instruction::LdVxDt(_) => "FX07",
+            Instruction::LdVxK(_) => "FX0A",
+            Instruction::LdDtVx(_) => "FX15",
+            Instruction::LdStVx(_) => "FX18",
+            Instruction::AddI(_) => "FX1E",
+            Instruction::LdF(_) => "FX29",
+            Instruction::LdB(_) => "FX33",
+            Instruction::LdMemVx(_) => "FX55",
+            Instruction::LdVxMem(_) => "FX65",
+            Instruction::Scd(_) => "00CN",
+            Instruction::Scr => "00FB",
+            Instruction::Scl => "00FC",
+            Instruction::Exit => "00FD",
+            Instruction::Low => "00FE",
+            Instruction::High => "00FF",
+            Instruction::LdHf(_) => "FX30",
+            Instruction::LdRVx(_) => "FX75",
+            Instruction::LdVxR(_) => "FX85",
+            Instruction::Scu(_) => "00DN",
+            Instruction::SaveRange(..) => "5XY2",
+            Instruction::LoadRange(..) => "5XY3",
+            Instruction::LdILong(_) => "F000",
+            Instruction::Plane(_) => "FN01",
+            Instruction::Audio => "F002",
+            Instruction::Pitch(_) => "FX3A",
+            Instruction::Unknown(_) => "????",
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Instruction::Cls => write!(f, "CLS"),
+            Instruction::Ret => write!(f, "RET"),
+            Instruction::Sys(nnn) => write!(f, "SYS 0x{:03X}", nnn),
+            Instruction::Jp(nnn) => write!(f, "JP 0x{:03X}", nnn),
+            Instruction::Call(nnn) => write!(f, "CALL 0x{:03X}", nnn),
+            Instruction::SeByte(x, nn) => write!(f, "SE V{:X}, 0x{:02X}", x, nn),
+            Instruction::SneByte(x, nn) => write!(f, "SNE V{:X}, 0x{:02X}", x, nn),
+            Instruction::SeReg(x, y) => write!(f, "SE V{:X}, V{:X}", x, y),
+            Instruction::LdByte(x, nn) => write!(f, "LD V{:X}, 0x{:02X}", x, nn),
+            Instruction::AddByte(x, nn) => write!(f, "ADD V{:X}, 0x{:02X}", x, nn),
+            Instruction::LdReg(x, y) => write!(f, "LD V{:X}, V{:X}", x, y),
+            Instruction::Or(x, y) => write!(f, "OR V{:X}, V{:X}", x, y),
+            Instruction::And(x, y) => write!(f, "AND V{:X}, V{:X}", x, y),
+            Instruction::Xor(x, y) => write!(f, "XOR V{:X}, V{:X}", x, y),
+            Instruction::AddReg(x, y) => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Instruction::Sub(x, y) => write!(f, "SUB V{:X}, V{:X}", x, y),
+            Instruction::Shr(x, y) => write!(f, "SHR V{:X}, V{:X}", x, y),
+            Instruction::Subn(x, y) => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            Instruction::Shl(x, y) => write!(f, "SHL V{:X}, V{:X}", x, y),
+            Instruction::SneReg(x, y) => write!(f, "SNE V{:X}, V{:X}", x, y),
+            Instruction::LdI(nnn) => write!(f, "LD I, 0x{:03X}", nnn),
+            Instruction::JpV0(nnn) => write!(f, "JP V0, 0x{:03X}", nnn),
+            Instruction::Rnd(x, nn) => write!(f, "RND V{:X}, 0x{:02X}", x, nn),
+            Instruction::Drw(x, y, n) => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+            Instruction::Skp(x) => write!(f, "SKP V{:X}", x),
+            Instruction::Sknp(x) => write!(f, "SKNP V{:X}", x),
+            Instruction::LdVxDt(x) => write!(f, "LD V{:X}, DT", x),
+            Instruction::LdVxK(x) => write!(f, "LD V{:X}, K", x),
+            Instruction::LdDtVx(x) => write!(f, "LD DT, V{:X}", x),
+            Instruction::LdStVx(x) => write!(f, "LD ST, V{:X}", x),
+            Instruction::AddI(x) => write!(f, "ADD I, V{:X}", x),
+            Instruction::LdF(x) => write!(f, "LD F, V{:X}", x),
+            Instruction::LdB(x) => write!(f, "LD B, V{:X}", x),
+            Instruction::LdMemVx(x) => write!(f, "LD [I], V{:X}", x),
+            Instruction::LdVxMem(x) => write!(f, "LD V{:X}, [I]", x),
+            Instruction::Scd(n) => write!(f, "SCD {}", n),
+            Instruction::Scr => write!(f, "SCR"),
+            Instruction::Scl => write!(f, "SCL"),
+            Instruction::Exit => write!(f, "EXIT"),
+            Instruction::Low => write!(f, "LOW"),
+            Instruction::High => write!(f, "HIGH"),
+            Instruction::LdHf(x) => write!(f, "LD HF, V{:X}", x),
+            Instruction::LdRVx(x) => write!(f, "LD R, V{:X}", x),
+            Instruction::LdVxR(x) => write!(f, "LD V{:X}, R", x),
+            Instruction::Scu(n) => write!(f, "SCU {}", n),
+            Instruction::SaveRange(x, y) => write!(f, "SAVE V{:X}, V{:X}", x, y),
+            Instruction::LoadRange(x, y) => write!(f, "LOAD V{:X}, V{:X}", x, y),
+            Instruction::LdILong(nnnn) => write!(f, "LD I, LONG 0x{:04X}", nnnn),
+            Instruction::Plane(n) => write!(f, "PLANE {}", n),
+            Instruction::Audio => write!(f, "AUDIO"),
+            Instruction::Pitch(x) => write!(f, "PITCH V{:X}", x),
+            Instruction::Unknown(opcode) => write!(f, "DW 0x{:04X}", opcode),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_each_class() {
+        let cases = [
+            (0x00E0, Instruction::Cls, "CLS", "00E0"),
+            (0x00EE, Instruction::Ret, "RET", "00EE"),
+            (0x0123, Instruction::Sys(0x123), "SYS 0x123", "0NNN"),
+            (0x1ABC, Instruction::Jp(0xABC), "JP 0xABC", "1NNN"),
+            (0x2ABC, Instruction::Call(0xABC), "CALL 0xABC", "2NNN"),
+            (
+                0x3A12,
+                Instruction::SeByte(0xA, 0x12),
+                "SE VA, 0x12",
+                "3XNN",
+            ),
+            (
+                0x4A12,
+                Instruction::SneByte(0xA, 0x12),
+                "SNE VA, 0x12",
+                "4XNN",
+            ),
+            (0x5AB0, Instruction::SeReg(0xA, 0xB), "SE VA, VB", "5XY0"),
+            (
+                0x6A12,
+                Instruction::LdByte(0xA, 0x12),
+                "LD VA, 0x12",
+                "6XNN",
+            ),
+            (
+                0x7A12,
+                Instruction::AddByte(0xA, 0x12),
+                "ADD VA, 0x12",
+                "7XNN",
+            ),
+            (0x8AB0, Instruction::LdReg(0xA, 0xB), "LD VA, VB", "8XY0"),
+            (0x8AB1, Instruction::Or(0xA, 0xB), "OR VA, VB", "8XY1"),
+            (0x8AB2, Instruction::And(0xA, 0xB), "AND VA, VB", "8XY2"),
+            (0x8AB3, Instruction::Xor(0xA, 0xB), "XOR VA, VB", "8XY3"),
+            (0x8AB4, Instruction::AddReg(0xA, 0xB), "ADD VA, VB", "8XY4"),
+            (0x8AB5, Instruction::Sub(0xA, 0xB), "SUB VA, VB", "8XY5"),
+            (0x8AB6, Instruction::Shr(0xA, 0xB), "SHR VA, VB", "8XY6"),
+            (0x8AB7, Instruction::Subn(0xA, 0xB), "SUBN VA, VB", "8XY7"),
+            (0x8ABE, Instruction::Shl(0xA, 0xB), "SHL VA, VB", "8XYE"),
+            (0x9AB0, Instruction::SneReg(0xA, 0xB), "SNE VA, VB", "9XY0"),
+            (0xAABC, Instruction::LdI(0xABC), "LD I, 0xABC", "ANNN"),
+            (0xBABC, Instruction::JpV0(0xABC), "JP V0, 0xABC", "BNNN"),
+            (0xCA12, Instruction::Rnd(0xA, 0x12), "RND VA, 0x12", "CXNN"),
+            (
+                0xDAB5,
+                Instruction::Drw(0xA, 0xB, 5),
+                "DRW VA, VB, 5",
+                "DXYN",
+            ),
+            (0xEA9E, Instruction::Skp(0xA), "SKP VA", "EX9E"),
+            (0xEAA1, Instruction::Sknp(0xA), "SKNP VA", "EXA1"),
+            (0xFA07, Instruction::LdVxDt(0xA), "LD VA, DT", "FX07"),
+            (0xFA0A, Instruction::LdVxK(0xA), "LD VA, K", "FX0A"),
+            (0xFA15, Instruction::LdDtVx(0xA), "LD DT, VA", "FX15"),
+            (0xFA18, Instruction::LdStVx(0xA), "LD ST, VA", "FX18"),
+            (0xFA1E, Instruction::AddI(0xA), "ADD I, VA", "FX1E"),
+            (0xFA29, Instruction::LdF(0xA), "LD F, VA", "FX29"),
+            (0xFA33, Instruction::LdB(0xA), "LD B, VA", "FX33"),
+            (0xFA55, Instruction::LdMemVx(0xA), "LD [I], VA", "FX55"),
+            (0xFA65, Instruction::LdVxMem(0xA), "LD VA, [I]", "FX65"),
+            (0x00C4, Instruction::Scd(4), "SCD 4", "00CN"),
+            (0x00FB, Instruction::Scr, "SCR", "00FB"),
+            (0x00FC, Instruction::Scl, "SCL", "00FC"),
+            (0x00FD, Instruction::Exit, "EXIT", "00FD"),
+            (0x00FE, Instruction::Low, "LOW", "00FE"),
+            (0x00FF, Instruction::High, "HIGH", "00FF"),
+            (0xFA30, Instruction::LdHf(0xA), "LD HF, VA", "FX30"),
+            (0xF775, Instruction::LdRVx(0x7), "LD R, V7", "FX75"),
+            (0xF785, Instruction::LdVxR(0x7), "LD V7, R", "FX85"),
+            (0x00D4, Instruction::Scu(4), "SCU 4", "00DN"),
+            (
+                0x5AB2,
+                Instruction::SaveRange(0xA, 0xB),
+                "SAVE VA, VB",
+                "5XY2",
+            ),
+            (
+                0x5AB3,
+                Instruction::LoadRange(0xA, 0xB),
+                "LOAD VA, VB",
+                "5XY3",
+            ),
+            (0xF201, Instruction::Plane(2), "PLANE 2", "FN01"),
+            (0xF002, Instruction::Audio, "AUDIO", "F002"),
+            (0xFA3A, Instruction::Pitch(0xA), "PITCH VA", "FX3A"),
+            (0x5AB1, Instruction::Unknown(0x5AB1), "DW 0x5AB1", "????"),
+            (0xFAFF, Instruction::Unknown(0xFAFF), "DW 0xFAFF", "????"),
+        ];
+        for &(opcode, instruction, mnemonic, pattern) in cases.iter() {
+            assert_eq!(decode(opcode), instruction, "0x{:04X}", opcode);
+            assert_eq!(instruction.to_string(), mnemonic);
+            assert_eq!(instruction.pattern(), pattern);
+            assert_eq!(instruction.size(), 2);
+        }
+    }
+
+    #[test]
+    fn disassembles_long_load() {
+        assert_eq!(decode(0xF000), Instruction::LdILong(0));
+        let instructions = disassemble(&[0xF0, 0x00, 0x12, 0x34, 0x00, 0xE0, 0xF0, 0x00], 0x200);
+        assert_eq!(
+            instructions,
+            vec![
+                (0x200, Instruction::LdILong(0x1234)),
+                (0x204, Instruction::Cls),
+                // The ROM ends before the address of the last F000.
+                (0x206, Instruction::Unknown(0xF000)),
+            ]
+        );
+        assert_eq!(instructions[0].1.size(), 4);
+        assert_eq!(instructions[0].1.pattern(), "F000");
+        assert_eq!(instructions[0].1.to_string(), "LD I, LONG 0x1234");
+    }
+}
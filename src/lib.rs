@@ -1,11 +1,71 @@
+mod assembler;
+mod audio;
+mod breakpoint;
 mod bus;
 mod chip8;
+mod coverage;
 mod cpu;
+mod instruction;
+mod listing;
+mod machine;
 mod memory;
+mod octo;
+mod platform;
+mod profile;
+mod quirks;
+mod random;
+mod rewind;
+mod savestate;
+mod screen;
+mod timing;
+mod trace;
 
+pub use assembler::assemble;
+pub use assembler::assemble_file;
+pub use assembler::AssemblerError;
+pub use audio::Beeper;
+pub use audio::Recording;
+pub use breakpoint::Comparison;
+pub use breakpoint::Condition;
+pub use breakpoint::Register;
 pub use bus::Bus;
+pub use coverage::Access;
+pub use coverage::Coverage;
 pub use cpu::Cpu;
+pub use cpu::CpuError;
+pub use cpu::FrameReport;
+pub use cpu::TickOutcome;
 pub use chip8::Chip8;
+pub use instruction::decode;
+pub use instruction::disassemble;
+pub use instruction::Instruction;
+pub use listing::Listing;
+pub use machine::Config;
+pub use machine::Machine;
+pub use memory::LoadError;
+pub use octo::compile;
+pub use octo::Program;
+pub use platform::Platform;
+pub use profile::Profile;
+pub use profile::Subroutine;
+pub use quirks::LoadStoreQuirk;
+pub use quirks::Quirks;
+pub use random::RandomSource;
+pub use random::Xorshift;
+pub use savestate::StateError;
+pub use screen::Screen;
+pub use timing::Timing;
+pub use trace::first_divergence;
+pub use trace::parse_line;
+pub use trace::Divergence;
+pub use trace::Value;
 
-pub use bus::WINDOW_HEIGHT;
-pub use bus::WINDOW_WIDTH;
+pub use audio::DEFAULT_FREQUENCY;
+pub use audio::DEFAULT_VOLUME;
+pub use audio::PATTERN_SIZE;
+pub use bus::HIRES_HEIGHT;
+pub use bus::HIRES_WIDTH;
+pub use bus::LORES_HEIGHT;
+pub use bus::LORES_WIDTH;
+pub use machine::DEFAULT_SPEED;
+pub use timing::VIP_CYCLES_PER_SECOND;
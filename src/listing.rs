@@ -0,0 +1,309 @@
+// Copyright of Jordan Werthman (2019).
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::fmt;
+
+use crate::coverage::{Access, Coverage};
+use crate::instruction::{decode, Instruction};
+use crate::memory::USER_OFFSET;
+
+// Models a control-flow aware listing of a ROM
+//
+// Code is found by tracing every path reachable from the start of the ROM,
+// following jumps (1NNN/BNNN), calls (2NNN) and both outcomes of skips. Any
+// byte which is never reached is assumed to be data (e.g. sprites) and is
+// listed one byte per line alongside a bitmap of its set bits. Subroutines,
+// jump targets and addresses loaded into I are labelled and referenced by
+// name so that the listing can be read (and assembled) as a program (which
+// begins with ORG unless the ROM is loaded at 0x200):
+//
+//   start:
+//       LD I, data_20A         ; 0x200  A2 0A
+//   ...
+//   data_20A:
+//       DB 0x3C                ; 0x20A  ..####..
+//
+// A listing annotated with coverage (see annotate()) adds how each line was
+// accessed to its comment (see Coverage::flags()) e.g. "X..." for an
+// instruction which was executed and ".S.." for data drawn as a sprite.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum LabelKind {
+    Start,
+    Subroutine,
+    Jump,
+    Data,
+}
+
+pub struct Listing {
+    rom: Vec<u8>,
+    base: usize,
+    // Address of each instruction found by tracing the ROM.
+    code: BTreeMap<usize, Instruction>,
+    labels: BTreeMap<usize, LabelKind>,
+}
+
+impl Listing {
+    // Traces |rom| loaded at |base| (where execution begins).
+    pub fn new(rom: &[u8], base: usize) -> Listing {
+        let mut listing = Listing {
+            rom: rom.to_vec(),
+            base,
+            code: BTreeMap::new(),
+            labels: BTreeMap::new(),
+        };
+        listing.trace();
+        listing.remove_overlaps();
+        listing
+    }
+
+    // Lists the ROM with the accesses in |coverage| after a summary of how
+    // much of the ROM was covered.
+    pub fn annotate(&self, coverage: &Coverage) -> String {
+        let rom = self.base..self.base + self.rom.len();
+        let executed = self
+            .code
+            .keys()
+            .filter(|&&address| coverage.has(address, Access::Executed))
+            .count();
+        let mut listing = format!(
+            "; {} of {} instructions executed, {} bytes drawn as sprites, \
+             {} read and {} written\n",
+            executed,
+            self.code.len(),
+            coverage.count(rom.clone(), Access::Sprite),
+            coverage.count(rom.clone(), Access::Read),
+            coverage.count(rom, Access::Written)
+        );
+        let _ = self.write(&mut listing, Some(coverage));
+        listing
+    }
+
+    // Whether |address| is the start of a traced instruction.
+    pub fn is_code(&self, address: usize) -> bool {
+        self.code.contains_key(&address)
+    }
+
+    // The name given to |address| if it is a jump target, subroutine or
+    // data referenced by I.
+    pub fn label(&self, address: usize) -> Option<String> {
+        let name = match self.labels.get(&address)? {
+            LabelKind::Start => String::from("start"),
+            LabelKind::Subroutine => format!("sub_{:03X}", address),
+            LabelKind::Jump => format!("label_{:03X}", address),
+            LabelKind::Data => format!("data_{:03X}", address),
+        };
+        Some(name)
+    }
+
+    // Decodes the instruction at |address| including the address word of
+    // F000 NNNN. Returns None if the instruction runs past the end of the ROM.
+    fn instruction_at(&self, address: usize) -> Option<Instruction> {
+        let offset = address.checked_sub(self.base)?;
+        let word = |offset: usize| -> Option<u16> {
+            let high = *self.rom.get(offset)? as u16;
+            let low = *self.rom.get(offset + 1)? as u16;
+            Some(high << 8 | low)
+        };
+
+        match decode(word(offset)?) {
+            Instruction::LdILong(_) => Some(Instruction::LdILong(word(offset + 2)? as usize)),
+            instruction => Some(instruction),
+        }
+    }
+
+    fn add_label(&mut self, address: usize, kind: LabelKind) {
+        let label = self.labels.entry(address).or_insert(kind);
+        *label = (*label).min(kind);
+    }
+
+    fn trace(&mut self) {
+        let mut pending = vec![self.base];
+        self.add_label(self.base, LabelKind::Start);
+
+        while let Some(address) = pending.pop() {
+            if self.code.contains_key(&address) {
+                continue;
+            }
+            let instruction = match self.instruction_at(address) {
+                // Unknown opcodes (including 0NNN) halt the CPU so whatever
+                // follows is not known to be code.
+                Some(Instruction::Unknown(_)) | Some(Instruction::Sys(_)) | None => continue,
+                Some(instruction) => instruction,
+            };
+            self.code.insert(address, instruction);
+            let next = address + instruction.size();
+
+            match instruction {
+                Instruction::Jp(nnn) | Instruction::JpV0(nnn) => {
+                    self.add_label(nnn, LabelKind::Jump);
+                    pending.push(nnn);
+                }
+                Instruction::Call(nnn) => {
+                    self.add_label(nnn, LabelKind::Subroutine);
+                    pending.push(nnn);
+                    pending.push(next);
+                }
+                Instruction::SeByte(..)
+                | Instruction::SneByte(..)
+                | Instruction::SeReg(..)
+                | Instruction::SneReg(..)
+                | Instruction::Skp(_)
+                | Instruction::Sknp(_) => {
+                    pending.push(next);
+                    if let Some(skipped) = self.instruction_at(next) {
+                        pending.push(next + skipped.size());
+                    }
+                }
+                Instruction::Ret | Instruction::Exit => {}
+                Instruction::LdI(nnn) | Instruction::LdILong(nnn) => {
+                    self.add_label(nnn, LabelKind::Data);
+                    pending.push(next);
+                }
+                _ => pending.push(next),
+            }
+        }
+    }
+
+    // Drops instructions which overlap the one before them (e.g. a jump into
+    // the middle of an instruction) so that every byte is listed once.
+    fn remove_overlaps(&mut self) {
+        let mut end = 0;
+        let mut overlapping = Vec::new();
+        for (&address, instruction) in self.code.iter() {
+            if address < end {
+                overlapping.push(address);
+            } else {
+                end = address + instruction.size();
+            }
+        }
+        for address in overlapping {
+            self.code.remove(&address);
+        }
+    }
+
+    // The addresses at which a line of the listing starts.
+    fn line_starts(&self) -> BTreeSet<usize> {
+        let mut starts = BTreeSet::new();
+        let mut address = self.base;
+        while address < self.base + self.rom.len() {
+            starts.insert(address);
+            address += self.code.get(&address).map_or(1, Instruction::size);
+        }
+        starts
+    }
+
+    // Formats |instruction| replacing addresses with labels where the target
+    // is labelled and starts a line (so that it can be referred to).
+    fn format_instruction(&self, instruction: Instruction, starts: &BTreeSet<usize>) -> String {
+        let target = |nnn: usize| match self.label(nnn) {
+            Some(label) if starts.contains(&nnn) => label,
+            _ => format!("0x{:03X}", nnn),
+        };
+
+        match instruction {
+            Instruction::Jp(nnn) => format!("JP {}", target(nnn)),
+            Instruction::JpV0(nnn) => format!("JP V0, {}", target(nnn)),
+            Instruction::Call(nnn) => format!("CALL {}", target(nnn)),
+            Instruction::LdI(nnn) => format!("LD I, {}", target(nnn)),
+            Instruction::LdILong(nnnn) => match self.label(nnnn) {
+                Some(label) if starts.contains(&nnnn) => format!("LD I, LONG {}", label),
+                _ => instruction.to_string(),
+            },
+            _ => instruction.to_string(),
+        }
+    }
+
+    // Writes the listing adding the accesses in |coverage| to each line.
+    fn write(&self, f: &mut dyn fmt::Write, coverage: Option<&Coverage>) -> fmt::Result {
+        let starts = self.line_starts();
+        let annotation = |address: usize, len: usize, detail: String| match coverage {
+            Some(coverage) => format!("{:<11}  {}", detail, coverage.flags(address..address + len)),
+            None => detail,
+        };
+
+        if self.base != USER_OFFSET {
+            writeln!(f, "    ORG 0x{:03X}", self.base)?;
+        }
+        for &address in starts.iter() {
+            if let Some(label) = self.label(address) {
+                writeln!(f, "{}:", label)?;
+            }
+
+            let offset = address - self.base;
+            match self.code.get(&address) {
+                Some(&instruction) => {
+                    let bytes = &self.rom[offset..offset + instruction.size()];
+                    let raw: Vec<String> =
+                        bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+                    let text = self.format_instruction(instruction, &starts);
+                    let detail = annotation(address, bytes.len(), raw.join(" "));
+                    writeln!(f, "    {:<24}; 0x{:03X}  {}", text, address, detail)?;
+                }
+                None => {
+                    let byte = self.rom[offset];
+                    let text = format!("DB 0x{:02X}", byte);
+                    let detail = annotation(address, 1, bitmap(byte));
+                    writeln!(f, "    {:<24}; 0x{:03X}  {}", text, address, detail)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Shows the set bits of |byte| as '#' from the most significant bit.
+fn bitmap(byte: u8) -> String {
+    (0..8)
+        .map(|bit| if byte & (0x80 >> bit) != 0 { '#' } else { '.' })
+        .collect()
+}
+
+impl fmt::Display for Listing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_code_and_data() {
+        let rom = [
+            0x22, 0x0A, // CALL 0x20A
+            0xA2, 0x0E, // LD I, 0x20E
+            0x70, 0x12, // ADD V0, 0x12
+            0x12, 0x05, // JP 0x205 into the ADD where 12 12 overlaps it.
+            0x00, 0x00, // Never reached.
+            0x60, 0x01, // LD V0, 0x01
+            0x00, 0xEE, // RET
+            0x3C, 0x42, // A sprite.
+        ];
+        let listing = Listing::new(&rom, 0x200);
+        assert!(listing.is_code(0x206));
+        assert!(!listing.is_code(0x205));
+        assert!(!listing.is_code(0x208));
+        assert_eq!(listing.label(0x205), Some("label_205".to_string()));
+        assert_eq!(
+            listing.to_string(),
+            "\
+start:
+    CALL sub_20A            ; 0x200  22 0A
+    LD I, data_20E          ; 0x202  A2 0E
+    ADD V0, 0x12            ; 0x204  70 12
+    JP 0x205                ; 0x206  12 05
+    DB 0x00                 ; 0x208  ........
+    DB 0x00                 ; 0x209  ........
+sub_20A:
+    LD V0, 0x01             ; 0x20A  60 01
+    RET                     ; 0x20C  00 EE
+data_20E:
+    DB 0x3C                 ; 0x20E  ..####..
+    DB 0x42                 ; 0x20F  .#....#.
+"
+        );
+    }
+}
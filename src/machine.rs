@@ -0,0 +1,271 @@
+// Copyright of Jordan Werthman (2019).
+
+use crate::bus::Bus;
+use crate::cpu::{Cpu, CpuError, FrameReport, TickOutcome};
+use crate::memory::LoadError;
+use crate::platform::Platform;
+use crate::quirks::Quirks;
+use crate::savestate::StateError;
+use crate::timing::Timing;
+
+// Models a complete CHIP-8 system
+//
+// A Machine owns the CPU (along with its memory) and the bus so that every
+// frontend loads, resets and runs ROMs in the same way. How the machine runs
+// is set by its |config| which takes effect whenever a ROM is (re)loaded.
+// The CPU and bus remain available for tools which need to look inside.
+
+pub const DEFAULT_SPEED: usize = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Config {
+    pub platform: Platform,
+    pub quirks: Quirks,
+    pub timing: Timing,
+    // Instructions executed by each run_frame() without COSMAC VIP timing.
+    pub speed: usize,
+    // Seeds CXNN so that runs repeat (otherwise system entropy is used).
+    pub seed: Option<u64>,
+    // The number of instructions which can be undone (0 disables rewind).
+    pub rewind: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config::new(Platform::default())
+    }
+}
+
+impl Config {
+    // The configuration for running ROMs written for |platform| with its
+    // usual quirks.
+    pub fn new(platform: Platform) -> Config {
+        Config {
+            platform,
+            quirks: platform.default_quirks(),
+            timing: Timing::default(),
+            speed: DEFAULT_SPEED,
+            seed: None,
+            rewind: 0,
+        }
+    }
+}
+
+pub struct Machine {
+    config: Config,
+    rom: Vec<u8>,
+    cpu: Cpu,
+    bus: Bus,
+}
+
+impl Default for Machine {
+    fn default() -> Self {
+        Self::new(Config::default())
+    }
+}
+
+impl Machine {
+    // Creates a machine with an empty ROM (see load_rom()).
+    pub fn new(config: Config) -> Machine {
+        Machine {
+            config,
+            rom: Vec::new(),
+            cpu: Machine::create_cpu(&config, &[]).expect("An empty ROM always fits"),
+            bus: Bus::new(),
+        }
+    }
+
+    fn create_cpu(config: &Config, rom: &[u8]) -> Result<Cpu, LoadError> {
+        let mut cpu = Cpu::new(rom, config.platform, config.quirks)?;
+        cpu.set_timing(config.timing);
+        if let Some(seed) = config.seed {
+            cpu.seed_random(seed);
+        }
+        if config.rewind > 0 {
+            cpu.enable_rewind(config.rewind);
+        }
+        Ok(cpu)
+    }
+
+    pub fn config(&self) -> Config {
+        self.config
+    }
+
+    // Changes the configuration used from the next load_rom() or reset().
+    pub fn set_config(&mut self, config: Config) {
+        self.config = config;
+    }
+
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    // Loads |rom| and starts running it from the beginning. Fails (leaving
+    // the current ROM running) if |rom| does not fit in memory.
+    pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), LoadError> {
+        self.cpu = Machine::create_cpu(&self.config, rom)?;
+        self.rom = rom.to_vec();
+        self.bus.reset();
+        Ok(())
+    }
+
+    // Restarts the current ROM with the current configuration. Fails
+    // (leaving the ROM running as before) if the configuration's platform
+    // has too little memory for the ROM.
+    pub fn reset(&mut self) -> Result<(), LoadError> {
+        self.cpu = Machine::create_cpu(&self.config, &self.rom)?;
+        self.bus.reset();
+        Ok(())
+    }
+
+    // Executes a single instruction (see Cpu::tick()).
+    pub fn step(&mut self) -> Result<TickOutcome, CpuError> {
+        self.cpu.tick(&mut self.bus)
+    }
+
+    // Runs a 60Hz frame of instructions (see Cpu::run_frame()).
+    pub fn run_frame(&mut self) -> FrameReport {
+        self.cpu.run_frame(&mut self.bus, self.config.speed)
+    }
+
+    // Undoes the last instruction if rewind is enabled (see Config).
+    pub fn step_back(&mut self) -> bool {
+        self.cpu.step_back(&mut self.bus)
+    }
+
+    // Renders |duration| seconds of audio at |sample_rate| for an update in
+    // which frames are |running|. The sound timer only counts down as frames
+    // run so a machine which is paused, rewinding or stopped is silent
+    // rather than holding its tone.
+    pub fn audio_samples(&mut self, sample_rate: u32, duration: f64, running: bool) -> Vec<f32> {
+        if running {
+            self.bus.audio_samples(sample_rate, duration)
+        } else {
+            self.bus.silence(sample_rate, duration)
+        }
+    }
+
+    pub fn save_state(&self) -> Vec<u8> {
+        self.cpu.save_state(&self.bus)
+    }
+
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        self.cpu.load_state(&mut self.bus, state)
+    }
+
+    // Holds down the hexadecimal |key| on the keypad.
+    pub fn press_key(&mut self, key: u8) {
+        self.bus.set_key(key as usize);
+    }
+
+    pub fn release_key(&mut self, key: u8) {
+        self.bus.clear_key(key as usize);
+    }
+
+    pub fn key_pressed(&self, key: u8) -> bool {
+        self.bus.check_key(key)
+    }
+
+    // The display as rows of width() pixels each holding a colour index.
+    pub fn framebuffer(&self) -> &[u8] {
+        self.bus.display()
+    }
+
+    pub fn width(&self) -> usize {
+        self.bus.width()
+    }
+
+    pub fn height(&self) -> usize {
+        self.bus.height()
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+
+    pub fn bus(&self) -> &Bus {
+        &self.bus
+    }
+
+    pub fn bus_mut(&mut self) -> &mut Bus {
+        &mut self.bus
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    #[test]
+    fn reset_restarts_with_config() {
+        let rom = assemble("RND V0, 0xFF\nHIGH\nLD DT, V0\nloop: JP loop").unwrap();
+        let mut config = Config::new(Platform::SuperChip);
+        config.seed = Some(7);
+        config.rewind = 16;
+        let mut machine = Machine::new(config);
+        machine.load_rom(&rom).unwrap();
+
+        machine.run_frame();
+        let random = machine.cpu().registers()[0];
+        assert_eq!(machine.width(), 128);
+        assert!(machine.step_back());
+
+        machine.press_key(0x3);
+        machine.reset().unwrap();
+        assert_eq!(machine.cpu().pc(), 0x200);
+        assert_eq!(machine.width(), 64);
+        assert!(!machine.key_pressed(0x3));
+        assert_eq!(machine.bus().delay_timer(), 0);
+
+        machine.run_frame();
+        assert_eq!(machine.cpu().registers()[0], random);
+    }
+
+    #[test]
+    fn rejects_roms_larger_than_memory() {
+        let mut machine = Machine::new(Config::new(Platform::Chip8));
+        machine.load_rom(&[0x12, 0x00]).unwrap();
+        assert_eq!(
+            machine.load_rom(&[0; 0xE01]),
+            Err(LoadError::TooLarge {
+                size: 0xE01,
+                capacity: 0xE00
+            })
+        );
+        assert_eq!(machine.rom(), &[0x12, 0x00]);
+        machine.load_rom(&[0; 0xE00]).unwrap();
+
+        machine.set_config(Config::new(Platform::XoChip));
+        machine.load_rom(&[0; 0xFE00]).unwrap();
+        // The ROM no longer fits once the machine is reset as CHIP-8.
+        machine.set_config(Config::new(Platform::Chip8));
+        assert!(machine.reset().is_err());
+        assert_eq!(machine.cpu().memory().len(), 0x10000);
+    }
+
+    #[test]
+    fn paused_machine_is_silent() {
+        let rom = assemble("LD V0, 0x3C\nLD ST, V0\nloop: JP loop").unwrap();
+        let mut machine = Machine::new(Config::new(Platform::Chip8));
+        machine.load_rom(&rom).unwrap();
+        machine.run_frame();
+        assert!(machine.bus().sound_active());
+
+        let samples = machine.audio_samples(1000, 0.1, true);
+        assert!(samples.iter().any(|&sample| sample != 0.0));
+        for _ in 0..10 {
+            let samples = machine.audio_samples(1000, 0.1, false);
+            assert_eq!(samples.len(), 100);
+            assert!(samples.iter().all(|&sample| sample == 0.0));
+        }
+        // The tone resumes with the frames.
+        assert!(machine.bus().sound_active());
+        let samples = machine.audio_samples(1000, 0.1, true);
+        assert!(samples.iter().any(|&sample| sample != 0.0));
+    }
+}
@@ -1,29 +1,171 @@
 // Copyright of Jordan Werthman (2019).
 
-use env_logger;
 use log::*;
 
 use piston_window::*;
 
+use std::env;
+use std::fs;
 use std::fs::File;
 use std::io;
 use std::io::prelude::*;
+use std::process;
 
-use rust8::Bus;
-use rust8::Cpu;
+use rust8::Config;
+use rust8::CpuError;
+use rust8::Listing;
+use rust8::Machine;
+use rust8::Platform;
+use rust8::Quirks;
+use rust8::TickOutcome;
+use rust8::Timing;
+use rust8::DEFAULT_FREQUENCY;
+use rust8::DEFAULT_SPEED;
+use rust8::DEFAULT_VOLUME;
 
-use rust8::WINDOW_HEIGHT;
-use rust8::WINDOW_WIDTH;
-
-// CRT Monitor green:
-const PIXEL_COLOR: [f32; 4] = [0.0, 0.95, 0.0, 1.0];
+// Colours for each combination of the XO-CHIP bitplanes; the first plane
+// alone (all other platforms) is drawn in CRT Monitor green:
+const PALETTE: [[f32; 4]; 4] = [
+    [0.0, 0.0, 0.0, 1.0],
+    [0.0, 0.95, 0.0, 1.0],
+    [0.95, 0.6, 0.0, 1.0],
+    [0.95, 0.95, 0.85, 1.0],
+];
 const WINDOW_SIZE: [u32; 2] = [500, 250];
+const EXITED_TITLE: &str = "RUST-8 - Exited";
+const RECORD_SAMPLE_RATE: u32 = 44100;
+// The number of instructions which can be undone by holding Backspace.
+const REWIND_CAPACITY: usize = 10_000;
+// The ROM runs a frame of instructions on each update at 60Hz.
+const FRAME_RATE: u64 = 60;
+// Pressing H overlays a heatmap of the first 4K of memory, 128 bytes to a
+// row, with each byte shaded by how often it was executed.
+const HEATMAP_SIZE: usize = 0x1000;
+const HEATMAP_COLUMNS: usize = 128;
+// The addresses and subroutines logged when the heatmap is shown.
+const PROFILE_LIMIT: usize = 20;
+// Where ROMs are loaded in memory.
+const ROM_BASE: usize = 0x200;
+const USAGE: &str = "\
+Usage: rust-8 [--platform NAME] [--quirks NAME] [--tone HZ] [--volume 0-1]
+              [--record FILE.wav] [--seed N] [--timing unlimited|vip]
+              [--speed N] [--coverage FILE]
+The beep (--tone and --volume) is played by builds with --features audio
+and is otherwise only written to the --record file.";
+
+// Quick-save slots: F1-F4 save to slots 1-4 while F5-F8 load from them.
+enum SlotAction {
+    Save,
+    Load,
+}
+
+fn quick_slot(key: Key) -> Option<(SlotAction, usize)> {
+    match key {
+        Key::F1 => Some((SlotAction::Save, 1)),
+        Key::F2 => Some((SlotAction::Save, 2)),
+        Key::F3 => Some((SlotAction::Save, 3)),
+        Key::F4 => Some((SlotAction::Save, 4)),
+        Key::F5 => Some((SlotAction::Load, 1)),
+        Key::F6 => Some((SlotAction::Load, 2)),
+        Key::F7 => Some((SlotAction::Load, 3)),
+        Key::F8 => Some((SlotAction::Load, 4)),
+        _ => None,
+    }
+}
+
+struct Options {
+    config: Config,
+    tone: f64,
+    volume: f32,
+    record: Option<String>,
+    coverage: Option<String>,
+}
+
+// Parses the command line (see USAGE) exiting with the usage for bad
+// options. The platform is e.g. "chip8" or "schip" and quirks is a preset
+// e.g. "vip" (defaulting to the platform's usual quirks). A seed makes CXNN
+// repeat the same numbers. The speed is the instructions run each 60Hz
+// frame unless "vip" timing runs ROMs at the speed of the COSMAC VIP. The
+// coverage of the ROM is saved as an annotated listing on exit.
+fn parse_options() -> Options {
+    let mut platform = Platform::default();
+    let mut quirks = None;
+    let mut tone = DEFAULT_FREQUENCY;
+    let mut volume = DEFAULT_VOLUME;
+    let mut record = None;
+    let mut coverage = None;
+    let mut seed = None;
+    let mut timing = Timing::default();
+    let mut speed = DEFAULT_SPEED;
+
+    let mut args = env::args().skip(1);
+    while let Some(flag) = args.next() {
+        let value = args.next().unwrap_or_else(|| exit_with_usage());
+        match flag.as_str() {
+            "--platform" => {
+                platform = Platform::from_name(&value).unwrap_or_else(|| exit_with_usage())
+            }
+            "--quirks" => {
+                quirks = Some(Quirks::from_name(&value).unwrap_or_else(|| exit_with_usage()))
+            }
+            "--tone" => tone = value.parse().unwrap_or_else(|_| exit_with_usage()),
+            "--volume" => volume = value.parse().unwrap_or_else(|_| exit_with_usage()),
+            "--record" => record = Some(value),
+            "--coverage" => coverage = Some(value),
+            "--seed" => seed = Some(value.parse().unwrap_or_else(|_| exit_with_usage())),
+            "--timing" => timing = Timing::from_name(&value).unwrap_or_else(|| exit_with_usage()),
+            "--speed" => speed = value.parse().unwrap_or_else(|_| exit_with_usage()),
+            _ => exit_with_usage(),
+        }
+    }
+
+    Options {
+        config: Config {
+            platform,
+            quirks: quirks.unwrap_or_else(|| platform.default_quirks()),
+            timing,
+            speed,
+            seed,
+            rewind: REWIND_CAPACITY,
+        },
+        tone,
+        volume,
+        record,
+        coverage,
+    }
+}
+
+fn exit_with_usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
 
 fn main() {
     env_logger::init();
 
-    let mut bus: Bus = Bus::new();
-    let mut cpu: Option<Cpu> = None;
+    let Options {
+        config,
+        tone,
+        volume,
+        record,
+        coverage,
+    } = parse_options();
+
+    let mut machine = Machine::new(config);
+    machine.bus_mut().set_beeper(tone, volume);
+
+    #[cfg(feature = "audio")]
+    let speaker = speaker::Speaker::open();
+    #[cfg(feature = "audio")]
+    let sample_rate = speaker
+        .as_ref()
+        .map_or(RECORD_SAMPLE_RATE, |speaker| speaker.sample_rate());
+    #[cfg(not(feature = "audio"))]
+    let sample_rate = RECORD_SAMPLE_RATE;
+
+    if record.is_some() {
+        machine.bus_mut().start_recording(sample_rate);
+    }
 
     let opengl = OpenGL::V3_2;
     let mut window: PistonWindow = WindowSettings::new("RUST-8", WINDOW_SIZE)
@@ -31,6 +173,7 @@ fn main() {
         .opengl(opengl)
         .build()
         .unwrap();
+    window.set_ups(FRAME_RATE);
 
     let key_mapping = |ref piston_key| {
         use keyboard::Key;
@@ -57,21 +200,31 @@ fn main() {
     };
 
     let mut should_tick = false;
+    let mut rewinding = false;
+    let mut show_heatmap = false;
+    // The path of the ROM being run (nothing runs until one is dropped).
+    let mut rom_path = None;
     while let Some(event) = window.next() {
         window.draw_2d(&event, |ctx, gfx| {
             clear(color::BLACK, gfx);
 
-            let board = bus.display();
-            let dimen = ctx.get_view_size()[0] / WINDOW_WIDTH as f64;
+            let board = machine.framebuffer();
+            let (width, height) = (machine.width(), machine.height());
+            let dimen = ctx.get_view_size()[0] / width as f64;
 
-            for x in 0..WINDOW_WIDTH {
-                for y in 0..WINDOW_HEIGHT {
-                    if board[y * WINDOW_WIDTH + x] {
+            for x in 0..width {
+                for y in 0..height {
+                    let color = board[y * width + x] as usize;
+                    if color != 0 {
                         let location = rectangle::square(x as f64 * dimen, y as f64 * dimen, dimen);
-                        rectangle(PIXEL_COLOR, location, ctx.transform, gfx);
+                        rectangle(PALETTE[color], location, ctx.transform, gfx);
                     }
                 }
             }
+
+            if let Some(profile) = machine.cpu().profile().filter(|_| show_heatmap) {
+                draw_heatmap(profile.addresses(), ctx, gfx);
+            }
         });
 
         if let Event::Input(ref input) = event {
@@ -87,9 +240,32 @@ fn main() {
                     button: Button::Keyboard(Key::Return),
                     state: ButtonState::Press,
                     ..
+                }) if rom_path.is_some() => {
+                    report_outcome(&mut window, machine.step());
+                }
+                Input::Button(ButtonArgs {
+                    button: Button::Keyboard(Key::H),
+                    state: ButtonState::Press,
+                    ..
+                }) => {
+                    show_heatmap = !show_heatmap;
+                    // Profiling slows the CPU so it only starts once the
+                    // heatmap is first shown.
+                    if show_heatmap && machine.cpu().profile().is_none() {
+                        machine.cpu_mut().start_profile();
+                    }
+                    if let Some(profile) = machine.cpu().profile().filter(|_| show_heatmap) {
+                        info!("{}", profile.report(machine.cpu().memory(), PROFILE_LIMIT));
+                    }
+                }
+                Input::Button(ButtonArgs {
+                    button: Button::Keyboard(Key::Backspace),
+                    state,
+                    ..
                 }) => {
-                    if let Some(ref mut cpu) = cpu {
-                        cpu.tick(&mut bus);
+                    rewinding = *state == ButtonState::Press;
+                    if rewinding {
+                        window.set_title("RUST-8".to_string());
                     }
                 }
                 Input::Button(ButtonArgs {
@@ -97,15 +273,24 @@ fn main() {
                     state,
                     ..
                 }) => {
+                    if let (Some((action, slot)), ButtonState::Press) = (quick_slot(*key), state) {
+                        if let Some(ref path) = rom_path {
+                            let path = format!("{}.state{}", path, slot);
+                            match action {
+                                SlotAction::Save => save_state(&mut window, &machine, &path),
+                                SlotAction::Load => load_state(&mut window, &mut machine, &path),
+                            }
+                        }
+                    }
                     if let Some(keypad) = key_mapping(*key) {
                         match state {
                             ButtonState::Press => {
                                 info!("Keypad set {:?}", keypad);
-                                bus.set_key(keypad);
+                                machine.press_key(keypad);
                             }
                             ButtonState::Release => {
                                 info!("Keypad clear {:?}", keypad);
-                                bus.clear_key(keypad);
+                                machine.release_key(keypad);
                             }
                         }
                     }
@@ -114,8 +299,19 @@ fn main() {
                     let filename = path.to_str().expect("Invalid path");
                     let buffer = read_file(filename).expect("File not found.");
 
-                    cpu = Some(Cpu::new(&buffer));
-                    bus.reset();
+                    if let Err(error) = machine.load_rom(&buffer) {
+                        error!("Failed to load {}: {}", filename, error);
+                        window.set_title(format!("RUST-8 - {}", error));
+                        continue;
+                    }
+                    if show_heatmap {
+                        machine.cpu_mut().start_profile();
+                    }
+                    if coverage.is_some() {
+                        machine.cpu_mut().start_coverage();
+                    }
+                    rom_path = Some(filename.to_string());
+                    window.set_title("RUST-8".to_string());
 
                     should_tick = true;
                 }
@@ -124,18 +320,134 @@ fn main() {
         }
 
         if let Some(ref args) = event.update_args() {
-            bus.update_timers(args.dt);
-        }
+            let running = rom_path.is_some() && should_tick && !rewinding;
+            let samples = machine.audio_samples(sample_rate, args.dt, running);
+            #[cfg(feature = "audio")]
+            {
+                if let Some(ref speaker) = speaker {
+                    speaker.play(samples);
+                }
+            }
+            #[cfg(not(feature = "audio"))]
+            drop(samples);
 
-        if should_tick {
-            if let Some(ref mut cpu) = cpu {
-                cpu.tick(&mut bus);
+            if rom_path.is_some() {
+                if rewinding {
+                    for _ in 0..machine.config().speed {
+                        machine.step_back();
+                    }
+                } else if should_tick {
+                    should_tick = report_outcome(&mut window, machine.run_frame().outcome);
+                }
             }
         }
     }
+
+    if let Some(path) = record {
+        save_recording(&mut machine, &path);
+    }
+    if let Some(path) = coverage {
+        save_coverage(&machine, &path);
+    }
+}
+
+// Shades each address in |counts| (executions per address) which has been
+// executed from translucent red (rarely) to yellow (the most) on a log scale.
+fn draw_heatmap<G: Graphics>(counts: &[u64], ctx: Context, gfx: &mut G) {
+    let counts = &counts[..counts.len().min(HEATMAP_SIZE)];
+    let max = counts.iter().cloned().max().unwrap_or(0);
+    if max == 0 {
+        return;
+    }
+
+    let [width, height] = ctx.get_view_size();
+    let rows = HEATMAP_SIZE / HEATMAP_COLUMNS;
+    let (cell_width, cell_height) = (width / HEATMAP_COLUMNS as f64, height / rows as f64);
+    for (address, &count) in counts.iter().enumerate().filter(|&(_, &count)| count > 0) {
+        let heat = ((count as f64).ln_1p() / (max as f64).ln_1p()) as f32;
+        let x = (address % HEATMAP_COLUMNS) as f64 * cell_width;
+        let y = (address / HEATMAP_COLUMNS) as f64 * cell_height;
+        let color = [1.0, heat, 0.0, 0.3 + 0.6 * heat];
+        rectangle(color, [x, y, cell_width, cell_height], ctx.transform, gfx);
+    }
+}
+
+// Saves the audio recorded during the session (if requested with --record).
+fn save_recording(machine: &mut Machine, path: &str) {
+    if let Some(recording) = machine.bus_mut().take_recording() {
+        let result = File::create(path).and_then(|mut file| recording.write_wav(&mut file));
+        match result {
+            Ok(()) => info!("Saved audio to {}", path),
+            Err(error) => error!("Failed to save audio to {}: {}", path, error),
+        }
+    }
+}
+
+// Saves an annotated listing of the ROM showing how it was used during the
+// session (if requested with --coverage).
+fn save_coverage(machine: &Machine, path: &str) {
+    if let Some(coverage) = machine.cpu().coverage() {
+        let listing = Listing::new(machine.rom(), ROM_BASE).annotate(coverage);
+        match fs::write(path, listing) {
+            Ok(()) => info!("Saved coverage to {}", path),
+            Err(error) => error!("Failed to save coverage to {}: {}", path, error),
+        }
+    }
 }
 
-fn read_file(filename: &str) -> io::Result<(Vec<u8>)> {
+fn save_state(window: &mut PistonWindow, machine: &Machine, path: &str) {
+    match fs::write(path, machine.save_state()) {
+        Ok(()) => {
+            info!("Saved state to {}", path);
+            window.set_title(format!("RUST-8 - Saved {}", path));
+        }
+        Err(error) => error!("Failed to save state to {}: {}", path, error),
+    }
+}
+
+fn load_state(window: &mut PistonWindow, machine: &mut Machine, path: &str) {
+    let result = fs::read(path)
+        .map_err(|error| error.to_string())
+        .and_then(|state| {
+            machine
+                .load_state(&state)
+                .map_err(|error| error.to_string())
+        });
+    match result {
+        Ok(()) => {
+            info!("Loaded state from {}", path);
+            window.set_title(format!("RUST-8 - Loaded {}", path));
+        }
+        Err(error) => {
+            error!("Failed to load state from {}: {}", path, error);
+            window.set_title(format!("RUST-8 - {}", error));
+        }
+    }
+}
+
+// Shows how an instruction (or frame) ended in the title. Returns false if
+// the CPU has stopped.
+fn report_outcome(window: &mut PistonWindow, outcome: Result<TickOutcome, CpuError>) -> bool {
+    match outcome {
+        Ok(TickOutcome::Exited) => {
+            window.set_title(EXITED_TITLE.to_string());
+            false
+        }
+        Ok(_) => true,
+        Err(error) => {
+            show_error(window, error);
+            false
+        }
+    }
+}
+
+// Reports an error which stopped the CPU in the log and in the window title.
+fn show_error(window: &mut PistonWindow, error: CpuError) {
+    error!("CPU stopped: {}", error);
+    window.set_title(format!("RUST-8 - {}", error));
+}
+
+fn read_file(filename: &str) -> io::Result<Vec<u8>> {
     let mut file = File::open(filename)?;
 
     let mut buffer = Vec::new();
@@ -144,3 +456,111 @@ fn read_file(filename: &str) -> io::Result<(Vec<u8>)> {
 
     Ok(buffer)
 }
+
+#[cfg(feature = "audio")]
+mod speaker {
+    use log::*;
+
+    use std::collections::VecDeque;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+    use std::thread;
+
+    // Limits the audio buffered ahead of the device (in seconds) so that the
+    // sound does not lag behind the game when the device falls behind.
+    const MAX_LATENCY: f64 = 0.1;
+
+    // Plays the mono PCM samples rendered by the bus on the default output
+    // device. Samples are queued by the main thread and consumed by cpal's
+    // event loop running on its own thread.
+    pub struct Speaker {
+        queue: Arc<Mutex<VecDeque<f32>>>,
+        sample_rate: u32,
+    }
+
+    impl Speaker {
+        pub fn open() -> Option<Speaker> {
+            let device = cpal::default_output_device()?;
+            let format = match device.default_output_format() {
+                Ok(format) => format,
+                Err(error) => {
+                    warn!("No audio output format: {:?}", error);
+                    return None;
+                }
+            };
+
+            let event_loop = cpal::EventLoop::new();
+            let stream_id = match event_loop.build_output_stream(&device, &format) {
+                Ok(stream_id) => stream_id,
+                Err(error) => {
+                    warn!("Failed to open audio output: {:?}", error);
+                    return None;
+                }
+            };
+            event_loop.play_stream(stream_id);
+
+            let queue = Arc::new(Mutex::new(VecDeque::new()));
+            let channels = format.channels as usize;
+            let stream_queue = queue.clone();
+            thread::spawn(move || {
+                event_loop.run(move |_, data| {
+                    let mut queue = stream_queue.lock().unwrap();
+                    let mut next_sample = || queue.pop_front().unwrap_or(0.0);
+
+                    match data {
+                        cpal::StreamData::Output {
+                            buffer: cpal::UnknownTypeOutputBuffer::F32(mut buffer),
+                        } => {
+                            for frame in buffer.chunks_mut(channels) {
+                                let sample = next_sample();
+                                for out in frame.iter_mut() {
+                                    *out = sample;
+                                }
+                            }
+                        }
+                        cpal::StreamData::Output {
+                            buffer: cpal::UnknownTypeOutputBuffer::I16(mut buffer),
+                        } => {
+                            for frame in buffer.chunks_mut(channels) {
+                                let sample = (next_sample() * i16::MAX as f32) as i16;
+                                for out in frame.iter_mut() {
+                                    *out = sample;
+                                }
+                            }
+                        }
+                        cpal::StreamData::Output {
+                            buffer: cpal::UnknownTypeOutputBuffer::U16(mut buffer),
+                        } => {
+                            for frame in buffer.chunks_mut(channels) {
+                                let sample = ((next_sample() * 0.5 + 0.5) * u16::MAX as f32) as u16;
+                                for out in frame.iter_mut() {
+                                    *out = sample;
+                                }
+                            }
+                        }
+                        _ => (),
+                    }
+                });
+            });
+
+            Some(Speaker {
+                queue,
+                sample_rate: format.sample_rate.0,
+            })
+        }
+
+        pub fn sample_rate(&self) -> u32 {
+            self.sample_rate
+        }
+
+        pub fn play(&self, samples: Vec<f32>) {
+            let mut queue = self.queue.lock().unwrap();
+            queue.extend(samples);
+
+            let max_len = (MAX_LATENCY * self.sample_rate as f64) as usize;
+            while queue.len() > max_len {
+                queue.pop_front();
+            }
+        }
+    }
+}
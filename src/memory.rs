@@ -2,13 +2,21 @@
 
 // Models the RAM used by the system.
 //
-// The memory is 4096 bytes with the first 512 bytes reserved for system use
-// and the rest used for user memory and is loaded with the given ROM. In the
-// reserved space [0x0-0x50) is used for the character graphics (numbers 0-F
-// in hexidecimal) and [0x50-0x70) contains the call stack (saving return
-// addressed) saving 16 16-byte addresses.
+// The memory is 4096 bytes (or 64K for XO-CHIP) with the first 512 bytes
+// reserved for system use and the rest used for user memory and is loaded
+// with the given ROM. In the reserved space [0x0-0x50) is used for the
+// character graphics (numbers 0-F in hexidecimal), [0x50-0x70) contains the
+// call stack (saving return addressed) saving 16 16-byte addresses and
+// [0x70-0x110) contains the SUPER-CHIP 8x10 character graphics (numbers 0-F
+// in hexidecimal).
 
+use crate::instruction::decode;
+use crate::savestate::{Reader, StateError, Writer};
 use log::*;
+use std::error::Error;
+use std::fmt;
+
+use std::ops::Range;
 
 const FONT_OFFSET: usize = 0x0;
 
@@ -32,22 +40,51 @@ const FONT: [u8; 80] = [
 ];
 
 const STACK_OFFSET: usize = 0x50;
+const STACK_END: usize = 0x70;
+
+pub const BIG_FONT_OFFSET: usize = 0x70;
+
+const BIG_FONT: [u8; 160] = [
+    0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, // 0
+    0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF, // 1
+    0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // 2
+    0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 3
+    0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0x03, 0x03, // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 5
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 6
+    0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18, // 7
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 8
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 9
+    0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+    0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+    0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+    0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0, // F
+];
 
 pub const USER_OFFSET: usize = 0x200;
 
-fn print_memory_map(memory: &[u8; 4096]) {
+fn print_memory_map(memory: &[u8]) {
     for pc in 0..2048 {
         if pc == FONT_OFFSET {
-            info!("System Memory:");
-            info!("Font");
+            debug!("System Memory:");
+            debug!("Font");
         } else if pc == STACK_OFFSET {
-            info!("Stack");
+            debug!("Stack");
+        } else if pc == BIG_FONT_OFFSET {
+            debug!("Big Font");
         } else if pc == USER_OFFSET {
-            info!("User Memory:");
+            debug!("User Memory:");
         }
 
         let instruction = (memory[pc] as u16) << 8 | memory[pc + 1] as u16;
-        info!("Location: {} Instruction: 0x{:04X}", pc, instruction);
+        debug!(
+            "Location: {} Instruction: 0x{:04X} {}",
+            pc,
+            instruction,
+            decode(instruction)
+        );
 
         if pc > USER_OFFSET && instruction == 0x0000 {
             break;
@@ -56,48 +93,199 @@ fn print_memory_map(memory: &[u8; 4096]) {
 }
 
 pub struct Memory {
-    memory: [u8; 4096],
+    memory: Vec<u8>,
     stack_pointer: usize,
+    // Address ranges where writes are reported by take_watch_hit().
+    watchpoints: Vec<Range<usize>>,
+    watch_hit: Option<usize>,
+    // The address and previous value of each write while journaling.
+    journal: Option<Vec<(usize, u8)>>,
 }
 
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LoadError {
+    // The ROM of |size| bytes is larger than the |capacity| of memory from
+    // USER_OFFSET.
+    TooLarge { size: usize, capacity: usize },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::TooLarge { size, capacity } => write!(
+                f,
+                "ROM is {} bytes but at most {} fit in memory",
+                size, capacity
+            ),
+        }
+    }
+}
+
+impl Error for LoadError {}
+
 impl Memory {
-    pub fn new(rom: &Vec<u8>) -> Memory {
-        let mut memory = [0; 4096];
+    // Creates |size| bytes of memory holding the fonts and |rom|. Fails if
+    // |rom| does not fit.
+    pub fn new(rom: &[u8], size: usize) -> Result<Memory, LoadError> {
+        let capacity = size.saturating_sub(USER_OFFSET);
+        if rom.len() > capacity {
+            return Err(LoadError::TooLarge {
+                size: rom.len(),
+                capacity,
+            });
+        }
+
+        let mut memory = vec![0; size];
         for (i, byte) in FONT.iter().enumerate() {
             memory[FONT_OFFSET + i] = *byte;
         }
 
+        for (i, byte) in BIG_FONT.iter().enumerate() {
+            memory[BIG_FONT_OFFSET + i] = *byte;
+        }
+
         for (i, byte) in rom.iter().enumerate() {
             memory[USER_OFFSET + i] = *byte;
         }
 
         print_memory_map(&memory);
 
-        Memory {
-            memory: memory,
+        Ok(Memory {
+            memory,
             stack_pointer: STACK_OFFSET,
-        }
+            watchpoints: Vec::new(),
+            watch_hit: None,
+            journal: None,
+        })
     }
 
-    pub fn push_stack(&mut self, address: usize) {
-        self.memory[self.stack_pointer] = ((address >> 8) & 0xFF) as u8;
-        self.memory[self.stack_pointer + 1] = (address & 0xFF) as u8;
+    // Returns None if the stack is already full.
+    pub fn push_stack(&mut self, address: usize) -> Option<()> {
+        if self.stack_pointer >= STACK_END {
+            return None;
+        }
+        self.write(self.stack_pointer, ((address >> 8) & 0xFF) as u8);
+        self.write(self.stack_pointer + 1, (address & 0xFF) as u8);
         self.stack_pointer += 2;
+        Some(())
     }
 
-    pub fn pop_stack(&mut self) -> usize {
+    // Returns None if the stack is empty.
+    pub fn pop_stack(&mut self) -> Option<usize> {
+        if self.stack_pointer <= STACK_OFFSET {
+            return None;
+        }
         self.stack_pointer -= 2;
         let high_byte: usize = self.memory[self.stack_pointer] as usize;
         let low_byte: usize = self.memory[self.stack_pointer + 1] as usize;
 
-        (high_byte << 8) | low_byte
+        Some((high_byte << 8) | low_byte)
+    }
+
+    // Returns None if |pc| does not point at a whole instruction in memory.
+    pub fn read_instruction(&self, pc: usize) -> Option<u16> {
+        let low = pc.checked_add(1).filter(|&low| low < self.memory.len())?;
+        Some((self.memory[pc] as u16) << 8 | self.memory[low] as u16)
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.memory
+    }
+
+    // The return addresses on the stack from the outermost call.
+    pub fn stack(&self) -> Vec<usize> {
+        self.memory[STACK_OFFSET..self.stack_pointer]
+            .chunks(2)
+            .map(|pair| (pair[0] as usize) << 8 | pair[1] as usize)
+            .collect()
+    }
+
+    // Panics if |address| is outside of memory; callers are expected to
+    // check the range of an access first.
+    pub fn read(&self, address: usize) -> u8 {
+        self.memory[address]
+    }
+
+    // Stores |value| at |address| recording the first write to a watched
+    // address (see take_watch_hit()).
+    pub fn write(&mut self, address: usize, value: u8) {
+        if let Some(ref mut journal) = self.journal {
+            journal.push((address, self.memory[address]));
+        }
+        self.memory[address] = value;
+        if self.watch_hit.is_none()
+            && self
+                .watchpoints
+                .iter()
+                .any(|range| range.contains(&address))
+        {
+            self.watch_hit = Some(address);
+        }
+    }
+
+    // Starts recording the previous value of every byte written.
+    pub fn start_journal(&mut self) {
+        self.journal = Some(Vec::new());
+    }
+
+    // Stops journaling returning the writes since start_journal().
+    pub fn take_journal(&mut self) -> Vec<(usize, u8)> {
+        self.journal.take().unwrap_or_default()
+    }
+
+    // Reverts the writes recorded in |journal| (newest last).
+    pub fn undo(&mut self, journal: &[(usize, u8)]) {
+        for &(address, value) in journal.iter().rev() {
+            self.memory[address] = value;
+        }
+    }
+
+    pub fn stack_pointer(&self) -> usize {
+        self.stack_pointer
+    }
+
+    pub fn set_stack_pointer(&mut self, stack_pointer: usize) {
+        self.stack_pointer = stack_pointer;
+    }
+
+    // Saves the stack pointer and contents of memory (including the stack).
+    pub fn write_state(&self, writer: &mut Writer) {
+        writer.u16(self.stack_pointer as u16);
+        writer.bytes(&self.memory);
+    }
+
+    pub fn read_state(&mut self, reader: &mut Reader) -> Result<(), StateError> {
+        let stack_pointer = reader.u16()? as usize;
+        if !(STACK_OFFSET..=STACK_END).contains(&stack_pointer) || !stack_pointer.is_multiple_of(2)
+        {
+            return Err(StateError::Invalid("stack pointer"));
+        }
+        let memory = reader.bytes()?;
+        if memory.len() != self.memory.len() {
+            return Err(StateError::Invalid("memory size"));
+        }
+        self.stack_pointer = stack_pointer;
+        self.memory.copy_from_slice(memory);
+        Ok(())
+    }
+
+    pub fn watchpoints(&self) -> &[Range<usize>] {
+        &self.watchpoints
+    }
+
+    pub fn add_watchpoint(&mut self, range: Range<usize>) {
+        self.watchpoints.push(range);
     }
 
-    pub fn read_instruction(&self, pc: usize) -> u16 {
-        (self.memory[pc] as u16) << 8 | self.memory[pc + 1] as u16
+    // Returns false if |range| was not being watched.
+    pub fn remove_watchpoint(&mut self, range: &Range<usize>) -> bool {
+        let count = self.watchpoints.len();
+        self.watchpoints.retain(|watched| watched != range);
+        self.watchpoints.len() != count
     }
 
-    pub fn data(&mut self) -> &mut [u8; 4096] {
-        &mut self.memory
+    // Returns the first watched address written since the last call.
+    pub fn take_watch_hit(&mut self) -> Option<usize> {
+        self.watch_hit.take()
     }
 }
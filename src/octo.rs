@@ -0,0 +1,1181 @@
+// Copyright of Jordan Werthman (2019).
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+
+use crate::assembler::AssemblerError;
+use crate::memory::USER_OFFSET;
+use crate::platform::Platform;
+
+// Models a compiler for the Octo assembly language
+//
+// Octo (see: https://github.com/JohnEarnest/Octo/blob/gh-pages/docs/Manual.md)
+// is the language most modern CHIP-8 programs are written in e.g.
+//
+//   :alias counter v2
+//   : main
+//     counter := 0
+//     loop
+//       counter += 1
+//       if counter == 10 then jump done
+//     again
+//   : done
+//     i := box
+//     sprite v0 v1 4
+//   : box 0x3C 0x42 0x42 0x3C
+//
+// Tokens are separated by whitespace and '#' starts a comment. Labels (':'),
+// ':const', ':alias', ':calc' (evaluated right to left without operator
+// precedence as in Octo), ':macro', ':org', ':byte', ':pointer', structured
+// 'if ... then', 'if ... begin ... else ... end' and 'loop ... while ...
+// again' are supported. Instructions from the SUPER-CHIP and XO-CHIP
+// extensions are only accepted when compiling for that platform.
+//
+// Execution starts at 0x200 so a jump to 'main' is added unless the program
+// begins with it. Each address which starts an instruction is mapped back to
+// the line it was compiled from.
+
+const MAX_MACRO_DEPTH: usize = 64;
+
+pub struct Program {
+    pub rom: Vec<u8>,
+    // The line (1-based) that the instruction at each address came from.
+    pub source_map: BTreeMap<usize, usize>,
+}
+
+impl Program {
+    // The source line of the instruction at |address| if there is one.
+    pub fn line(&self, address: usize) -> Option<usize> {
+        self.source_map.get(&address).cloned()
+    }
+}
+
+// Compiles Octo |source| into a ROM for |platform|. Errors are reported
+// against the file "<source>".
+pub fn compile(source: &str, platform: Platform) -> Result<Program, AssemblerError> {
+    let mut compiler = Compiler::new(tokenize(source), platform);
+    compiler.compile(false)?;
+
+    // Whether 'main' is first can't be known until the program (including
+    // any macros) has been compiled so compile again with a jump if not.
+    if compiler
+        .labels
+        .get("main")
+        .is_some_and(|&main| main != USER_OFFSET)
+    {
+        compiler = Compiler::new(tokenize(source), platform);
+        compiler.compile(true)?;
+    }
+    compiler.finish()
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    text: String,
+    line: usize,
+    column: usize,
+    // The number of macro expansions this token came from.
+    depth: usize,
+}
+
+impl Token {
+    fn error<S: Into<String>>(&self, message: S) -> AssemblerError {
+        AssemblerError {
+            file: String::from("<source>"),
+            line: self.line,
+            column: self.column,
+            message: message.into(),
+        }
+    }
+}
+
+fn tokenize(source: &str) -> VecDeque<Token> {
+    let mut tokens = VecDeque::new();
+    for (index, line) in source.lines().enumerate() {
+        let line_text = line.split('#').next().unwrap_or("");
+        let mut chars = line_text.char_indices().peekable();
+        while let Some((start, c)) = chars.next() {
+            if c.is_whitespace() {
+                continue;
+            }
+            let mut end = start + c.len_utf8();
+            while let Some(&(next, c)) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                end = next + c.len_utf8();
+                chars.next();
+            }
+            tokens.push_back(Token {
+                text: line_text[start..end].to_string(),
+                line: index + 1,
+                column: line_text[..start].chars().count() + 1,
+                depth: 0,
+            });
+        }
+    }
+    tokens
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()?
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value })
+}
+
+fn parse_register(text: &str) -> Option<usize> {
+    match text.as_bytes() {
+        [b'v', digit] | [b'V', digit] => (*digit as char).to_digit(16).map(|x| x as usize),
+        _ => None,
+    }
+}
+
+struct Macro {
+    arguments: Vec<String>,
+    body: Vec<Token>,
+    calls: usize,
+}
+
+// A reference to a label which had not been defined when it was used.
+enum FixupKind {
+    // The low 12 bits of the opcode at the address.
+    Address,
+    // The 16-bit word at the address.
+    Long,
+}
+
+struct Fixup {
+    address: usize,
+    kind: FixupKind,
+    token: Token,
+}
+
+// The right hand side of a comparison in a condition.
+enum Operand {
+    Register(usize),
+    Byte(u8),
+}
+
+enum Condition {
+    Equal(usize, Operand),
+    NotEqual(usize, Operand),
+    Key(usize),
+    NotKey(usize),
+    // A comparison made by subtracting into VF leaving the (no) borrow flag
+    // which must equal the given value for the condition to hold.
+    Flag(u8),
+}
+
+struct Loop {
+    start: usize,
+    // Addresses of the jumps out of the loop made by 'while'.
+    exits: Vec<usize>,
+}
+
+struct Compiler {
+    tokens: VecDeque<Token>,
+    platform: Platform,
+    // Memory from 0x200 up to the highest address written.
+    rom: Vec<u8>,
+    here: usize,
+    labels: HashMap<String, usize>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, usize>,
+    macros: HashMap<String, Macro>,
+    fixups: Vec<Fixup>,
+    loops: Vec<Loop>,
+    // Addresses of the jumps to patch at the next 'else' or 'end'.
+    branches: Vec<usize>,
+    source_map: BTreeMap<usize, usize>,
+}
+
+impl Compiler {
+    fn new(tokens: VecDeque<Token>, platform: Platform) -> Compiler {
+        Compiler {
+            tokens,
+            platform,
+            rom: Vec::new(),
+            here: USER_OFFSET,
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            fixups: Vec::new(),
+            loops: Vec::new(),
+            branches: Vec::new(),
+            source_map: BTreeMap::new(),
+        }
+    }
+
+    // Compiles the program with a jump to 'main' first if |jump_to_main|.
+    fn compile(&mut self, jump_to_main: bool) -> Result<(), AssemblerError> {
+        if jump_to_main {
+            let token = self.tokens[0].clone();
+            self.fixups.push(Fixup {
+                address: self.here,
+                kind: FixupKind::Address,
+                token: Token {
+                    text: String::from("main"),
+                    ..token
+                },
+            });
+            self.emit_word(0x1000);
+        }
+
+        while let Some(token) = self.tokens.pop_front() {
+            let start = self.here;
+            let line = token.line;
+            self.statement(token)?;
+            if self.here > start {
+                self.source_map.entry(start).or_insert(line);
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<Program, AssemblerError> {
+        if let Some(&address) = self.branches.last() {
+            return Err(self.unterminated(address, "'if ... begin' without 'end'"));
+        }
+        if let Some(start) = self.loops.last().map(|l| l.start) {
+            return Err(self.unterminated(start, "'loop' without 'again'"));
+        }
+
+        for fixup in std::mem::take(&mut self.fixups) {
+            let value = *self.labels.get(&fixup.token.text).ok_or_else(|| {
+                fixup
+                    .token
+                    .error(format!("undefined name '{}'", fixup.token.text))
+            })?;
+            let offset = fixup.address - USER_OFFSET;
+            match fixup.kind {
+                FixupKind::Address => {
+                    if value > 0xFFF {
+                        return Err(fixup
+                            .token
+                            .error(format!("0x{:X} is not a 12-bit address", value)));
+                    }
+                    self.rom[offset] |= (value >> 8) as u8;
+                    self.rom[offset + 1] = value as u8;
+                }
+                FixupKind::Long => {
+                    self.rom[offset] = (value >> 8) as u8;
+                    self.rom[offset + 1] = value as u8;
+                }
+            }
+        }
+
+        Ok(Program {
+            rom: self.rom,
+            source_map: self.source_map,
+        })
+    }
+
+    fn unterminated(&self, address: usize, message: &str) -> AssemblerError {
+        let line = self
+            .source_map
+            .range(..=address)
+            .next_back()
+            .map_or(0, |(_, &line)| line);
+        AssemblerError {
+            file: String::from("<source>"),
+            line,
+            column: 1,
+            message: message.to_string(),
+        }
+    }
+
+    fn next(&mut self, after: &Token) -> Result<Token, AssemblerError> {
+        self.tokens
+            .pop_front()
+            .ok_or_else(|| after.error(format!("expected a token after '{}'", after.text)))
+    }
+
+    fn expect(&mut self, after: &Token, text: &str) -> Result<Token, AssemblerError> {
+        let token = self.next(after)?;
+        if token.text != text {
+            return Err(token.error(format!("expected '{}' but found '{}'", text, token.text)));
+        }
+        Ok(token)
+    }
+
+    // Takes the tokens between a '{' (which must come next) and its '}'.
+    fn braces(&mut self, after: &Token) -> Result<Vec<Token>, AssemblerError> {
+        self.expect(after, "{")?;
+        let mut body = Vec::new();
+        let mut depth = 0;
+        loop {
+            let token = self.next(after)?;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" if depth == 0 => return Ok(body),
+                "}" => depth -= 1,
+                _ => {}
+            }
+            body.push(token);
+        }
+    }
+
+    fn require(
+        &self,
+        token: &Token,
+        supported: bool,
+        platform: &str,
+    ) -> Result<(), AssemblerError> {
+        if !supported {
+            return Err(token.error(format!("'{}' requires {}", token.text, platform)));
+        }
+        Ok(())
+    }
+
+    fn require_superchip(&self, token: &Token) -> Result<(), AssemblerError> {
+        self.require(token, self.platform.has_superchip(), "SUPER-CHIP")
+    }
+
+    fn require_xochip(&self, token: &Token) -> Result<(), AssemblerError> {
+        self.require(token, self.platform.has_xochip(), "XO-CHIP")
+    }
+
+    fn emit_byte(&mut self, byte: u8) {
+        let offset = self.here - USER_OFFSET;
+        if self.rom.len() <= offset {
+            self.rom.resize(offset + 1, 0);
+        }
+        self.rom[offset] = byte;
+        self.here += 1;
+    }
+
+    fn emit_word(&mut self, word: u16) {
+        self.emit_byte((word >> 8) as u8);
+        self.emit_byte(word as u8);
+    }
+
+    // Emits |opcode| with NNN taken from the address named by |token| which
+    // may be patched once the label is defined.
+    fn emit_address(&mut self, opcode: u16, token: Token) -> Result<(), AssemblerError> {
+        match self.lookup(&token) {
+            Some(value) => {
+                if !(0.0..=4095.0).contains(&value) {
+                    return Err(token.error(format!("{} is not a 12-bit address", value)));
+                }
+                self.emit_word(opcode | value as u16);
+            }
+            None => {
+                self.check_name(&token)?;
+                self.fixups.push(Fixup {
+                    address: self.here,
+                    kind: FixupKind::Address,
+                    token,
+                });
+                self.emit_word(opcode);
+            }
+        }
+        Ok(())
+    }
+
+    fn emit_long(&mut self, token: Token) -> Result<(), AssemblerError> {
+        match self.lookup(&token) {
+            Some(value) => {
+                if !(0.0..=65535.0).contains(&value) {
+                    return Err(token.error(format!("{} is not a 16-bit address", value)));
+                }
+                self.emit_word(value as u16);
+            }
+            None => {
+                self.check_name(&token)?;
+                self.fixups.push(Fixup {
+                    address: self.here,
+                    kind: FixupKind::Long,
+                    token,
+                });
+                self.emit_word(0);
+            }
+        }
+        Ok(())
+    }
+
+    // Replaces the address of the jump at |address| with the current address.
+    fn patch_jump(&mut self, address: usize) {
+        let offset = address - USER_OFFSET;
+        self.rom[offset] = 0x10 | (self.here >> 8) as u8;
+        self.rom[offset + 1] = self.here as u8;
+    }
+
+    // The value of a number, constant or (already defined) label.
+    fn lookup(&self, token: &Token) -> Option<f64> {
+        if let Some(value) = parse_number(&token.text) {
+            return Some(value as f64);
+        }
+        if let Some(&value) = self.constants.get(&token.text) {
+            return Some(value);
+        }
+        self.labels.get(&token.text).map(|&address| address as f64)
+    }
+
+    fn value(&self, token: &Token) -> Result<f64, AssemblerError> {
+        self.lookup(token)
+            .ok_or_else(|| token.error(format!("undefined name '{}'", token.text)))
+    }
+
+    fn byte(&self, token: &Token) -> Result<u8, AssemblerError> {
+        let value = self.value(token)?;
+        if !(-128.0..=255.0).contains(&value) {
+            return Err(token.error(format!("{} does not fit in a byte", value)));
+        }
+        Ok(value as i64 as u8)
+    }
+
+    fn nibble(&self, token: &Token) -> Result<u16, AssemblerError> {
+        let value = self.value(token)?;
+        if !(0.0..=15.0).contains(&value) {
+            return Err(token.error(format!("{} does not fit in a nibble", value)));
+        }
+        Ok(value as u16)
+    }
+
+    fn register(&self, token: &Token) -> Option<usize> {
+        parse_register(&token.text).or_else(|| self.aliases.get(&token.text).cloned())
+    }
+
+    fn expect_register(&mut self, after: &Token) -> Result<usize, AssemblerError> {
+        let token = self.next(after)?;
+        self.register(&token)
+            .ok_or_else(|| token.error(format!("expected a register but found '{}'", token.text)))
+    }
+
+    // Checks that |token| can be used as the name of a label or constant.
+    fn check_name(&self, token: &Token) -> Result<(), AssemblerError> {
+        let reserved = parse_register(&token.text).is_some()
+            || parse_number(&token.text).is_some()
+            || RESERVED.contains(&token.text.as_str())
+            || token.text.starts_with(':');
+        if reserved {
+            return Err(token.error(format!("'{}' can't be used as a name", token.text)));
+        }
+        Ok(())
+    }
+
+    fn define_label(&mut self, token: &Token) -> Result<(), AssemblerError> {
+        self.check_name(token)?;
+        if self.labels.insert(token.text.clone(), self.here).is_some() {
+            return Err(token.error(format!("'{}' is already defined", token.text)));
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, token: Token) -> Result<(), AssemblerError> {
+        if let Some(x) = self.register(&token) {
+            return self.register_statement(x, token);
+        }
+
+        match token.text.as_str() {
+            ":" => {
+                let name = self.next(&token)?;
+                self.define_label(&name)?;
+            }
+            ":const" => {
+                let name = self.next(&token)?;
+                let value = self.next(&token)?;
+                self.check_name(&name)?;
+                let value = self.value(&value)?;
+                self.constants.insert(name.text, value);
+            }
+            ":alias" => {
+                let name = self.next(&token)?;
+                self.check_name(&name)?;
+                let x = self.expect_register(&name)?;
+                self.aliases.insert(name.text, x);
+            }
+            ":calc" => {
+                let name = self.next(&token)?;
+                self.check_name(&name)?;
+                let expression = self.braces(&name)?;
+                let value = self.calculate(&name, &expression)?;
+                self.constants.insert(name.text, value);
+            }
+            ":macro" => {
+                let name = self.next(&token)?;
+                self.check_name(&name)?;
+                let mut arguments = Vec::new();
+                while self.tokens.front().is_some_and(|next| next.text != "{") {
+                    arguments.push(self.next(&name)?.text);
+                }
+                let body = self.braces(&name)?;
+                self.macros.insert(
+                    name.text,
+                    Macro {
+                        arguments,
+                        body,
+                        calls: 0,
+                    },
+                );
+            }
+            ":org" => {
+                let address = self.next(&token)?;
+                let value = self.value(&address)?;
+                if value < USER_OFFSET as f64 || value > 0xFFFF as f64 {
+                    return Err(address.error(format!("can't compile to 0x{:X}", value as i64)));
+                }
+                self.here = value as usize;
+            }
+            ":byte" => {
+                let value = if self.tokens.front().is_some_and(|next| next.text == "{") {
+                    let expression = self.braces(&token)?;
+                    self.calculate(&token, &expression)? as i64 as u8
+                } else {
+                    let value = self.next(&token)?;
+                    self.byte(&value)?
+                };
+                self.emit_byte(value);
+            }
+            ":pointer" => {
+                let address = self.next(&token)?;
+                self.emit_long(address)?;
+            }
+            ":call" => {
+                let address = self.next(&token)?;
+                self.emit_address(0x2000, address)?;
+            }
+            ":breakpoint" => {
+                self.next(&token)?;
+            }
+            ":monitor" => {
+                self.next(&token)?;
+                self.next(&token)?;
+            }
+            "return" | ";" => self.emit_word(0x00EE),
+            "clear" => self.emit_word(0x00E0),
+            "bcd" => {
+                let x = self.expect_register(&token)?;
+                self.emit_word(0xF033 | (x as u16) << 8);
+            }
+            "save" | "load" => {
+                let x = self.expect_register(&token)?;
+                let (single, range) = if token.text == "save" {
+                    (0xF055, 0x5002)
+                } else {
+                    (0xF065, 0x5003)
+                };
+                if self.tokens.front().is_some_and(|next| next.text == "-") {
+                    self.require_xochip(&token)?;
+                    let dash = self.next(&token)?;
+                    let y = self.expect_register(&dash)?;
+                    self.emit_word(range | (x as u16) << 8 | (y as u16) << 4);
+                } else {
+                    self.emit_word(single | (x as u16) << 8);
+                }
+            }
+            "sprite" => {
+                let x = self.expect_register(&token)?;
+                let y = self.expect_register(&token)?;
+                let height = self.next(&token)?;
+                let n = self.nibble(&height)?;
+                if n == 0 {
+                    self.require(&height, self.platform.has_superchip(), "SUPER-CHIP")?;
+                }
+                self.emit_word(0xD000 | (x as u16) << 8 | (y as u16) << 4 | n);
+            }
+            "jump" => {
+                let address = self.next(&token)?;
+                self.emit_address(0x1000, address)?;
+            }
+            "jump0" => {
+                let address = self.next(&token)?;
+                self.emit_address(0xB000, address)?;
+            }
+            "native" => {
+                let address = self.next(&token)?;
+                self.emit_address(0x0000, address)?;
+            }
+            "hires" | "lores" | "scroll-left" | "scroll-right" | "exit" => {
+                self.require_superchip(&token)?;
+                self.emit_word(match token.text.as_str() {
+                    "hires" => 0x00FF,
+                    "lores" => 0x00FE,
+                    "scroll-left" => 0x00FC,
+                    "scroll-right" => 0x00FB,
+                    _ => 0x00FD,
+                });
+            }
+            "scroll-down" => {
+                self.require_superchip(&token)?;
+                let n = self.next(&token)?;
+                let n = self.nibble(&n)?;
+                self.emit_word(0x00C0 | n);
+            }
+            "scroll-up" => {
+                self.require_xochip(&token)?;
+                let n = self.next(&token)?;
+                let n = self.nibble(&n)?;
+                self.emit_word(0x00D0 | n);
+            }
+            "saveflags" | "loadflags" => {
+                self.require_superchip(&token)?;
+                let x = self.expect_register(&token)? as u16;
+                let opcode = if token.text == "saveflags" {
+                    0xF075
+                } else {
+                    0xF085
+                };
+                self.emit_word(opcode | x << 8);
+            }
+            "plane" => {
+                self.require_xochip(&token)?;
+                let n = self.next(&token)?;
+                let n = self.nibble(&n)?;
+                self.emit_word(0xF001 | n << 8);
+            }
+            "audio" => {
+                self.require_xochip(&token)?;
+                self.emit_word(0xF002);
+            }
+            "delay" | "buzzer" | "pitch" => {
+                if token.text == "pitch" {
+                    self.require_xochip(&token)?;
+                }
+                self.expect(&token, ":=")?;
+                let x = self.expect_register(&token)? as u16;
+                self.emit_word(
+                    match token.text.as_str() {
+                        "delay" => 0xF015,
+                        "buzzer" => 0xF018,
+                        _ => 0xF03A,
+                    } | x << 8,
+                );
+            }
+            "i" => self.i_statement(token)?,
+            "if" => {
+                let condition = self.condition(&token)?;
+                let keyword = self.next(&token)?;
+                match keyword.text.as_str() {
+                    // Skip the next statement unless the condition holds.
+                    "then" => self.emit_skip(condition, false),
+                    // Skip the jump past the block if the condition holds.
+                    "begin" => {
+                        self.emit_skip(condition, true);
+                        self.branches.push(self.here);
+                        self.emit_word(0x1000);
+                    }
+                    _ => return Err(keyword.error("expected 'then' or 'begin'")),
+                }
+            }
+            "else" => {
+                let branch = self
+                    .branches
+                    .pop()
+                    .ok_or_else(|| token.error("'else' without 'if ... begin'"))?;
+                let jump = self.here;
+                self.emit_word(0x1000);
+                self.patch_jump(branch);
+                self.branches.push(jump);
+            }
+            "end" => {
+                let branch = self
+                    .branches
+                    .pop()
+                    .ok_or_else(|| token.error("'end' without 'if ... begin'"))?;
+                self.patch_jump(branch);
+            }
+            "loop" => self.loops.push(Loop {
+                start: self.here,
+                exits: Vec::new(),
+            }),
+            "while" => {
+                if self.loops.is_empty() {
+                    return Err(token.error("'while' outside of a loop"));
+                }
+                let condition = self.condition(&token)?;
+                self.emit_skip(condition, true);
+                let exit = self.here;
+                self.loops.last_mut().unwrap().exits.push(exit);
+                self.emit_word(0x1000);
+            }
+            "again" => {
+                let Loop { start, exits } = self
+                    .loops
+                    .pop()
+                    .ok_or_else(|| token.error("'again' without 'loop'"))?;
+                self.emit_word(0x1000 | start as u16);
+                for exit in exits {
+                    self.patch_jump(exit);
+                }
+            }
+            _ if self.macros.contains_key(&token.text) => self.expand(token)?,
+            _ => match parse_number(&token.text) {
+                Some(_) => {
+                    let byte = self.byte(&token)?;
+                    self.emit_byte(byte);
+                }
+                // Any other name is a call to a subroutine.
+                None => self.emit_address(0x2000, token)?,
+            },
+        }
+        Ok(())
+    }
+
+    fn register_statement(&mut self, x: usize, token: Token) -> Result<(), AssemblerError> {
+        let op = self.next(&token)?;
+        let rhs = self.next(&op)?;
+        let x16 = (x as u16) << 8;
+
+        if let Some(y) = self.register(&rhs) {
+            let n = match op.text.as_str() {
+                ":=" => 0x0,
+                "|=" => 0x1,
+                "&=" => 0x2,
+                "^=" => 0x3,
+                "+=" => 0x4,
+                "-=" => 0x5,
+                ">>=" => 0x6,
+                "=-" => 0x7,
+                "<<=" => 0xE,
+                _ => return Err(op.error(format!("unknown operator '{}'", op.text))),
+            };
+            self.emit_word(0x8000 | x16 | (y as u16) << 4 | n);
+            return Ok(());
+        }
+
+        match (op.text.as_str(), rhs.text.as_str()) {
+            (":=", "key") => self.emit_word(0xF00A | x16),
+            (":=", "delay") => self.emit_word(0xF007 | x16),
+            (":=", "random") => {
+                let mask = self.next(&rhs)?;
+                let mask = self.byte(&mask)?;
+                self.emit_word(0xC000 | x16 | mask as u16);
+            }
+            (":=", _) => {
+                let nn = self.byte(&rhs)?;
+                self.emit_word(0x6000 | x16 | nn as u16);
+            }
+            ("+=", _) => {
+                let nn = self.byte(&rhs)?;
+                self.emit_word(0x7000 | x16 | nn as u16);
+            }
+            ("-=", _) => {
+                let nn = self.byte(&rhs)?;
+                self.emit_word(0x7000 | x16 | nn.wrapping_neg() as u16);
+            }
+            _ => return Err(op.error(format!("unknown operator '{}'", op.text))),
+        }
+        Ok(())
+    }
+
+    fn i_statement(&mut self, token: Token) -> Result<(), AssemblerError> {
+        let op = self.next(&token)?;
+        let rhs = self.next(&op)?;
+        match (op.text.as_str(), rhs.text.as_str()) {
+            ("+=", _) => {
+                let x = self
+                    .register(&rhs)
+                    .ok_or_else(|| rhs.error("expected a register"))?;
+                self.emit_word(0xF01E | (x as u16) << 8);
+            }
+            (":=", "hex") => {
+                let x = self.expect_register(&rhs)?;
+                self.emit_word(0xF029 | (x as u16) << 8);
+            }
+            (":=", "bighex") => {
+                self.require_superchip(&rhs)?;
+                let x = self.expect_register(&rhs)?;
+                self.emit_word(0xF030 | (x as u16) << 8);
+            }
+            (":=", "long") => {
+                self.require_xochip(&rhs)?;
+                let address = self.next(&rhs)?;
+                self.emit_word(0xF000);
+                self.emit_long(address)?;
+            }
+            (":=", _) => self.emit_address(0xA000, rhs)?,
+            _ => return Err(op.error(format!("unknown operator '{}'", op.text))),
+        }
+        Ok(())
+    }
+
+    fn condition(&mut self, after: &Token) -> Result<Condition, AssemblerError> {
+        let x = self.expect_register(after)?;
+        let op = self.next(after)?;
+        match op.text.as_str() {
+            "key" => return Ok(Condition::Key(x)),
+            "-key" => return Ok(Condition::NotKey(x)),
+            _ => {}
+        }
+
+        let rhs = self.next(&op)?;
+        let operand = match self.register(&rhs) {
+            Some(y) => Operand::Register(y),
+            None => Operand::Byte(self.byte(&rhs)?),
+        };
+        let condition = match op.text.as_str() {
+            "==" => Condition::Equal(x, operand),
+            "!=" => Condition::NotEqual(x, operand),
+            // VF := rhs - VX or VF := VX - rhs leaving VF as 1 if there was
+            // no borrow (the subtrahend was not greater).
+            "<" | ">" | "<=" | ">=" => {
+                match operand {
+                    Operand::Register(y) => self.emit_word(0x8F00 | (y as u16) << 4),
+                    Operand::Byte(nn) => self.emit_word(0x6F00 | nn as u16),
+                }
+                let subtract = if op.text == "<" || op.text == ">=" {
+                    0x8F07
+                } else {
+                    0x8F05
+                };
+                self.emit_word(subtract | (x as u16) << 4);
+                match op.text.as_str() {
+                    "<" | ">" => Condition::Flag(0),
+                    _ => Condition::Flag(1),
+                }
+            }
+            _ => return Err(op.error(format!("unknown comparison '{}'", op.text))),
+        };
+        Ok(condition)
+    }
+
+    // Emits a skip of the next instruction taken when |condition| holds (if
+    // |when| is true) or when it does not hold (if |when| is false).
+    fn emit_skip(&mut self, condition: Condition, when: bool) {
+        let (skip_equal, x, operand) = match condition {
+            Condition::Key(x) => {
+                self.emit_word(if when { 0xE09E } else { 0xE0A1 } | (x as u16) << 8);
+                return;
+            }
+            Condition::NotKey(x) => {
+                self.emit_word(if when { 0xE0A1 } else { 0xE09E } | (x as u16) << 8);
+                return;
+            }
+            Condition::Equal(x, operand) => (when, x, operand),
+            Condition::NotEqual(x, operand) => (!when, x, operand),
+            Condition::Flag(value) => (when, 0xF, Operand::Byte(value)),
+        };
+
+        let x = (x as u16) << 8;
+        self.emit_word(match (operand, skip_equal) {
+            (Operand::Byte(nn), true) => 0x3000 | x | nn as u16,
+            (Operand::Byte(nn), false) => 0x4000 | x | nn as u16,
+            (Operand::Register(y), true) => 0x5000 | x | (y as u16) << 4,
+            (Operand::Register(y), false) => 0x9000 | x | (y as u16) << 4,
+        });
+    }
+
+    // Replaces a macro invocation with the macro body where each argument
+    // name is replaced by the tokens following the invocation.
+    fn expand(&mut self, token: Token) -> Result<(), AssemblerError> {
+        if token.depth >= MAX_MACRO_DEPTH {
+            return Err(token.error(format!("macro '{}' is expanded too deeply", token.text)));
+        }
+
+        let argument_count = self.macros[&token.text].arguments.len();
+        let mut values = HashMap::new();
+        for index in 0..argument_count {
+            let value = self.next(&token)?;
+            values.insert(self.macros[&token.text].arguments[index].clone(), value);
+        }
+
+        let definition = self.macros.get_mut(&token.text).unwrap();
+        let calls = definition.calls;
+        definition.calls += 1;
+
+        let expansion: Vec<Token> = definition
+            .body
+            .iter()
+            .map(|body| {
+                let mut expanded = match values.get(&body.text) {
+                    Some(value) => value.clone(),
+                    None if body.text == "CALLS" => Token {
+                        text: calls.to_string(),
+                        ..body.clone()
+                    },
+                    None => body.clone(),
+                };
+                expanded.depth = token.depth + 1;
+                expanded
+            })
+            .collect();
+        for expanded in expansion.into_iter().rev() {
+            self.tokens.push_front(expanded);
+        }
+        Ok(())
+    }
+
+    // Evaluates a ':calc' expression (right to left without precedence).
+    fn calculate(&self, name: &Token, tokens: &[Token]) -> Result<f64, AssemblerError> {
+        let (value, rest) = self.expression(name, tokens)?;
+        match rest.first() {
+            Some(token) => Err(token.error(format!("unexpected '{}'", token.text))),
+            None => Ok(value),
+        }
+    }
+
+    fn expression<'a>(
+        &self,
+        name: &Token,
+        tokens: &'a [Token],
+    ) -> Result<(f64, &'a [Token]), AssemblerError> {
+        let (lhs, rest) = self.term(name, tokens)?;
+        let (op, rest) = match rest.split_first() {
+            Some((op, rest)) if op.text != ")" => (op, rest),
+            _ => return Ok((lhs, rest)),
+        };
+        let (rhs, rest) = self.expression(op, rest)?;
+        let value = match op.text.as_str() {
+            "+" => lhs + rhs,
+            "-" => lhs - rhs,
+            "*" => lhs * rhs,
+            "/" => lhs / rhs,
+            "%" => lhs % rhs,
+            "&" => (lhs as i64 & rhs as i64) as f64,
+            "|" => (lhs as i64 | rhs as i64) as f64,
+            "^" => (lhs as i64 ^ rhs as i64) as f64,
+            "<<" => ((lhs as i64) << rhs as i64) as f64,
+            ">>" => ((lhs as i64) >> rhs as i64) as f64,
+            "min" => lhs.min(rhs),
+            "max" => lhs.max(rhs),
+            "<" => (lhs < rhs) as i64 as f64,
+            ">" => (lhs > rhs) as i64 as f64,
+            "<=" => (lhs <= rhs) as i64 as f64,
+            ">=" => (lhs >= rhs) as i64 as f64,
+            "==" => (lhs == rhs) as i64 as f64,
+            "!=" => (lhs != rhs) as i64 as f64,
+            _ => return Err(op.error(format!("unknown operator '{}'", op.text))),
+        };
+        Ok((value, rest))
+    }
+
+    fn term<'a>(
+        &self,
+        name: &Token,
+        tokens: &'a [Token],
+    ) -> Result<(f64, &'a [Token]), AssemblerError> {
+        let (token, rest) = tokens
+            .split_first()
+            .ok_or_else(|| name.error("expected a value"))?;
+        match token.text.as_str() {
+            "(" => {
+                let (value, rest) = self.expression(token, rest)?;
+                match rest.split_first() {
+                    Some((close, rest)) if close.text == ")" => Ok((value, rest)),
+                    _ => Err(token.error("'(' without ')'")),
+                }
+            }
+            "-" => self.term(token, rest).map(|(value, rest)| (-value, rest)),
+            "~" => self
+                .term(token, rest)
+                .map(|(value, rest)| (!(value as i64) as f64, rest)),
+            "!" => self
+                .term(token, rest)
+                .map(|(value, rest)| ((value == 0.0) as i64 as f64, rest)),
+            "HERE" => Ok((self.here as f64, rest)),
+            _ => Ok((self.value(token)?, rest)),
+        }
+    }
+}
+
+const RESERVED: &[&str] = &[
+    "return",
+    ";",
+    "clear",
+    "bcd",
+    "save",
+    "load",
+    "sprite",
+    "jump",
+    "jump0",
+    "native",
+    "hires",
+    "lores",
+    "scroll-down",
+    "scroll-up",
+    "scroll-left",
+    "scroll-right",
+    "exit",
+    "saveflags",
+    "loadflags",
+    "plane",
+    "audio",
+    "delay",
+    "buzzer",
+    "pitch",
+    "i",
+    "if",
+    "then",
+    "begin",
+    "else",
+    "end",
+    "loop",
+    "while",
+    "again",
+    "key",
+    "-key",
+    "random",
+    "hex",
+    "bighex",
+    "long",
+    "HERE",
+    "CALLS",
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile_chip8(source: &str) -> Vec<u8> {
+        compile(source, Platform::Chip8).unwrap().rom
+    }
+
+    #[test]
+    fn compiles_statements() {
+        assert_eq!(
+            compile_chip8(
+                ": main\n\
+                 clear\n\
+                 v3 := 0x1F\n\
+                 v1 += v2\n\
+                 v0 -= 1\n\
+                 i := hex v4\n\
+                 sprite v0 v1 5\n\
+                 v2 := random 0xFF\n\
+                 return"
+            ),
+            vec![
+                0x00, 0xE0, 0x63, 0x1F, 0x81, 0x24, 0x70, 0xFF, 0xF4, 0x29, 0xD0, 0x15, 0xC2, 0xFF,
+                0x00, 0xEE
+            ]
+        );
+    }
+
+    #[test]
+    fn jumps_to_main_and_resolves_labels() {
+        assert_eq!(
+            compile_chip8(
+                ": face 0x3C 0x42\n\
+                 : main\n\
+                 i := face\n\
+                 draw\n\
+                 : draw ;"
+            ),
+            vec![0x12, 0x04, 0x3C, 0x42, 0xA2, 0x02, 0x22, 0x08, 0x00, 0xEE]
+        );
+    }
+
+    #[test]
+    fn compiles_structured_control_flow() {
+        assert_eq!(
+            compile_chip8(
+                ":alias counter v2\n\
+                 : main\n\
+                 loop\n\
+                 counter += 1\n\
+                 while counter != 10\n\
+                 if counter key then counter := 0\n\
+                 if v0 == v1 begin\n\
+                 v0 := 1\n\
+                 else\n\
+                 v0 := 2\n\
+                 end\n\
+                 again"
+            ),
+            vec![
+                0x72, 0x01, // counter += 1
+                0x42, 0x0A, 0x12, 0x16, // while
+                0xE2, 0xA1, 0x62, 0x00, // if counter key then
+                0x50, 0x10, 0x12, 0x12, 0x60, 0x01, // if begin
+                0x12, 0x14, 0x60, 0x02, // else
+                0x12, 0x00, // again
+            ]
+        );
+    }
+
+    #[test]
+    fn compiles_comparisons_through_vf() {
+        assert_eq!(
+            compile_chip8(
+                "if v1 < 5 then v0 := 1\n\
+                 if v1 >= v2 then v0 := 2"
+            ),
+            vec![
+                0x6F, 0x05, 0x8F, 0x17, 0x4F, 0x00, 0x60, 0x01, 0x8F, 0x20, 0x8F, 0x17, 0x4F, 0x01,
+                0x60, 0x02
+            ]
+        );
+    }
+
+    #[test]
+    fn expands_macros_and_calculations() {
+        assert_eq!(
+            compile_chip8(
+                ":const width 64\n\
+                 :calc center { ( width / 2 ) - 4 }\n\
+                 :calc mask { 1 << 3 + 1 }\n\
+                 :macro centre reg { reg := center }\n\
+                 :macro tally { :byte CALLS }\n\
+                 centre v0\n\
+                 centre v1\n\
+                 :byte mask\n\
+                 tally tally"
+            ),
+            vec![0x60, 0x1C, 0x61, 0x1C, 0x10, 0x00, 0x01]
+        );
+    }
+
+    #[test]
+    fn maps_addresses_to_lines() {
+        let program = compile(
+            ": main\n  clear\n\n  v0 := 1 # comment\n: data 1 2",
+            Platform::Chip8,
+        )
+        .unwrap();
+
+        assert_eq!(program.line(0x200), Some(2));
+        assert_eq!(program.line(0x202), Some(4));
+        assert_eq!(program.line(0x204), Some(5));
+        assert_eq!(program.line(0x203), None);
+    }
+
+    #[test]
+    fn requires_platform_extensions() {
+        let error = compile("hires", Platform::Chip8).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "<source>:1:1: 'hires' requires SUPER-CHIP"
+        );
+
+        assert_eq!(
+            compile("hires", Platform::SuperChip).unwrap().rom,
+            vec![0x00, 0xFF]
+        );
+        assert_eq!(
+            compile("i := long data plane 3 : data", Platform::XoChip)
+                .unwrap()
+                .rom,
+            vec![0xF0, 0x00, 0x02, 0x06, 0xF3, 0x01]
+        );
+    }
+
+    #[test]
+    fn reports_errors() {
+        let error = |source| compile(source, Platform::Chip8).err().unwrap().to_string();
+
+        assert_eq!(
+            error("jump nowhere"),
+            "<source>:1:6: undefined name 'nowhere'"
+        );
+        assert_eq!(
+            error("\n  v0 := 300"),
+            "<source>:2:9: 300 does not fit in a byte"
+        );
+        assert_eq!(error("v0 **= v1"), "<source>:1:4: unknown operator '**='");
+        assert_eq!(error(": v0"), "<source>:1:3: 'v0' can't be used as a name");
+    }
+}
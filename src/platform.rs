@@ -0,0 +1,71 @@
+// Copyright of Jordan Werthman (2019).
+
+use crate::quirks::Quirks;
+
+// Models the instruction set extension a ROM was written for
+//
+// SUPER-CHIP (SCHIP 1.1) extends CHIP-8 with a 128x64 high resolution mode,
+// scrolling, 16x16 sprites, a big font and an exit instruction. XO-CHIP
+// further extends SUPER-CHIP with 64K of memory, a second bitplane and a few
+// extra instructions (see: http://johnearnest.github.io/Octo/docs/XO-ChipSpecification.html).
+// Opcodes from an extension are only executed when the CPU is running that
+// platform.
+
+pub const MEMORY_SIZE: usize = 0x1000;
+pub const XO_MEMORY_SIZE: usize = 0x10000;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Platform {
+    #[default]
+    Chip8,
+    SuperChip,
+    XoChip,
+}
+
+impl Platform {
+    // Whether the SUPER-CHIP extensions are available.
+    pub fn has_superchip(self) -> bool {
+        self != Platform::Chip8
+    }
+
+    // Whether the XO-CHIP extensions are available.
+    pub fn has_xochip(self) -> bool {
+        self == Platform::XoChip
+    }
+
+    // The size of the address space in bytes.
+    pub fn memory_size(self) -> usize {
+        if self.has_xochip() {
+            XO_MEMORY_SIZE
+        } else {
+            MEMORY_SIZE
+        }
+    }
+
+    // The number of RPL user flags which can be saved with FX75.
+    pub fn flag_count(self) -> usize {
+        match self {
+            Platform::Chip8 => 0,
+            Platform::SuperChip => 8,
+            Platform::XoChip => 16,
+        }
+    }
+
+    // The quirks most ROMs written for this platform expect.
+    pub fn default_quirks(self) -> Quirks {
+        match self {
+            Platform::Chip8 => Quirks::default(),
+            Platform::SuperChip => Quirks::superchip(),
+            Platform::XoChip => Quirks::xochip(),
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Platform> {
+        match name {
+            "chip8" | "chip-8" => Some(Platform::Chip8),
+            "schip" | "superchip" => Some(Platform::SuperChip),
+            "xochip" | "xo-chip" => Some(Platform::XoChip),
+            _ => None,
+        }
+    }
+}
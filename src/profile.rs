@@ -0,0 +1,225 @@
+// Copyright of Jordan Werthman (2019).
+
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::instruction::{disassemble, Instruction};
+
+// Models a profile of where a ROM spends its time
+//
+// While profiling (see Cpu::start_profile()) each instruction executed is
+// counted by its address and by its opcode class (e.g. DXYN). A subroutine
+// is timed from its call (2NNN) to its return (00EE) by the instructions
+// executed in between, including those of the subroutines it calls, so the
+// time of a subroutine which has not yet returned is not included.
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Subroutine {
+    pub calls: u64,
+    // The instructions executed from each call up to and including the
+    // return.
+    pub instructions: u64,
+}
+
+pub struct Profile {
+    // The executions of the instruction at each address.
+    addresses: Vec<u64>,
+    classes: BTreeMap<&'static str, u64>,
+    subroutines: BTreeMap<usize, Subroutine>,
+    // The address of each subroutine being run and the number of
+    // instructions executed before it was called.
+    calls: Vec<(usize, u64)>,
+    instructions: u64,
+}
+
+impl Profile {
+    // Creates an empty profile of a memory of |size| bytes.
+    pub fn new(size: usize) -> Profile {
+        Profile {
+            addresses: vec![0; size],
+            classes: BTreeMap::new(),
+            subroutines: BTreeMap::new(),
+            calls: Vec::new(),
+            instructions: 0,
+        }
+    }
+
+    // Counts |instruction| which was executed at |pc|.
+    pub fn record(&mut self, pc: usize, instruction: Instruction) {
+        self.instructions += 1;
+        if let Some(count) = self.addresses.get_mut(pc) {
+            *count += 1;
+        }
+        *self.classes.entry(instruction.pattern()).or_insert(0) += 1;
+
+        match instruction {
+            Instruction::Call(address) => self.calls.push((address, self.instructions)),
+            Instruction::Ret => {
+                // Returns without a call (e.g. after loading a state) are
+                // not timed.
+                if let Some((address, start)) = self.calls.pop() {
+                    let subroutine = self.subroutines.entry(address).or_default();
+                    subroutine.calls += 1;
+                    subroutine.instructions += self.instructions - start;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    // The executions of the instruction at each address in memory.
+    pub fn addresses(&self) -> &[u64] {
+        &self.addresses
+    }
+
+    // The addresses executed from the most executed.
+    pub fn hottest(&self) -> Vec<(usize, u64)> {
+        let mut addresses: Vec<(usize, u64)> = self
+            .addresses
+            .iter()
+            .cloned()
+            .enumerate()
+            .filter(|&(_, count)| count > 0)
+            .collect();
+        addresses.sort_by_key(|&(address, count)| (Reverse(count), address));
+        addresses
+    }
+
+    // The opcode classes executed from the most executed.
+    pub fn classes(&self) -> Vec<(&'static str, u64)> {
+        let mut classes: Vec<(&'static str, u64)> = self
+            .classes
+            .iter()
+            .map(|(&class, &count)| (class, count))
+            .collect();
+        classes.sort_by_key(|&(class, count)| (Reverse(count), class));
+        classes
+    }
+
+    // The subroutines which returned from the one taking the most time.
+    pub fn subroutines(&self) -> Vec<(usize, Subroutine)> {
+        let mut subroutines: Vec<(usize, Subroutine)> = self
+            .subroutines
+            .iter()
+            .map(|(&address, &subroutine)| (address, subroutine))
+            .collect();
+        subroutines
+            .sort_by_key(|&(address, subroutine)| (Reverse(subroutine.instructions), address));
+        subroutines
+    }
+
+    // Describes the profile showing at most |limit| addresses and
+    // subroutines. The instructions are disassembled from |memory|.
+    pub fn report(&self, memory: &[u8], limit: usize) -> String {
+        let percent = |count: u64| 100.0 * count as f64 / self.instructions.max(1) as f64;
+        let mut report = format!("Profiled {} instructions.\n", self.instructions);
+
+        report += "\nHottest addresses:\n";
+        for (address, count) in self.hottest().into_iter().take(limit) {
+            let end = memory.len().min(address + 4);
+            let mnemonic = disassemble(&memory[address.min(end)..end], address)
+                .first()
+                .map(|(_, instruction)| instruction.to_string())
+                .unwrap_or_default();
+            let _ = writeln!(
+                report,
+                "  0x{:03X}  {:<22} {:>10} {:>6.2}%",
+                address,
+                mnemonic,
+                count,
+                percent(count)
+            );
+        }
+
+        report += "\nOpcode classes:\n";
+        for (class, count) in self.classes() {
+            let _ = writeln!(
+                report,
+                "  {}  {:>10} {:>6.2}%",
+                class,
+                count,
+                percent(count)
+            );
+        }
+
+        report += "\nSubroutines (instructions from call to return):\n";
+        for (address, subroutine) in self.subroutines().into_iter().take(limit) {
+            let _ = writeln!(
+                report,
+                "  0x{:03X}  {:>8} calls {:>10} {:>6.2}% {:>10.1} per call",
+                address,
+                subroutine.calls,
+                subroutine.instructions,
+                percent(subroutine.instructions),
+                subroutine.instructions as f64 / subroutine.calls as f64
+            );
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::instruction::decode;
+
+    fn profile(program: &[(usize, u16)]) -> Profile {
+        let mut profile = Profile::new(0x1000);
+        for &(pc, opcode) in program.iter() {
+            profile.record(pc, decode(opcode));
+        }
+        profile
+    }
+
+    #[test]
+    fn counts_addresses_and_classes() {
+        let profile = profile(&[(0x200, 0x6001), (0x202, 0x7001), (0x200, 0x6002)]);
+        assert_eq!(profile.instructions(), 3);
+        assert_eq!(profile.hottest(), vec![(0x200, 2), (0x202, 1)]);
+        assert_eq!(profile.classes(), vec![("6XNN", 2), ("7XNN", 1)]);
+        assert_eq!(profile.addresses()[0x200], 2);
+    }
+
+    #[test]
+    fn times_nested_subroutines() {
+        let profile = profile(&[
+            (0x200, 0x2300), // CALL 0x300
+            (0x300, 0x2400), //   CALL 0x400
+            (0x400, 0x6001), //     LD V0, 0x01
+            (0x402, 0x00EE), //     RET
+            (0x302, 0x00EE), //   RET
+            (0x202, 0x2400), // CALL 0x400
+            (0x400, 0x6001), //   LD V0, 0x01
+            (0x402, 0x00EE), //   RET
+            (0x204, 0x2300), // CALL 0x300
+            (0x300, 0x00EE), //   RET
+            (0x206, 0x00EE), // A return without a call is ignored.
+            (0x208, 0x2500), // A call which has not returned is not included.
+        ]);
+        assert_eq!(
+            profile.subroutines(),
+            vec![
+                (
+                    0x300,
+                    Subroutine {
+                        calls: 2,
+                        instructions: 5
+                    }
+                ),
+                (
+                    0x400,
+                    Subroutine {
+                        calls: 2,
+                        instructions: 4
+                    }
+                ),
+            ]
+        );
+    }
+}
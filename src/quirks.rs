@@ -0,0 +1,103 @@
+// Copyright of Jordan Werthman (2019).
+
+// Models the behaviour of ambiguous CHIP-8 opcodes
+//
+// The original COSMAC VIP interpreter and its successors (CHIP-48,
+// SUPER-CHIP, XO-CHIP) disagree on how a handful of opcodes behave and ROMs
+// are usually written against one of them. Each flag switches one of these
+// behaviours in the CPU. A good summary of the differences can be found at:
+// https://chip8.gulrak.net/
+
+// How FX55/FX65 modify I after storing or loading the registers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoadStoreQuirk {
+    Unchanged,           // I is left unmodified (SUPER-CHIP)
+    IncrementByX,        // I is incremented by X (CHIP-48)
+    IncrementByXPlusOne, // I is incremented by X + 1 (COSMAC VIP)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quirks {
+    // 8XY6/8XYE: Shift VY and store the result in VX (otherwise VX is
+    // shifted in place and VY is ignored).
+    pub shift_uses_vy: bool,
+    // FX55/FX65: How I is modified by the operation.
+    pub load_store: LoadStoreQuirk,
+    // BNNN: Jump to XNN plus VX (otherwise NNN plus V0).
+    pub jump_uses_vx: bool,
+    // DXYN: Clip sprites at the edges of the screen (otherwise they wrap).
+    pub clip_sprites: bool,
+    // 8XY1/8XY2/8XY3: Reset VF to 0 after the logical operation.
+    pub vf_reset: bool,
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Self::modern()
+    }
+}
+
+impl Quirks {
+    pub fn cosmac_vip() -> Quirks {
+        Quirks {
+            shift_uses_vy: true,
+            load_store: LoadStoreQuirk::IncrementByXPlusOne,
+            jump_uses_vx: false,
+            clip_sprites: true,
+            vf_reset: true,
+        }
+    }
+
+    pub fn chip48() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            load_store: LoadStoreQuirk::IncrementByX,
+            jump_uses_vx: true,
+            clip_sprites: true,
+            vf_reset: false,
+        }
+    }
+
+    pub fn superchip() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            load_store: LoadStoreQuirk::Unchanged,
+            jump_uses_vx: true,
+            clip_sprites: true,
+            vf_reset: false,
+        }
+    }
+
+    pub fn xochip() -> Quirks {
+        Quirks {
+            shift_uses_vy: true,
+            load_store: LoadStoreQuirk::IncrementByXPlusOne,
+            jump_uses_vx: false,
+            clip_sprites: false,
+            vf_reset: false,
+        }
+    }
+
+    // The behaviour most modern interpreters (and tutorials) settled on.
+    pub fn modern() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            load_store: LoadStoreQuirk::Unchanged,
+            jump_uses_vx: false,
+            clip_sprites: false,
+            vf_reset: false,
+        }
+    }
+
+    // Looks up a preset by name e.g. for selection from a frontend.
+    pub fn from_name(name: &str) -> Option<Quirks> {
+        match name {
+            "vip" | "cosmac-vip" => Some(Quirks::cosmac_vip()),
+            "chip48" | "chip-48" => Some(Quirks::chip48()),
+            "schip" | "superchip" => Some(Quirks::superchip()),
+            "xochip" | "xo-chip" => Some(Quirks::xochip()),
+            "modern" => Some(Quirks::modern()),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,111 @@
+// Copyright of Jordan Werthman (2019).
+
+#[cfg(target_arch = "wasm32")]
+use wasm_bindgen::prelude::*;
+
+// Models the random number generator used by CXNN
+//
+// The CPU draws random bytes from a RandomSource which can be replaced (e.g.
+// with a scripted sequence) using Cpu::set_random(). Its entire state is a
+// u64 so that it can be saved along with the machine and a replay produces
+// the same numbers. By default Xorshift is seeded from system entropy but a
+// fixed seed makes a run reproducible (e.g. for tests).
+
+pub trait RandomSource {
+    fn next_byte(&mut self) -> u8;
+
+    // The state from which the sequence continues.
+    fn state(&self) -> u64;
+
+    fn set_state(&mut self, state: u64);
+}
+
+// The xorshift64* generator (see: https://vigna.di.unimi.it/ftp/papers/xorshift.pdf).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Xorshift {
+    state: u64,
+}
+
+impl Xorshift {
+    pub fn new(seed: u64) -> Xorshift {
+        let mut random = Xorshift { state: 0 };
+        random.set_state(seed);
+        random
+    }
+
+    pub fn from_entropy() -> Xorshift {
+        Xorshift::new(entropy())
+    }
+}
+
+impl RandomSource for Xorshift {
+    fn next_byte(&mut self) -> u8 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        (self.state.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 56) as u8
+    }
+
+    fn state(&self) -> u64 {
+        self.state
+    }
+
+    // A zero state would only ever produce zero so it is replaced.
+    fn set_state(&mut self, state: u64) {
+        self.state = if state == 0 {
+            0x9e37_79b9_7f4a_7c15
+        } else {
+            state
+        };
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn entropy() -> u64 {
+    rand::random()
+}
+
+#[cfg(target_arch = "wasm32")]
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = Math)]
+    fn random() -> f64;
+}
+
+// The operating system's entropy is unavailable in the browser so two calls
+// to Math.random() supply the seed instead.
+#[cfg(target_arch = "wasm32")]
+fn entropy() -> u64 {
+    let half = || (random() * u32::MAX as f64) as u64;
+    half() << 32 | half()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seeded_sequences_repeat() {
+        let mut first = Xorshift::new(1234);
+        let mut second = Xorshift::new(1234);
+        let bytes: Vec<u8> = (0..32).map(|_| first.next_byte()).collect();
+        assert_eq!(
+            bytes,
+            (0..32).map(|_| second.next_byte()).collect::<Vec<u8>>()
+        );
+
+        let mut resumed = Xorshift::new(1);
+        resumed.set_state(first.state());
+        assert_eq!(first.next_byte(), resumed.next_byte());
+    }
+
+    #[test]
+    fn covers_every_byte() {
+        let mut random = Xorshift::new(0);
+        let mut seen = [false; 256];
+        for _ in 0..10_000 {
+            seen[random.next_byte() as usize] = true;
+        }
+        assert!(seen.iter().all(|&seen| seen));
+    }
+}
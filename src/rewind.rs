@@ -0,0 +1,163 @@
+// Copyright of Jordan Werthman (2019).
+
+use std::collections::VecDeque;
+
+// Models a history of executed instructions which can be undone
+//
+// Rather than copying the whole machine each tick() of the CPU a Delta
+// holds only what an instruction changed: the previous PC, I, stack pointer,
+// random state and cycle count, the old value of each register, flag or byte
+// of memory written, the timers and, for instructions which draw, the pixels
+// they changed. The newest |capacity| deltas are kept with the oldest dropped
+// as more arrive.
+
+// The previous contents of the display before an instruction.
+pub enum DisplayChange {
+    // The index and old colour of each pixel which changed.
+    Pixels(Vec<(usize, u8)>),
+    // The whole display when the resolution changed (00FE/00FF).
+    Resolution { hires: bool, display: Vec<u8> },
+}
+
+pub struct Delta {
+    pub pc: usize,
+    pub i: usize,
+    pub stack_pointer: usize,
+    pub registers: Vec<(usize, u8)>,
+    pub flags: Vec<(usize, u8)>,
+    pub memory: Vec<(usize, u8)>,
+    pub random: u64,
+    pub cycles: u64,
+    pub display: Option<DisplayChange>,
+    pub planes: u8,
+    pub delay_timer: f64,
+    pub sound_timer: f64,
+}
+
+pub struct Rewind {
+    deltas: VecDeque<Delta>,
+    capacity: usize,
+}
+
+impl Rewind {
+    pub fn new(capacity: usize) -> Rewind {
+        Rewind {
+            deltas: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, delta: Delta) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.deltas.len() == self.capacity {
+            self.deltas.pop_front();
+        }
+        self.deltas.push_back(delta);
+    }
+
+    // Removes the delta of the most recently executed instruction.
+    pub fn pop(&mut self) -> Option<Delta> {
+        self.deltas.pop_back()
+    }
+
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.deltas.clear();
+    }
+}
+
+// The index and value in |before| of each element which differs in |after|.
+pub fn diff(before: &[u8], after: &[u8]) -> Vec<(usize, u8)> {
+    before
+        .iter()
+        .zip(after.iter())
+        .enumerate()
+        .filter(|(_, (old, new))| old != new)
+        .map(|(index, (&old, _))| (index, old))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::assemble;
+    use crate::bus::Bus;
+    use crate::cpu::Cpu;
+    use crate::platform::Platform;
+
+    #[test]
+    fn step_back_restores_state() {
+        let rom = assemble(
+            "
+            start:
+                LD V0, 0x0A
+                LD F, V0
+                DRW V0, V0, 5
+                LD I, 0x300
+                LD B, V0
+                CALL sub
+                HIGH
+                JP start
+            sub:
+                ADD V0, 0x20
+                LD DT, V0
+                RET
+            ",
+        )
+        .unwrap();
+        let platform = Platform::SuperChip;
+        let mut cpu = Cpu::new(&rom, platform, platform.default_quirks()).unwrap();
+        let mut bus = Bus::new();
+        cpu.enable_rewind(100);
+
+        let mut history = Vec::new();
+        for _ in 0..20 {
+            history.push((
+                cpu.pc(),
+                cpu.i(),
+                cpu.registers().to_vec(),
+                cpu.memory().to_vec(),
+                bus.display().to_vec(),
+                bus.hires(),
+                bus.delay_timer(),
+            ));
+            cpu.tick(&mut bus).unwrap();
+        }
+        assert_eq!(cpu.rewind_len(), 20);
+
+        while let Some(expected) = history.pop() {
+            assert!(cpu.step_back(&mut bus));
+            let actual = (
+                cpu.pc(),
+                cpu.i(),
+                cpu.registers().to_vec(),
+                cpu.memory().to_vec(),
+                bus.display().to_vec(),
+                bus.hires(),
+                bus.delay_timer(),
+            );
+            assert!(actual == expected, "mismatch at 0x{:03X}", expected.0);
+        }
+        assert!(!cpu.step_back(&mut bus));
+    }
+
+    #[test]
+    fn drops_oldest_deltas() {
+        let rom = assemble("loop: ADD V0, 1\nJP loop").unwrap();
+        let platform = Platform::Chip8;
+        let mut cpu = Cpu::new(&rom, platform, platform.default_quirks()).unwrap();
+        let mut bus = Bus::new();
+        cpu.enable_rewind(3);
+
+        for _ in 0..10 {
+            cpu.tick(&mut bus).unwrap();
+        }
+        let undone = (0..10).take_while(|_| cpu.step_back(&mut bus)).count();
+        assert_eq!(undone, 3);
+        assert_eq!(cpu.registers()[0], 4);
+    }
+}
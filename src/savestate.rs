@@ -0,0 +1,355 @@
+// Copyright of Jordan Werthman (2019).
+
+use std::error::Error;
+use std::fmt;
+
+use crate::platform::Platform;
+
+// Models the binary format used to save the state of the machine
+//
+// A save state is laid out as (with integers stored little-endian):
+//
+//   MAGIC    8 bytes  "RUST8SAV"
+//   VERSION  u16      the layout of the payload (see VERSION)
+//   PLATFORM u8       0 = CHIP-8, 1 = SUPER-CHIP, 2 = XO-CHIP
+//   ROM HASH u64      FNV-1a hash of the ROM the state was saved from
+//   LENGTH   u32      the length of the payload in bytes
+//   PAYLOAD  ...      the CPU followed by the bus (see Cpu::save_state())
+//   CHECKSUM u64      FNV-1a hash of every byte before it
+//
+// States are only loaded into a CPU running the same ROM on the same
+// platform. Older versions are read by checking Reader::version().
+
+pub const VERSION: u16 = 3;
+
+const MAGIC: &[u8; 8] = b"RUST8SAV";
+const HEADER_SIZE: usize = 8 + 2 + 1 + 8 + 4;
+const CHECKSUM_SIZE: usize = 8;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StateError {
+    NotAState,
+    UnsupportedVersion(u16),
+    ChecksumMismatch,
+    PlatformMismatch,
+    RomMismatch,
+    Truncated,
+    Invalid(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::NotAState => write!(f, "Not a RUST-8 save state"),
+            StateError::UnsupportedVersion(version) => write!(
+                f,
+                "Unsupported save state version {} (expected at most {})",
+                version, VERSION
+            ),
+            StateError::ChecksumMismatch => write!(f, "Save state is corrupt (bad checksum)"),
+            StateError::PlatformMismatch => {
+                write!(f, "Save state was made on a different platform")
+            }
+            StateError::RomMismatch => write!(f, "Save state was made with a different ROM"),
+            StateError::Truncated => write!(f, "Save state is truncated"),
+            StateError::Invalid(what) => write!(f, "Save state has an invalid {}", what),
+        }
+    }
+}
+
+impl Error for StateError {}
+
+// The 64-bit FNV-1a hash of |bytes|.
+pub fn hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn platform_id(platform: Platform) -> u8 {
+    match platform {
+        Platform::Chip8 => 0,
+        Platform::SuperChip => 1,
+        Platform::XoChip => 2,
+    }
+}
+
+// Builds the payload of a save state.
+#[derive(Default)]
+pub struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    pub fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn f64(&mut self, value: f64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // Writes |bytes| prefixed by their length.
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    // Wraps the payload with the header and checksum.
+    pub fn finish(self, platform: Platform, rom_hash: u64) -> Vec<u8> {
+        let mut state = Vec::with_capacity(HEADER_SIZE + self.bytes.len() + CHECKSUM_SIZE);
+        state.extend_from_slice(MAGIC);
+        state.extend_from_slice(&VERSION.to_le_bytes());
+        state.push(platform_id(platform));
+        state.extend_from_slice(&rom_hash.to_le_bytes());
+        state.extend_from_slice(&(self.bytes.len() as u32).to_le_bytes());
+        state.extend_from_slice(&self.bytes);
+        let checksum = hash(&state);
+        state.extend_from_slice(&checksum.to_le_bytes());
+        state
+    }
+}
+
+// Reads the payload of a save state checked by Reader::new().
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    version: u16,
+}
+
+impl<'a> Reader<'a> {
+    // Validates the header and checksum of |state| against the ROM and
+    // platform it is to be loaded into.
+    pub fn new(
+        state: &'a [u8],
+        platform: Platform,
+        rom_hash: u64,
+    ) -> Result<Reader<'a>, StateError> {
+        if state.len() < HEADER_SIZE + CHECKSUM_SIZE || &state[..MAGIC.len()] != MAGIC {
+            return Err(StateError::NotAState);
+        }
+        let mut header = Reader {
+            bytes: &state[MAGIC.len()..HEADER_SIZE],
+            version: VERSION,
+        };
+        let version = header.u16()?;
+        if version == 0 || version > VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        let (contents, checksum) = state.split_at(state.len() - CHECKSUM_SIZE);
+        if hash(contents).to_le_bytes() != checksum {
+            return Err(StateError::ChecksumMismatch);
+        }
+        if header.u8()? != platform_id(platform) {
+            return Err(StateError::PlatformMismatch);
+        }
+        if header.u64()? != rom_hash {
+            return Err(StateError::RomMismatch);
+        }
+        let length = header.u32()? as usize;
+        if HEADER_SIZE + length != contents.len() {
+            return Err(StateError::Truncated);
+        }
+        Ok(Reader {
+            bytes: &contents[HEADER_SIZE..],
+            version,
+        })
+    }
+
+    // The version of the format the state was saved with.
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [u8], StateError> {
+        if count > self.bytes.len() {
+            return Err(StateError::Truncated);
+        }
+        let (taken, rest) = self.bytes.split_at(count);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], StateError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    pub fn f64(&mut self) -> Result<f64, StateError> {
+        Ok(f64::from_le_bytes(self.array()?))
+    }
+
+    // Reads bytes written by Writer::bytes().
+    pub fn bytes(&mut self) -> Result<&'a [u8], StateError> {
+        let length = self.u32()? as usize;
+        self.take(length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::bus::Bus;
+    use crate::cpu::Cpu;
+    use crate::quirks::Quirks;
+
+    const ROM: &str = "
+        start:
+            HIGH
+            LD V0, 0x30
+            LD DT, V0
+            LD I, 0x400
+            LD B, V0
+            CALL sub
+            JP start
+        sub:
+            DRW V0, V0, 0
+            RET
+    ";
+
+    fn run(ticks: usize) -> (Cpu, Bus) {
+        let rom = assemble(ROM).unwrap();
+        let platform = Platform::SuperChip;
+        let mut cpu = Cpu::new(&rom, platform, platform.default_quirks()).unwrap();
+        let mut bus = Bus::new();
+        for _ in 0..ticks {
+            cpu.tick(&mut bus).unwrap();
+            bus.update_timers(0.004);
+        }
+        bus.set_key(0xA);
+        (cpu, bus)
+    }
+
+    #[test]
+    fn round_trip() {
+        let (cpu, bus) = run(7);
+        let state = cpu.save_state(&bus);
+
+        let (mut loaded, mut loaded_bus) = run(0);
+        loaded.load_state(&mut loaded_bus, &state).unwrap();
+        assert_eq!(loaded.pc(), cpu.pc());
+        assert_eq!(loaded.i(), cpu.i());
+        assert_eq!(loaded.registers(), cpu.registers());
+        assert_eq!(loaded.stack(), cpu.stack());
+        assert_eq!(loaded.memory(), cpu.memory());
+        assert_eq!(loaded_bus.display(), bus.display());
+        assert!(loaded_bus.hires());
+        assert!(loaded_bus.check_key(0xA));
+        assert_eq!(loaded_bus.timers(), bus.timers());
+        assert_eq!(loaded.save_state(&loaded_bus), state);
+    }
+
+    #[test]
+    fn rejects_other_roms_and_corruption() {
+        let (cpu, bus) = run(7);
+        let mut state = cpu.save_state(&bus);
+
+        let rom = assemble("JP 0x200").unwrap();
+        let mut other = Cpu::new(&rom, Platform::SuperChip, Quirks::superchip()).unwrap();
+        let mut other_bus = Bus::new();
+        let result = other.load_state(&mut other_bus, &state);
+        assert_eq!(result, Err(StateError::RomMismatch));
+        assert_eq!(other.pc(), 0x200);
+
+        let (mut fresh, mut fresh_bus) = run(0);
+        state[HEADER_SIZE + 4] ^= 0xFF;
+        let result = fresh.load_state(&mut fresh_bus, &state);
+        assert_eq!(result, Err(StateError::ChecksumMismatch));
+        assert_eq!(
+            fresh.load_state(&mut fresh_bus, b"RUST8"),
+            Err(StateError::NotAState)
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_pixels() {
+        let (cpu, bus) = run(7);
+        let mut state = cpu.save_state(&bus);
+        // The display is followed by the keys, timers, pitch and an empty
+        // audio pattern. The checksum is recalculated so only the pixel is
+        // wrong.
+        let end = state.len() - CHECKSUM_SIZE;
+        state[end - 24] = 0x80;
+        let checksum = hash(&state[..end]);
+        state[end..].copy_from_slice(&checksum.to_le_bytes());
+
+        let (mut loaded, mut loaded_bus) = run(0);
+        let result = loaded.load_state(&mut loaded_bus, &state);
+        assert_eq!(result, Err(StateError::Invalid("display")));
+        assert!(loaded_bus.display().iter().all(|&pixel| pixel == 0));
+    }
+
+    // The numbers produced by the next |count| loops of "RND V0; JP loop".
+    fn random_numbers(cpu: &mut Cpu, bus: &mut Bus, count: usize) -> Vec<u8> {
+        (0..count)
+            .map(|_| {
+                cpu.tick(bus).unwrap();
+                cpu.tick(bus).unwrap();
+                cpu.registers()[0]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn restores_random_state() {
+        let rom = assemble("loop: RND V0, 0xFF\nJP loop").unwrap();
+        let mut cpu = Cpu::new(&rom, Platform::Chip8, Quirks::default()).unwrap();
+        let mut bus = Bus::new();
+        cpu.seed_random(42);
+        let state = cpu.save_state(&bus);
+        let expected = random_numbers(&mut cpu, &mut bus, 8);
+
+        let mut loaded = Cpu::new(&rom, Platform::Chip8, Quirks::default()).unwrap();
+        loaded.load_state(&mut bus, &state).unwrap();
+        assert_eq!(random_numbers(&mut loaded, &mut bus, 8), expected);
+    }
+
+    #[test]
+    fn invalid_payload_changes_nothing() {
+        let (cpu, _) = run(0);
+        let mut writer = Writer::default();
+        writer.u32(0x234);
+        writer.u32(0x300);
+        writer.bytes(&[1; 16]);
+        writer.bytes(&[0; 16]);
+        writer.u64(1);
+        writer.u64(0);
+        writer.u16(0x51);
+        let state = writer.finish(Platform::SuperChip, hash(&assemble(ROM).unwrap()));
+
+        let (mut loaded, mut loaded_bus) = run(0);
+        let result = loaded.load_state(&mut loaded_bus, &state);
+        assert_eq!(result, Err(StateError::Invalid("stack pointer")));
+        assert_eq!(loaded.pc(), cpu.pc());
+        assert_eq!(loaded.registers(), cpu.registers());
+    }
+}
@@ -0,0 +1,187 @@
+// Copyright of Jordan Werthman (2019).
+
+use std::fmt;
+use std::io;
+use std::io::Write;
+
+use crate::bus::Bus;
+
+// Models a capture of the display which can be saved or compared
+//
+// Screens are written as text with a character per pixel (as printed by the
+// bus when logging) or as a PNG image. The text form can be parsed again so
+// that a capture can be checked against one saved earlier:
+//
+//   ____##__
+//   ___#_#__
+//
+// where '_' is off, '#' is the first bitplane and '+' and '*' are the second
+// and both bitplanes (XO-CHIP).
+
+const CHARACTERS: [char; 4] = ['_', '#', '+', '*'];
+
+// RGB colours for each pixel value matching the desktop frontend.
+const PALETTE: [[u8; 3]; 4] = [[0, 0, 0], [0, 242, 0], [242, 153, 0], [242, 242, 217]];
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+const MAX_STORED_BLOCK: usize = 0xFFFF;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Screen {
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>,
+}
+
+impl Screen {
+    pub fn capture(bus: &Bus) -> Screen {
+        Screen {
+            width: bus.width(),
+            height: bus.height(),
+            pixels: bus.display().to_vec(),
+        }
+    }
+
+    // Parses the text written by Display. Returns None if the rows differ in
+    // length or contain other characters.
+    pub fn parse(text: &str) -> Option<Screen> {
+        let rows: Vec<&str> = text.lines().filter(|row| !row.is_empty()).collect();
+        let width = rows.first()?.chars().count();
+        let mut pixels = Vec::with_capacity(width * rows.len());
+        for row in rows.iter() {
+            if row.chars().count() != width {
+                return None;
+            }
+            for character in row.chars() {
+                let value = CHARACTERS.iter().position(|&c| c == character)?;
+                pixels.push(value as u8);
+            }
+        }
+        Some(Screen {
+            width,
+            height: rows.len(),
+            pixels,
+        })
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> u8 {
+        self.pixels[y * self.width + x]
+    }
+
+    // Writes the screen as an 8-bit indexed PNG with a pixel per pixel.
+    pub fn write_png<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut header = Vec::new();
+        header.extend_from_slice(&(self.width as u32).to_be_bytes());
+        header.extend_from_slice(&(self.height as u32).to_be_bytes());
+        // Bit depth 8, indexed colour, deflate, standard filters, no interlace.
+        header.extend_from_slice(&[8, 3, 0, 0, 0]);
+        let palette: Vec<u8> = PALETTE.iter().flatten().cloned().collect();
+
+        // Each row is prefixed with filter type 0 (none).
+        let mut rows = Vec::with_capacity((self.width + 1) * self.height);
+        for row in self.pixels.chunks(self.width) {
+            rows.push(0);
+            rows.extend_from_slice(row);
+        }
+
+        writer.write_all(&PNG_SIGNATURE)?;
+        write_chunk(writer, b"IHDR", &header)?;
+        write_chunk(writer, b"PLTE", &palette)?;
+        write_chunk(writer, b"IDAT", &zlib_stored(&rows))?;
+        write_chunk(writer, b"IEND", &[])
+    }
+}
+
+impl fmt::Display for Screen {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for row in self.pixels.chunks(self.width) {
+            let text: String = row
+                .iter()
+                .map(|&pixel| CHARACTERS[pixel as usize & 0x3])
+                .collect();
+            writeln!(f, "{}", text)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_chunk<W: Write>(writer: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    writer.write_all(&(data.len() as u32).to_be_bytes())?;
+    writer.write_all(kind)?;
+    writer.write_all(data)?;
+    let crc = crc32(kind.iter().chain(data.iter()));
+    writer.write_all(&crc.to_be_bytes())
+}
+
+// Wraps |data| in a zlib stream of uncompressed (stored) deflate blocks.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut stream = vec![0x78, 0x01];
+    let blocks: Vec<&[u8]> = data.chunks(MAX_STORED_BLOCK).collect();
+    for (index, block) in blocks.iter().enumerate() {
+        let last = index + 1 == blocks.len();
+        stream.push(last as u8);
+        stream.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        stream.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        stream.extend_from_slice(block);
+    }
+    stream.extend_from_slice(&adler32(data).to_be_bytes());
+    stream
+}
+
+fn crc32<'a>(bytes: impl Iterator<Item = &'a u8>) -> u32 {
+    !bytes.fold(!0, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u32, |crc, _| {
+            if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let (a, b) = bytes.iter().fold((1u32, 0u32), |(a, b), &byte| {
+        let a = (a + byte as u32) % 65521;
+        (a, (b + a) % 65521)
+    });
+    b << 16 | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_round_trip() {
+        let mut bus = Bus::new();
+        bus.set_pixel(1, 1);
+        bus.set_pixel(64 * 31 + 63, 3);
+        let screen = Screen::capture(&bus);
+
+        let text = screen.to_string();
+        assert!(text.starts_with("_#__"));
+        assert_eq!(text.lines().count(), 32);
+        assert_eq!(Screen::parse(&text), Some(screen));
+        assert_eq!(Screen::parse("__\n_"), None);
+    }
+
+    #[test]
+    fn png_checksums() {
+        assert_eq!(crc32(b"IEND".iter()), 0xAE42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+
+        let mut png = Vec::new();
+        Screen::capture(&Bus::new()).write_png(&mut png).unwrap();
+        assert_eq!(&png[..8], &PNG_SIGNATURE);
+        assert_eq!(&png[png.len() - 8..], b"IEND\xAE\x42\x60\x82");
+    }
+}
@@ -0,0 +1,144 @@
+// Copyright of Jordan Werthman (2019).
+
+use crate::instruction::Instruction;
+
+// Models how long instructions take to execute
+//
+// By default the CPU executes an instruction each tick() however fast the
+// frontend calls it and the timers follow the wall-clock. With COSMAC VIP
+// timing each instruction is charged the machine cycles the original
+// interpreter spent on it, the 60Hz timers are decremented every
+// VIP_CYCLES_PER_FRAME cycles and DXYN waits for the vertical blank before
+// drawing, so a ROM runs at its original speed whatever the host. A frontend
+// runs the CPU for VIP_CYCLES_PER_SECOND cycles a second (see Cpu::cycles())
+// and leaves the timers to the CPU.
+//
+// The costs approximate those measured by Laurence Scotford's disassembly of
+// the VIP interpreter (https://laurencescotford.net/2020/07/25/chip-8-on-the-cosmac-vip-instruction-index/)
+// using a single figure where they vary with the values of the operands. The
+// extension opcodes, which the VIP never ran, are charged as arithmetic.
+
+// The 1802 runs at 1.76MHz taking 8 clock cycles per machine cycle.
+pub const VIP_CYCLES_PER_SECOND: f64 = 1_760_640.0 / 8.0;
+pub const VIP_CYCLES_PER_FRAME: u64 = 3668;
+
+// The cycles taken from each frame by the display interrupt (and DMA).
+pub const VIP_INTERRUPT_CYCLES: u64 = 1832;
+
+// The cycles spent fetching and decoding every instruction.
+const VIP_FETCH_CYCLES: u64 = 40;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Timing {
+    #[default]
+    Unlimited, // One instruction per tick() with timers updated by the frontend
+    CosmacVip, // Instructions take COSMAC VIP machine cycles and drive the timers
+}
+
+impl Timing {
+    pub fn from_name(name: &str) -> Option<Timing> {
+        match name {
+            "unlimited" | "none" => Some(Timing::Unlimited),
+            "vip" | "cosmac-vip" => Some(Timing::CosmacVip),
+            _ => None,
+        }
+    }
+}
+
+// The machine cycles the COSMAC VIP interpreter spends executing
+// |instruction| where |skipped| is whether a skip was taken. Any wait for the
+// vertical blank (DXYN) is not included.
+pub fn vip_cycles(instruction: Instruction, skipped: bool) -> u64 {
+    let skip = |cycles: u64| if skipped { cycles + 4 } else { cycles };
+    let execute = match instruction {
+        Instruction::Cls => 3078,
+        Instruction::Ret => 10,
+        Instruction::Sys(_) => 0,
+        Instruction::Jp(_) => 12,
+        Instruction::Call(_) => 26,
+        Instruction::SeByte(..) | Instruction::SneByte(..) => skip(10),
+        Instruction::SeReg(..) | Instruction::SneReg(..) => skip(14),
+        Instruction::LdByte(..) => 6,
+        Instruction::AddByte(..) => 10,
+        Instruction::LdReg(..) => 12,
+        Instruction::Or(..)
+        | Instruction::And(..)
+        | Instruction::Xor(..)
+        | Instruction::AddReg(..)
+        | Instruction::Sub(..)
+        | Instruction::Shr(..)
+        | Instruction::Subn(..)
+        | Instruction::Shl(..) => 44,
+        Instruction::LdI(_) => 12,
+        Instruction::JpV0(_) => 22,
+        Instruction::Rnd(..) => 36,
+        Instruction::Drw(_, _, n) => 26 + 46 * n.max(1) as u64,
+        Instruction::Skp(_) | Instruction::Sknp(_) => skip(14),
+        Instruction::LdVxDt(_) | Instruction::LdDtVx(_) | Instruction::LdStVx(_) => 10,
+        Instruction::LdVxK(_) => 8,
+        Instruction::AddI(_) => 16,
+        Instruction::LdF(_) => 16,
+        Instruction::LdB(_) => 176,
+        Instruction::LdMemVx(x) | Instruction::LdVxMem(x) => 14 + 14 * (x as u64 + 1),
+        _ => 44,
+    };
+    VIP_FETCH_CYCLES + execute
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::bus::Bus;
+    use crate::cpu::Cpu;
+    use crate::platform::Platform;
+
+    fn vip_cpu(source: &str) -> Cpu {
+        let rom = assemble(source).unwrap();
+        let mut cpu = Cpu::new(&rom, Platform::Chip8, Platform::Chip8.default_quirks()).unwrap();
+        cpu.set_timing(Timing::CosmacVip);
+        cpu
+    }
+
+    #[test]
+    fn timers_follow_cycles() {
+        let mut cpu = vip_cpu("LD V0, 60\nLD DT, V0\nloop: JP loop");
+        let mut bus = Bus::new();
+        while cpu.cycles() < 30 * VIP_CYCLES_PER_FRAME {
+            cpu.tick(&mut bus).unwrap();
+        }
+        assert_eq!(bus.delay_timer(), 30);
+    }
+
+    #[test]
+    fn draw_waits_for_vertical_blank() {
+        let mut cpu = vip_cpu("LD V0, 1\nDRW V0, V0, 5");
+        let mut bus = Bus::new();
+        cpu.tick(&mut bus).unwrap();
+        cpu.tick(&mut bus).unwrap();
+        assert_eq!(
+            cpu.cycles(),
+            VIP_CYCLES_PER_FRAME
+                + VIP_INTERRUPT_CYCLES
+                + vip_cycles(Instruction::Drw(0, 0, 5), false)
+        );
+    }
+
+    #[test]
+    fn skips_cost_more_when_taken() {
+        let skip = Instruction::SeByte(0, 0);
+        assert!(vip_cycles(skip, true) > vip_cycles(skip, false));
+    }
+
+    #[test]
+    fn costs_grow_with_operands() {
+        let small = vip_cycles(Instruction::Drw(0, 0, 1), false);
+        let large = vip_cycles(Instruction::Drw(0, 0, 15), false);
+        assert!(large > small);
+        assert_eq!(
+            vip_cycles(Instruction::LdMemVx(0xF), false)
+                - vip_cycles(Instruction::LdMemVx(0), false),
+            15 * 14
+        );
+    }
+}
@@ -0,0 +1,393 @@
+// Copyright of Jordan Werthman (2019).
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::io::Write;
+
+// Models a trace of the instructions executed by the CPU
+//
+// When tracing is started (see Cpu::start_trace()) a Record of the state
+// before each instruction is written as a line of JSON (JSONL) e.g.
+//
+//   {"cycle":0,"pc":512,"opcode":24066,"mnemonic":"LD VA, 0x02",
+//    "v":[0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],"i":0,"sp":0,"dt":0,"st":0}
+//
+// (on one line) where |cycle| counts the instructions executed since the
+// trace started and |sp| is the depth of the stack. Traces of ROMs compiled
+// from Octo source also have the |line| each instruction came from (when
+// given the source map). Traces are compared by
+// first_divergence() on the fields present in both so logs from other
+// emulators can be converted with as many of these fields as they have.
+// Strings such as "0x200" are read as numbers so values may be hexadecimal.
+
+pub struct Record {
+    pub cycle: u64,
+    pub pc: usize,
+    pub opcode: u16,
+    pub mnemonic: String,
+    pub registers: [u8; 16],
+    pub i: usize,
+    pub stack_depth: usize,
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+    // The source line of the instruction (if known).
+    pub line: Option<usize>,
+}
+
+impl Record {
+    pub fn to_json(&self) -> String {
+        let registers: Vec<String> = self.registers.iter().map(|v| v.to_string()).collect();
+        let line = match self.line {
+            Some(line) => format!(",\"line\":{}", line),
+            None => String::new(),
+        };
+        format!(
+            "{{\"cycle\":{},\"pc\":{},\"opcode\":{},\"mnemonic\":\"{}\",\"v\":[{}],\
+             \"i\":{},\"sp\":{},\"dt\":{},\"st\":{}{}}}",
+            self.cycle,
+            self.pc,
+            self.opcode,
+            escape(&self.mnemonic),
+            registers.join(","),
+            self.i,
+            self.stack_depth,
+            self.delay_timer,
+            self.sound_timer,
+            line
+        )
+    }
+}
+
+// Writes records to |writer| until finish(). The first error stops the
+// trace and is returned by finish().
+pub struct Tracer {
+    writer: Box<dyn Write>,
+    // The source line of the instruction at each address (see Program).
+    source_map: BTreeMap<usize, usize>,
+    cycle: u64,
+    error: Option<io::Error>,
+}
+
+impl Tracer {
+    pub fn new(writer: Box<dyn Write>, source_map: BTreeMap<usize, usize>) -> Tracer {
+        Tracer {
+            writer,
+            source_map,
+            cycle: 0,
+            error: None,
+        }
+    }
+
+    // The cycle of the next record.
+    pub fn cycle(&self) -> u64 {
+        self.cycle
+    }
+
+    // The source line of the instruction at |pc| if it is known.
+    pub fn line(&self, pc: usize) -> Option<usize> {
+        self.source_map.get(&pc).cloned()
+    }
+
+    pub fn record(&mut self, record: &Record) {
+        if self.error.is_none() {
+            if let Err(error) = writeln!(self.writer, "{}", record.to_json()) {
+                self.error = Some(error);
+            }
+        }
+        self.cycle += 1;
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(error) => Err(error),
+            None => self.writer.flush(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Value {
+    Number(u64),
+    Text(String),
+    List(Vec<Value>),
+    // true, false, null or a number which is not a whole one.
+    Other(String),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Number(number) => write!(f, "{} (0x{:X})", number, number),
+            Value::Text(text) => write!(f, "\"{}\"", text),
+            Value::List(values) => {
+                let values: Vec<String> = values.iter().map(|value| value.to_string()).collect();
+                write!(f, "[{}]", values.join(", "))
+            }
+            Value::Other(text) => write!(f, "{}", text),
+        }
+    }
+}
+
+// Parses a line holding a JSON object whose values are not objects.
+pub fn parse_line(line: &str) -> Result<BTreeMap<String, Value>, String> {
+    let mut parser = Parser {
+        chars: line.chars().collect(),
+        position: 0,
+    };
+    let mut fields = BTreeMap::new();
+    parser.expect('{')?;
+    if !parser.next_is('}') {
+        loop {
+            let key = parser.string()?;
+            parser.expect(':')?;
+            fields.insert(key, parser.value()?);
+            if parser.next_is('}') {
+                break;
+            }
+            parser.expect(',')?;
+        }
+    }
+    parser.expect('}')?;
+    if parser.peek().is_some() {
+        return Err(parser.error("expected the end of the line"));
+    }
+    Ok(fields)
+}
+
+// Where two traces first differ.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Divergence {
+    // The index of the record (and line) in both traces.
+    pub index: usize,
+    // The fields which differ with their values in each trace. Empty if one
+    // trace ended before the other.
+    pub fields: Vec<(String, Value, Value)>,
+}
+
+// Finds the first record where |first| and |second| differ in a field they
+// both have other than those in |ignore|.
+pub fn first_divergence(
+    first: &[BTreeMap<String, Value>],
+    second: &[BTreeMap<String, Value>],
+    ignore: &[String],
+) -> Option<Divergence> {
+    for (index, (a, b)) in first.iter().zip(second.iter()).enumerate() {
+        let fields: Vec<(String, Value, Value)> = a
+            .iter()
+            .filter(|(key, _)| !ignore.contains(key))
+            .filter_map(|(key, value)| {
+                let other = b.get(key)?;
+                if value == other {
+                    None
+                } else {
+                    Some((key.clone(), value.clone(), other.clone()))
+                }
+            })
+            .collect();
+        if !fields.is_empty() {
+            return Some(Divergence { index, fields });
+        }
+    }
+    if first.len() != second.len() {
+        return Some(Divergence {
+            index: first.len().min(second.len()),
+            fields: Vec::new(),
+        });
+    }
+    None
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+}
+
+impl Parser {
+    fn error(&self, message: &str) -> String {
+        format!("column {}: {}", self.position + 1, message)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self
+            .chars
+            .get(self.position)
+            .is_some_and(|c| c.is_whitespace())
+        {
+            self.position += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_whitespace();
+        self.chars.get(self.position).cloned()
+    }
+
+    fn next_is(&mut self, c: char) -> bool {
+        self.peek() == Some(c)
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        if self.next_is(c) {
+            self.position += 1;
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected '{}'", c)))
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut text = String::new();
+        loop {
+            let c = match self.chars.get(self.position) {
+                Some(&c) => c,
+                None => return Err(self.error("unterminated string")),
+            };
+            self.position += 1;
+            match c {
+                '"' => return Ok(text),
+                '\\' => {
+                    let escaped = self.chars.get(self.position).cloned();
+                    self.position += 1;
+                    match escaped {
+                        Some('n') => text.push('\n'),
+                        Some('t') => text.push('\t'),
+                        Some(c @ ('"' | '\\' | '/')) => text.push(c),
+                        _ => return Err(self.error("unsupported escape")),
+                    }
+                }
+                c => text.push(c),
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        match self.peek() {
+            Some('"') => {
+                let text = self.string()?;
+                let hex = text
+                    .strip_prefix("0x")
+                    .or_else(|| text.strip_prefix("0X"))
+                    .and_then(|hex| u64::from_str_radix(hex, 16).ok());
+                Ok(hex.map(Value::Number).unwrap_or(Value::Text(text)))
+            }
+            Some('[') => {
+                self.position += 1;
+                let mut values = Vec::new();
+                if !self.next_is(']') {
+                    loop {
+                        values.push(self.value()?);
+                        if self.next_is(']') {
+                            break;
+                        }
+                        self.expect(',')?;
+                    }
+                }
+                self.expect(']')?;
+                Ok(Value::List(values))
+            }
+            Some(_) => {
+                let start = self.position;
+                while self
+                    .chars
+                    .get(self.position)
+                    .is_some_and(|c| c.is_alphanumeric() || "+-.".contains(*c))
+                {
+                    self.position += 1;
+                }
+                if start == self.position {
+                    return Err(self.error("expected a value"));
+                }
+                let text: String = self.chars[start..self.position].iter().collect();
+                Ok(text
+                    .parse()
+                    .map(Value::Number)
+                    .unwrap_or(Value::Other(text)))
+            }
+            None => Err(self.error("expected a value")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(pc: usize) -> Record {
+        Record {
+            cycle: 3,
+            pc,
+            opcode: 0x6A02,
+            mnemonic: "LD VA, 0x02".to_string(),
+            registers: [7; 16],
+            i: 0x300,
+            stack_depth: 1,
+            delay_timer: 2,
+            sound_timer: 0,
+            line: None,
+        }
+    }
+
+    #[test]
+    fn records_parse_back() {
+        let fields = parse_line(&record(0x200).to_json()).unwrap();
+        assert_eq!(fields["pc"], Value::Number(0x200));
+        assert_eq!(fields["mnemonic"], Value::Text("LD VA, 0x02".to_string()));
+        assert_eq!(fields["v"], Value::List(vec![Value::Number(7); 16]));
+        assert_eq!(fields.len(), 9);
+
+        let traced = Record {
+            line: Some(12),
+            ..record(0x200)
+        };
+        let fields = parse_line(&traced.to_json()).unwrap();
+        assert_eq!(fields["line"], Value::Number(12));
+        assert_eq!(fields.len(), 10);
+
+        let other = parse_line(r#"{ "pc": "0x200", "flag": true, "s": "a\"b" }"#).unwrap();
+        assert_eq!(other["pc"], Value::Number(0x200));
+        assert_eq!(other["flag"], Value::Other("true".to_string()));
+        assert_eq!(other["s"], Value::Text("a\"b".to_string()));
+        assert!(parse_line(r#"{"pc": 1"#).is_err());
+        assert!(parse_line(r#"{"pc": 1} x"#).is_err());
+    }
+
+    #[test]
+    fn finds_first_divergence() {
+        let trace = |pcs: &[usize]| -> Vec<BTreeMap<String, Value>> {
+            pcs.iter()
+                .map(|&pc| parse_line(&record(pc).to_json()).unwrap())
+                .collect()
+        };
+        let first = trace(&[0x200, 0x202, 0x204]);
+        assert_eq!(first_divergence(&first, &first, &[]), None);
+
+        let second = trace(&[0x200, 0x202, 0x206]);
+        let divergence = first_divergence(&first, &second, &[]).unwrap();
+        assert_eq!(divergence.index, 2);
+        assert_eq!(
+            divergence.fields,
+            vec![("pc".to_string(), Value::Number(0x204), Value::Number(0x206))]
+        );
+        assert_eq!(first_divergence(&first, &second, &["pc".to_string()]), None);
+
+        // Only the fields present in both are compared.
+        let mut partial = parse_line(r#"{"pc": "0x200"}"#).unwrap();
+        assert_eq!(first_divergence(&first[..1], &[partial.clone()], &[]), None);
+        partial.insert("i".to_string(), Value::Number(0));
+        assert_eq!(
+            first_divergence(&first[..1], &[partial], &[])
+                .unwrap()
+                .index,
+            0
+        );
+
+        let shorter = first_divergence(&first, &first[..2], &[]).unwrap();
+        assert_eq!((shorter.index, shorter.fields.len()), (2, 0));
+    }
+}
@@ -0,0 +1,421 @@
+// Copyright of Jordan Werthman (2019).
+
+mod reference;
+
+use std::env;
+use std::fmt;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+
+use rust8::decode;
+use rust8::Bus;
+use rust8::Cpu;
+use rust8::Platform;
+use rust8::Quirks;
+use rust8::RandomSource;
+use rust8::TickOutcome;
+use rust8::Xorshift;
+
+use reference::{Outcome, Reference};
+
+// Runs random programs on the CPU and on the reference model in
+// tests/reference/ comparing their state after every instruction. Each case
+// is generated from its own seed so a failure can be run again alone; it is
+// first minimized (dropping instructions, registers and data which are not
+// needed to see the difference) and printed as a reproducer.
+//
+// RUST8_FUZZ_CASES sets the number of cases (for a longer run) and
+// RUST8_FUZZ_SEED the seed of the first case.
+
+const DEFAULT_CASES: u64 = 2000;
+const DEFAULT_SEED: u64 = 0x5EED;
+const PROGRAM_LENGTH: usize = 24;
+const DATA_LENGTH: usize = 32;
+const STEPS: usize = 64;
+const NOP: u16 = 0x8000; // V0 = V0
+
+#[derive(Clone, Debug, PartialEq)]
+struct Case {
+    seed: u64,
+    program: Vec<u16>,
+    // Bytes loaded after the program for instructions which read memory.
+    data: Vec<u8>,
+    registers: [u8; 16],
+    i: usize,
+    // The keys held down (bit N is key N).
+    keys: u16,
+    steps: usize,
+}
+
+impl Case {
+    fn generate(seed: u64) -> Case {
+        let mut random = Generator(Xorshift::new(seed));
+        let mut registers = [0; 16];
+        for register in registers.iter_mut() {
+            *register = random.byte();
+        }
+        Case {
+            seed,
+            program: (0..PROGRAM_LENGTH).map(|_| random.opcode()).collect(),
+            data: (0..DATA_LENGTH).map(|_| random.byte()).collect(),
+            registers,
+            i: random.address(),
+            keys: random.word() & random.word(),
+            steps: STEPS,
+        }
+    }
+
+    fn rom(&self) -> Vec<u8> {
+        let mut rom: Vec<u8> = self
+            .program
+            .iter()
+            .flat_map(|op| op.to_be_bytes())
+            .collect();
+        rom.extend_from_slice(&self.data);
+        rom
+    }
+}
+
+impl fmt::Display for Case {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "  registers: {:02X?}", self.registers)?;
+        writeln!(
+            f,
+            "  I: 0x{:03X}, keys: 0x{:04X}, CXNN seed: {}",
+            self.i, self.keys, self.seed
+        )?;
+        writeln!(f, "  program:")?;
+        for (index, &opcode) in self.program.iter().enumerate() {
+            writeln!(
+                f,
+                "    0x{:03X}: {:04X}  {}",
+                0x200 + index * 2,
+                opcode,
+                decode(opcode)
+            )?;
+        }
+        write!(f, "  data: {:02X?}", self.data)
+    }
+}
+
+// Draws the parts of a case from a seeded Xorshift.
+struct Generator(Xorshift);
+
+impl Generator {
+    fn byte(&mut self) -> u8 {
+        self.0.next_byte()
+    }
+
+    fn word(&mut self) -> u16 {
+        (self.byte() as u16) << 8 | self.byte() as u16
+    }
+
+    fn below(&mut self, limit: usize) -> usize {
+        self.word() as usize % limit
+    }
+
+    fn nibble(&mut self) -> u16 {
+        self.byte() as u16 & 0xF
+    }
+
+    // An address within the program or its data (where most jumps and
+    // memory accesses should land to keep the case interesting).
+    fn address(&mut self) -> usize {
+        0x200 + self.below(PROGRAM_LENGTH * 2 + DATA_LENGTH)
+    }
+
+    // An instruction from the original set with random operands. A few are
+    // entirely random words to exercise the unknown instructions.
+    fn opcode(&mut self) -> u16 {
+        let (x, y) = (self.nibble() << 8, self.nibble() << 4);
+        let nn = self.byte() as u16;
+        let target = (0x200 + self.below(PROGRAM_LENGTH) * 2) as u16;
+        match self.below(36) {
+            0 => 0x00E0,
+            1 => 0x00EE,
+            2 => 0x1000 | target,
+            3 => 0x2000 | target,
+            4 => 0x3000 | x | nn,
+            5 => 0x4000 | x | nn,
+            6 => 0x5000 | x | y,
+            7 => 0x6000 | x | nn,
+            8 => 0x7000 | x | nn,
+            9..=17 => {
+                let n = [0x0, 0x1, 0x2, 0x3, 0x4, 0x5, 0x6, 0x7, 0xE][self.below(9)];
+                0x8000 | x | y | n
+            }
+            18 => 0x9000 | x | y,
+            19 => 0xA000 | self.address() as u16,
+            20 => 0xB000 | (target - self.below(4) as u16),
+            21 => 0xC000 | x | nn,
+            22 | 23 => 0xD000 | x | y | self.nibble(),
+            24 => 0xE09E | x,
+            25 => 0xE0A1 | x,
+            26 => 0xF007 | x,
+            27 => 0xF00A | x,
+            28 => 0xF015 | x,
+            29 => 0xF018 | x,
+            30 => 0xF01E | x,
+            31 => 0xF029 | x,
+            32 => 0xF033 | x,
+            33 => 0xF055 | x,
+            34 => 0xF065 | x,
+            _ => self.word(),
+        }
+    }
+}
+
+// The part of the state in which a mismatch was found.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Difference {
+    Panic,
+    Outcome,
+    Pc,
+    Registers,
+    I,
+    Stack,
+    Memory,
+    Display,
+    Timers,
+}
+
+// Where the CPU first disagreed with the reference.
+#[derive(Debug)]
+struct Mismatch {
+    step: usize,
+    difference: Difference,
+    description: String,
+}
+
+// Runs |case| on both interpreters returning the first difference.
+fn run(case: &Case) -> Option<Mismatch> {
+    let mut cpu = Cpu::new(&case.rom(), Platform::Chip8, Quirks::modern()).unwrap();
+    cpu.seed_random(case.seed);
+    cpu.set_i(case.i);
+    let mut bus = Bus::new();
+
+    let mut random = Xorshift::new(case.seed);
+    let mut reference = Reference::new(cpu.memory(), Box::new(move || random.next_byte()));
+    reference.i = case.i;
+    for (x, &value) in case.registers.iter().enumerate() {
+        cpu.set_register(x, value);
+        reference.v[x] = value;
+    }
+    for key in (0..16).filter(|key| case.keys & 1 << key != 0) {
+        bus.set_key(key);
+        reference.keys[key] = true;
+    }
+
+    for step in 0..case.steps {
+        let expected = reference.step();
+        if expected == Outcome::Undefined {
+            return None;
+        }
+        let mismatch = |(difference, description)| {
+            Some(Mismatch {
+                step,
+                difference,
+                description,
+            })
+        };
+        let actual = match panic::catch_unwind(AssertUnwindSafe(|| cpu.tick(&mut bus))) {
+            Ok(actual) => actual,
+            Err(_) => return mismatch((Difference::Panic, "the CPU panicked".to_string())),
+        };
+        match (expected, &actual) {
+            (Outcome::Executed, Ok(TickOutcome::Executed))
+            | (Outcome::WaitingForKey, Ok(TickOutcome::WaitingForKey)) => (),
+            (Outcome::Error, Err(_)) => return None,
+            _ => {
+                let description =
+                    format!("the reference gave {:?} but the CPU {:?}", expected, actual);
+                return mismatch((Difference::Outcome, description));
+            }
+        }
+        if let Err(difference) = compare(&reference, &cpu, &bus) {
+            return mismatch(difference);
+        }
+    }
+    None
+}
+
+// Describes the first difference between the state of the interpreters.
+fn compare(reference: &Reference, cpu: &Cpu, bus: &Bus) -> Result<(), (Difference, String)> {
+    if cpu.pc() != reference.pc {
+        return Err((
+            Difference::Pc,
+            format!("PC is 0x{:03X} not 0x{:03X}", cpu.pc(), reference.pc),
+        ));
+    }
+    if cpu.registers() != reference.v {
+        return Err((
+            Difference::Registers,
+            format!(
+                "registers are {:02X?} not {:02X?}",
+                cpu.registers(),
+                reference.v
+            ),
+        ));
+    }
+    if cpu.i() != reference.i {
+        return Err((
+            Difference::I,
+            format!("I is 0x{:03X} not 0x{:03X}", cpu.i(), reference.i),
+        ));
+    }
+    if cpu.stack() != reference.stack {
+        return Err((
+            Difference::Stack,
+            format!("stack is {:03X?} not {:03X?}", cpu.stack(), reference.stack),
+        ));
+    }
+    // Below 0x200 the CPU also holds its stack.
+    let (actual, expected) = (&cpu.memory()[0x200..], &reference.memory[0x200..]);
+    if let Some(offset) = first_difference(actual, expected) {
+        return Err((
+            Difference::Memory,
+            format!(
+                "memory at 0x{:03X} is 0x{:02X} not 0x{:02X}",
+                0x200 + offset,
+                actual[offset],
+                expected[offset]
+            ),
+        ));
+    }
+    if let Some(index) = first_difference(bus.display(), &reference.display) {
+        return Err((
+            Difference::Display,
+            format!(
+                "pixel ({}, {}) differs",
+                index % reference::WIDTH,
+                index / reference::WIDTH
+            ),
+        ));
+    }
+    let timers = (bus.delay_timer(), bus.sound_timer());
+    if timers != (reference.delay_timer, reference.sound_timer) {
+        return Err((
+            Difference::Timers,
+            format!(
+                "timers are {:?} not {:?}",
+                timers,
+                (reference.delay_timer, reference.sound_timer)
+            ),
+        ));
+    }
+    Ok(())
+}
+
+fn first_difference(actual: &[u8], expected: &[u8]) -> Option<usize> {
+    if actual == expected {
+        return None;
+    }
+    actual.iter().zip(expected.iter()).position(|(a, e)| a != e)
+}
+
+// Simplifies |case| for as long as it still |fails|: by stopping earlier,
+// removing instructions (or replacing them with a NOP if their addresses
+// matter), dropping data and clearing registers and keys.
+fn minimize(mut case: Case, fails: impl Fn(&Case) -> Option<usize>) -> Case {
+    loop {
+        let before = case.clone();
+        if let Some(step) = fails(&case) {
+            case.steps = step + 1;
+        }
+
+        let mut candidates = Vec::new();
+        for index in 0..case.program.len() {
+            let mut removed = case.clone();
+            removed.program.remove(index);
+            candidates.push(removed);
+            if case.program[index] != NOP {
+                let mut replaced = case.clone();
+                replaced.program[index] = NOP;
+                candidates.push(replaced);
+            }
+        }
+        for length in 0..case.data.len() {
+            let mut truncated = case.clone();
+            truncated.data.truncate(length);
+            candidates.push(truncated);
+        }
+        for x in (0..16).filter(|&x| case.registers[x] != 0) {
+            let mut cleared = case.clone();
+            cleared.registers[x] = 0;
+            candidates.push(cleared);
+        }
+        if case.keys != 0 {
+            candidates.push(Case {
+                keys: 0,
+                ..case.clone()
+            });
+        }
+
+        if let Some(simpler) = candidates
+            .into_iter()
+            .find(|candidate| fails(candidate).is_some())
+        {
+            case = simpler;
+        }
+        if case == before {
+            return case;
+        }
+    }
+}
+
+fn env_or(name: &str, default: u64) -> u64 {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+#[test]
+fn cpu_matches_reference() {
+    let first = env_or("RUST8_FUZZ_SEED", DEFAULT_SEED);
+    let cases = env_or("RUST8_FUZZ_CASES", DEFAULT_CASES);
+    // Panics in the CPU are reported as mismatches so their messages are
+    // hidden until the result is known.
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    for seed in first..first + cases {
+        let case = Case::generate(seed);
+        if let Some(mismatch) = run(&case) {
+            let same = |case: &Case| {
+                run(case)
+                    .filter(|other| other.difference == mismatch.difference)
+                    .map(|other| other.step)
+            };
+            let case = minimize(case, same);
+            let minimized = run(&case).unwrap();
+            panic::set_hook(hook);
+            panic!(
+                "case {} differs from the reference at step {}: {}\n\
+                 minimized to differ at step {}: {}\n{}\n\
+                 rerun with RUST8_FUZZ_SEED={} RUST8_FUZZ_CASES=1",
+                seed,
+                mismatch.step,
+                mismatch.description,
+                minimized.step,
+                minimized.description,
+                case,
+                seed
+            );
+        }
+    }
+    panic::set_hook(hook);
+}
+
+#[test]
+fn minimize_keeps_what_fails() {
+    let mut case = Case::generate(1);
+    case.program[5] = 0x8F14;
+    let fails = |case: &Case| case.program.iter().position(|&op| op == 0x8F14);
+
+    let case = minimize(case, fails);
+    assert_eq!(case.program, vec![0x8F14]);
+    assert!(case.data.is_empty());
+    assert_eq!(case.registers, [0; 16]);
+    assert_eq!(case.keys, 0);
+    assert_eq!(case.steps, 1);
+}
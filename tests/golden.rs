@@ -0,0 +1,235 @@
+// Copyright of Jordan Werthman (2019).
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use rust8::compile;
+use rust8::Config;
+use rust8::Machine;
+use rust8::Platform;
+use rust8::Quirks;
+use rust8::Screen;
+
+// Runs the test ROMs in tests/roms/ (Octo sources modelled on the community
+// CHIP-8 test suite) for a fixed number of frames and compares the display
+// with the golden image saved in tests/golden/. Most ROMs draw a tick or a
+// cross for each of their checks so a failure shows which one went wrong.
+//
+// To accept a change to the display run the tests with RUST8_BLESS=1 which
+// rewrites the golden images instead of comparing them.
+
+const BLESS_VARIABLE: &str = "RUST8_BLESS";
+
+struct Case {
+    rom: &'static str,
+    // The golden image (defaults to the ROM name).
+    golden: Option<&'static str>,
+    platform: Platform,
+    quirks: Option<Quirks>,
+    frames: usize,
+    // (frame, key, pressed) applied before the frame runs.
+    keys: &'static [(usize, u8, bool)],
+}
+
+impl Case {
+    fn new(rom: &'static str) -> Case {
+        Case {
+            rom,
+            golden: None,
+            platform: Platform::Chip8,
+            quirks: None,
+            frames: 60,
+            keys: &[],
+        }
+    }
+}
+
+struct Run {
+    screen: Screen,
+    // The number of frames during which the buzzer sounded.
+    sound_frames: usize,
+}
+
+fn run(case: &Case) -> Run {
+    let path = test_path("roms", case.rom, "8o");
+    let source = fs::read_to_string(&path).unwrap();
+    let program = compile(&source, case.platform)
+        .unwrap_or_else(|error| panic!("{}: {}", path.display(), error));
+
+    let mut config = Config::new(case.platform);
+    config.quirks = case.quirks.unwrap_or(config.quirks);
+    config.seed = Some(0);
+    let mut machine = Machine::new(config);
+    machine.load_rom(&program.rom).unwrap();
+
+    let mut sound_frames = 0;
+    for frame in 0..case.frames {
+        for &(_, key, pressed) in case.keys.iter().filter(|event| event.0 == frame) {
+            if pressed {
+                machine.press_key(key);
+            } else {
+                machine.release_key(key);
+            }
+        }
+        let report = machine.run_frame();
+        if let Err(error) = report.outcome {
+            panic!("{} stopped in frame {}: {}", case.rom, frame, error);
+        }
+        sound_frames += report.sound_on as usize;
+    }
+
+    Run {
+        screen: Screen::capture(machine.bus()),
+        sound_frames,
+    }
+}
+
+// Runs |case| and panics with a diff if the display differs from its golden
+// image (or saves it as the golden image when blessing).
+fn check(case: Case) -> Run {
+    let run = run(&case);
+    let path = test_path("golden", case.golden.unwrap_or(case.rom), "txt");
+    if env::var_os(BLESS_VARIABLE).is_some() {
+        fs::write(&path, run.screen.to_string()).unwrap();
+        return run;
+    }
+
+    let text = fs::read_to_string(&path).unwrap_or_else(|error| {
+        panic!(
+            "{}: {} (run with {}=1 to create it)",
+            path.display(),
+            error,
+            BLESS_VARIABLE
+        )
+    });
+    let expected =
+        Screen::parse(&text).unwrap_or_else(|| panic!("{}: not a valid screen", path.display()));
+    if expected != run.screen {
+        panic!(
+            "{} does not match {}:\n{}",
+            case.rom,
+            path.display(),
+            diff(&expected, &run.screen)
+        );
+    }
+    run
+}
+
+fn test_path(directory: &str, name: &str, extension: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join(directory)
+        .join(name)
+        .with_extension(extension)
+}
+
+// Describes how |actual| differs from |expected| by printing each row which
+// differs as expected (-) and actual (+) with the changed pixels marked (^).
+fn diff(expected: &Screen, actual: &Screen) -> String {
+    if (expected.width(), expected.height()) != (actual.width(), actual.height()) {
+        return format!(
+            "expected a {}x{} display but it was {}x{}:\n{}",
+            expected.width(),
+            expected.height(),
+            actual.width(),
+            actual.height(),
+            actual
+        );
+    }
+
+    let expected_rows: Vec<String> = expected.to_string().lines().map(String::from).collect();
+    let actual_rows: Vec<String> = actual.to_string().lines().map(String::from).collect();
+    let mut output = String::new();
+    let mut pixels = 0;
+    for (y, (before, after)) in expected_rows.iter().zip(actual_rows.iter()).enumerate() {
+        if before == after {
+            continue;
+        }
+        let marks: String = (0..actual.width())
+            .map(|x| {
+                if expected.pixel(x, y) == actual.pixel(x, y) {
+                    ' '
+                } else {
+                    pixels += 1;
+                    '^'
+                }
+            })
+            .collect();
+        output += &format!(
+            "row {:2} - {}\n       + {}\n         {}\n",
+            y, before, after, marks
+        );
+    }
+    format!("{}{} pixels differ", output, pixels)
+}
+
+#[test]
+fn splash() {
+    check(Case::new("splash"));
+}
+
+#[test]
+fn opcodes() {
+    check(Case::new("opcodes"));
+}
+
+#[test]
+fn flags() {
+    check(Case::new("flags"));
+}
+
+#[test]
+fn quirks() {
+    let quirks = [
+        ("quirks-modern", Quirks::modern()),
+        ("quirks-vip", Quirks::cosmac_vip()),
+        ("quirks-chip48", Quirks::chip48()),
+        ("quirks-superchip", Quirks::superchip()),
+    ];
+    for &(golden, quirks) in quirks.iter() {
+        check(Case {
+            golden: Some(golden),
+            quirks: Some(quirks),
+            ..Case::new("quirks")
+        });
+    }
+}
+
+#[test]
+fn keypad() {
+    check(Case {
+        keys: &[
+            (5, 0x5, true),
+            (6, 0x5, false),
+            (10, 0x7, true),
+            (20, 0x7, false),
+        ],
+        ..Case::new("keypad")
+    });
+}
+
+#[test]
+fn beep() {
+    let run = check(Case::new("beep"));
+    // The sound timer is first decremented at the end of the frame setting it.
+    assert_eq!(run.sound_frames, 29);
+}
+
+#[test]
+fn superchip() {
+    check(Case {
+        platform: Platform::SuperChip,
+        ..Case::new("superchip")
+    });
+}
+
+#[test]
+fn diff_marks_changed_pixels() {
+    let expected = Screen::parse("____\n_##_\n").unwrap();
+    let actual = Screen::parse("____\n_#_#\n").unwrap();
+    assert_eq!(
+        diff(&expected, &actual),
+        "row  1 - _##_\n       + _#_#\n           ^^\n2 pixels differ"
+    );
+}
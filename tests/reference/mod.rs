@@ -0,0 +1,217 @@
+// Copyright of Jordan Werthman (2019).
+
+// Models a reference CHIP-8 interpreter to check the CPU against
+//
+// This is written to be obviously correct rather than fast or complete: it
+// only runs the original instruction set with the modern quirks (shifts use
+// VX, FX55/FX65 leave I alone, BNNN adds V0, sprites wrap) and keeps its
+// stack apart from memory. Behaviour it does not model is reported as
+// Undefined so that the fuzzer stops comparing rather than guessing.
+
+pub const WIDTH: usize = 64;
+pub const HEIGHT: usize = 32;
+pub const STACK_DEPTH: usize = 16;
+
+// The CPU keeps its stack in memory here so accesses to it are not modelled.
+const STACK_MEMORY: std::ops::Range<usize> = 0x50..0x70;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Executed,
+    WaitingForKey,
+    Error,
+    Undefined,
+}
+
+pub struct Reference {
+    pub v: [u8; 16],
+    pub i: usize,
+    pub pc: usize,
+    pub stack: Vec<usize>,
+    pub memory: Vec<u8>,
+    // A byte per pixel (0 or 1) as held by the bus.
+    pub display: Vec<u8>,
+    pub keys: [bool; 16],
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+    // Returns the random byte used by the next CXNN.
+    pub random: Box<dyn FnMut() -> u8>,
+}
+
+impl Reference {
+    // Starts at 0x200 with a copy of the CPU's initial |memory| (for its
+    // fonts) and random bytes from |random|.
+    pub fn new(memory: &[u8], random: Box<dyn FnMut() -> u8>) -> Reference {
+        Reference {
+            v: [0; 16],
+            i: 0,
+            pc: 0x200,
+            stack: Vec::new(),
+            memory: memory.to_vec(),
+            display: vec![0; WIDTH * HEIGHT],
+            keys: [false; 16],
+            delay_timer: 0,
+            sound_timer: 0,
+            random,
+        }
+    }
+
+    pub fn step(&mut self) -> Outcome {
+        if self.pc + 1 >= self.memory.len() {
+            return Outcome::Error;
+        }
+        if self.pc + 2 > STACK_MEMORY.start && self.pc < STACK_MEMORY.end {
+            return Outcome::Undefined;
+        }
+        let opcode = (self.memory[self.pc] as u16) << 8 | self.memory[self.pc + 1] as u16;
+        let x = (opcode >> 8 & 0xF) as usize;
+        let y = (opcode >> 4 & 0xF) as usize;
+        let n = (opcode & 0xF) as usize;
+        let nn = (opcode & 0xFF) as u8;
+        let nnn = (opcode & 0xFFF) as usize;
+
+        let mut next = self.pc + 2;
+        match (opcode >> 12, n) {
+            _ if opcode == 0x00E0 => self.display = vec![0; WIDTH * HEIGHT],
+            _ if opcode == 0x00EE => match self.stack.pop() {
+                Some(address) => next = address,
+                None => return Outcome::Error,
+            },
+            (0x1, _) => next = nnn,
+            (0x2, _) => {
+                if self.stack.len() == STACK_DEPTH {
+                    return Outcome::Error;
+                }
+                self.stack.push(self.pc + 2);
+                next = nnn;
+            }
+            (0x3, _) if self.v[x] == nn => next += 2,
+            (0x3, _) => (),
+            (0x4, _) if self.v[x] != nn => next += 2,
+            (0x4, _) => (),
+            (0x5, 0) if self.v[x] == self.v[y] => next += 2,
+            (0x5, 0) => (),
+            (0x6, _) => self.v[x] = nn,
+            (0x7, _) => self.v[x] = self.v[x].wrapping_add(nn),
+            (0x8, 0x0) => self.v[x] = self.v[y],
+            (0x8, 0x1) => self.v[x] |= self.v[y],
+            (0x8, 0x2) => self.v[x] &= self.v[y],
+            (0x8, 0x3) => self.v[x] ^= self.v[y],
+            (0x8, 0x4) => {
+                let sum = self.v[x] as u16 + self.v[y] as u16;
+                self.v[x] = sum as u8;
+                self.v[0xF] = (sum > 0xFF) as u8;
+            }
+            (0x8, 0x5) => {
+                let flag = (self.v[x] >= self.v[y]) as u8;
+                self.v[x] = self.v[x].wrapping_sub(self.v[y]);
+                self.v[0xF] = flag;
+            }
+            (0x8, 0x6) => {
+                let flag = self.v[x] & 1;
+                self.v[x] >>= 1;
+                self.v[0xF] = flag;
+            }
+            (0x8, 0x7) => {
+                let flag = (self.v[y] >= self.v[x]) as u8;
+                self.v[x] = self.v[y].wrapping_sub(self.v[x]);
+                self.v[0xF] = flag;
+            }
+            (0x8, 0xE) => {
+                let flag = self.v[x] >> 7;
+                self.v[x] <<= 1;
+                self.v[0xF] = flag;
+            }
+            (0x9, 0) if self.v[x] != self.v[y] => next += 2,
+            (0x9, 0) => (),
+            (0xA, _) => self.i = nnn,
+            (0xB, _) => next = nnn + self.v[0] as usize,
+            (0xC, _) => self.v[x] = (self.random)() & nn,
+            (0xD, _) => {
+                if let Some(outcome) = self.check(n) {
+                    return outcome;
+                }
+                self.v[0xF] = self.draw(self.v[x] as usize, self.v[y] as usize, n) as u8;
+            }
+            (0xE, _) if nn == 0x9E && self.key(x) => next += 2,
+            (0xE, _) if nn == 0xA1 && !self.key(x) => next += 2,
+            (0xE, _) if nn == 0x9E || nn == 0xA1 => (),
+            (0xF, _) => match nn {
+                0x07 => self.v[x] = self.delay_timer,
+                0x0A => match self.keys.iter().position(|&pressed| pressed) {
+                    Some(key) => self.v[x] = key as u8,
+                    None => return Outcome::WaitingForKey,
+                },
+                0x15 => self.delay_timer = self.v[x],
+                0x18 => self.sound_timer = self.v[x],
+                0x1E => self.i += self.v[x] as usize,
+                0x29 => self.i = (self.v[x] & 0xF) as usize * 5,
+                0x33 => {
+                    let digits = [self.v[x] / 100, self.v[x] / 10 % 10, self.v[x] % 10];
+                    if let Some(outcome) = self.store(&digits) {
+                        return outcome;
+                    }
+                }
+                0x55 => {
+                    let registers = self.v[..=x].to_vec();
+                    if let Some(outcome) = self.store(&registers) {
+                        return outcome;
+                    }
+                }
+                0x65 => {
+                    if let Some(outcome) = self.check(x + 1) {
+                        return outcome;
+                    }
+                    self.v[..=x].copy_from_slice(&self.memory[self.i..=self.i + x]);
+                }
+                _ => return Outcome::Error,
+            },
+            _ => return Outcome::Error,
+        }
+        self.pc = next;
+        Outcome::Executed
+    }
+
+    fn key(&self, x: usize) -> bool {
+        self.keys.get(self.v[x] as usize) == Some(&true)
+    }
+
+    // The outcome if |len| bytes from I fall outside of memory (Error) or on
+    // the CPU's stack (Undefined).
+    fn check(&self, len: usize) -> Option<Outcome> {
+        let range = self.i..self.i + len;
+        if range.end > self.memory.len() {
+            Some(Outcome::Error)
+        } else if range.start < STACK_MEMORY.end && STACK_MEMORY.start < range.end {
+            Some(Outcome::Undefined)
+        } else {
+            None
+        }
+    }
+
+    fn store(&mut self, bytes: &[u8]) -> Option<Outcome> {
+        let outcome = self.check(bytes.len());
+        if outcome.is_none() {
+            self.memory[self.i..self.i + bytes.len()].copy_from_slice(bytes);
+        }
+        outcome
+    }
+
+    // XORs an 8xN sprite from I onto the display at (x, y) wrapping at the
+    // edges. Returns whether any pixel was turned off.
+    fn draw(&mut self, x: usize, y: usize, n: usize) -> bool {
+        let mut collision = false;
+        for row in 0..n {
+            let byte = self.memory[self.i + row];
+            for column in 0..8 {
+                if byte & (0x80 >> column) == 0 {
+                    continue;
+                }
+                let index = (y + row) % HEIGHT * WIDTH + (x + column) % WIDTH;
+                collision |= self.display[index] == 1;
+                self.display[index] ^= 1;
+            }
+        }
+        collision
+    }
+}
@@ -19,11 +19,21 @@ chip8.load(load_rom());
 var canvas = document.getElementById("gameView");
 var ctx = canvas.getContext("2d");
 
-let pixel_width = Math.floor(canvas.width / 64);
-let pixel_height = Math.floor(canvas.height / 32);
+// The display resolution changes when SUPER-CHIP ROMs toggle hires mode:
+var screen_width, screen_height, pixel_width, pixel_height, window_padx, window_pady;
 
-var window_padx = (canvas.width - pixel_width * 64) / 2;
-var window_pady = (canvas.height - pixel_height * 32) / 2;
+function update_resolution() {
+  screen_width = chip8.width();
+  screen_height = chip8.height();
+
+  pixel_width = Math.floor(canvas.width / screen_width);
+  pixel_height = Math.floor(canvas.height / screen_height);
+
+  window_padx = (canvas.width - pixel_width * screen_width) / 2;
+  window_pady = (canvas.height - pixel_height * screen_height) / 2;
+}
+
+update_resolution();
 
 function build_grad(ctx, x, y, radius) {
   let grd = ctx.createRadialGradient(x, y, radius * 0.03, x, y, radius * 0.9);
@@ -34,21 +44,47 @@ function build_grad(ctx, x, y, radius) {
 
 let use_window_gradient = true;
 
+// Colours for the second XO-CHIP bitplane and both planes overlapping; the
+// first plane is drawn with the CRT gradient.
+const palette = ["#000000", null, "#F29900", "#F2F2D9"];
+
 let wingrd = build_grad(ctx, canvas.width / 2, canvas.height / 2, canvas.width);
 var previous_time = new Date().getTime();
 
+function show_error(message) {
+  ctx.fillStyle = "#F20000";
+  ctx.font = "16px monospace";
+  ctx.fillText(message, window_padx + 8, window_pady + 24);
+}
+
 function run_loop() {
   chip8.tick();
 
+  let error = chip8.error();
+  if (error !== undefined) {
+    show_error(error);
+    return;
+  }
+
+  if (chip8.width() != screen_width || chip8.height() != screen_height) {
+    ctx.fillStyle = "#000000";
+    ctx.fillRect(0, 0, canvas.width, canvas.height);
+    update_resolution();
+  }
+
   ctx.fillStyle = "#000000";
-  ctx.fillRect(window_padx, window_pady, pixel_width * 64, pixel_height * 32);
+  ctx.fillRect(window_padx, window_pady, pixel_width * screen_width, pixel_height * screen_height);
 
-  for (var y = 0; y < 32; ++y) {
-    for (var x = 0; x < 64; ++x) {
+  for (var y = 0; y < screen_height; ++y) {
+    for (var x = 0; x < screen_width; ++x) {
       let startX = window_padx + x * pixel_width;
       let startY = window_pady + y * pixel_height;
 
-      if (chip8.check_pixel(x, y)) {
+      let color = chip8.pixel(x, y);
+      if (color > 1) {
+        ctx.fillStyle = palette[color];
+        ctx.fillRect(startX, startY, pixel_width, pixel_height);
+      } else if (color == 1) {
         ctx.fillStyle = use_window_gradient
           ? wingrd
           : build_grad(